@@ -2,6 +2,9 @@
 
 cargo build --release || exit 1
 
+# Keep each student's latest valid request, writing the dropped responses (and why) to rejects.csv
+../target/release/project-grades requests responses.csv -P 1a,1b,2a,2b,3,4a,4b,5a,5b -r ../../rosters/roster-idmap.csv -o filtered.csv -x rejects.csv || exit 1
+
 for project in $(env ls p* -d)
 do
     continue # XXX: REMOVE THIS LINE TO ACTUALLY DO THE GRADING
@@ -0,0 +1,153 @@
+//! Load students' accommodations, which extend their deadline by a fraction of the time they had
+//! to work on the project (e.g. 1.5x the window), rather than by a fixed number of hours.
+
+use crate::{roster::*, extensions::*, policy::*};
+use std::{fs::File, fmt};
+use chrono::Duration;
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    AccommodationsReadError,
+    AccommodationsFormatError(usize),
+    MissingReleaseDateError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AccommodationsReadError => write!(f, "Couldn't read the accommodations file"),
+            Error::AccommodationsFormatError(line) => write!(f, "Line {} of the accommodations file is malformed", line),
+            Error::MissingReleaseDateError => write!(f, "Accommodations need the project's release date"),
+        }
+    }
+}
+
+/// Represents a single row from the accommodations CSV.
+#[derive(serde::Deserialize)]
+pub struct Accommodation {
+    #[serde(rename = "UID")]
+    pub uid: String,
+    // How much time the student gets relative to everyone else, e.g. 1.5 for time and a half.
+    #[serde(rename = "Multiplier")]
+    pub multiplier: f64,
+    // The most hours the accommodation can extend the deadline by, if limited.
+    #[serde(rename = "Cap", default)]
+    pub cap: Option<u32>,
+}
+
+/// Contains a list of accommodations.
+pub struct AccommodationSet {
+    accommodations: Vec<Accommodation>,
+}
+
+impl Accommodation {
+    /// Compute the number of hours this accommodation extends the deadline by: the extra fraction
+    /// of the window the student gets, rounded up to a whole hour, and limited by the cap.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - The time from the project's release to the student's due date.
+    pub fn hours(&self, window: Duration) -> u32 {
+        let hours = ((self.multiplier - 1.) * window.num_seconds() as f64 / 3600.).ceil().max(0.) as u32;
+
+        match self.cap {
+            Some(cap) => hours.min(cap),
+            None => hours,
+        }
+    }
+}
+
+impl AccommodationSet {
+    /// Get an empty accommodation set.
+    pub fn empty() -> AccommodationSet {
+        AccommodationSet {
+            accommodations: Vec::new(),
+        }
+    }
+
+    /// Given the name of the CSV file containing the list of accommodations, loads them into an
+    /// instance of `AccommodationSet`.  The format of the file must be UID,Multiplier,Cap (the Cap
+    /// may be left blank), and the header line must be included at the top of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The name of the accommodations file.
+    ///
+    /// # Errors
+    ///
+    /// If the accommodations file cannot be read, will return `AccommodationsReadError`.  If there
+    /// is an error during deserialization, will return `AccommodationsFormatError` with the line
+    /// number of the first error.
+    pub fn load(file: &str) -> Result<AccommodationSet, Error> {
+        // Open the file (this will fail if the file doesn't exist or we can't read it).
+        let file = File::open(file).or(Err(Error::AccommodationsReadError))?;
+
+        // Create a CSV reader over this file.
+        let mut rdr = csv::Reader::from_reader(file);
+
+        // Map each row into an Accommodation instance.
+        let accommodations: Result<Vec<_>, _> = rdr.deserialize().enumerate().map(|(i, row)| row.or(Err(Error::AccommodationsFormatError(i + 1)))).collect();
+
+        Ok(AccommodationSet {
+            accommodations: accommodations?,
+        })
+    }
+
+    /// Turn these accommodations into extensions for a project.  The hours from a student's
+    /// accommodation are added to any extension they were already given.
+    ///
+    /// # Arguments
+    ///
+    /// * `roster` - The roster, for finding each student's due date
+    /// * `policy` - The late policy, which must have a release date if there are any accommodations
+    /// * `extensions` - The extensions given so far
+    ///
+    /// # Errors
+    ///
+    /// If there are any accommodations but the policy has no release date, will return
+    /// `MissingReleaseDateError`.
+    pub fn apply(&self, roster: &Roster, policy: &Policy, extensions: &ExtensionSet) -> Result<ExtensionSet, Error> {
+        let mut extensions = extensions.clone();
+
+        for accommodation in self.accommodations.iter() {
+            // Students who aren't on the roster won't be graded anyway
+            if let Some(student) = roster.find_student_by_uid(accommodation.uid.clone()) {
+                let window = policy.window_for(student).ok_or(Error::MissingReleaseDateError)?;
+                extensions.add(&accommodation.uid, accommodation.hours(window));
+            }
+        }
+
+        Ok(extensions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn hours_round_up_and_are_capped() {
+        let accommodation = |multiplier, cap| Accommodation { uid: "1".to_owned(), multiplier, cap };
+        let window = Duration::hours(70) + Duration::minutes(1);
+
+        assert_eq!(accommodation(1.5, None).hours(window), 36);
+        assert_eq!(accommodation(1.5, Some(24)).hours(window), 24);
+        assert_eq!(accommodation(1., None).hours(window), 0);
+    }
+
+    #[test]
+    fn accommodations_add_to_extensions() {
+        let roster = roster(&["1", "2"]);
+        let policy = policy("release_date: 2021-03-01 00:00 +0000\ndue_date: 2021-03-11 00:00 +0000\n");
+        let accommodations = AccommodationSet::load(&file("accommodations_add", "accommodations.csv", "UID,Multiplier,Cap\n1,1.5,\n2,2,48\n999,2,\n")).unwrap();
+        let mut extensions = ExtensionSet::empty();
+        extensions.set("1", 10);
+
+        let extensions = accommodations.apply(&roster, &policy, &extensions).unwrap();
+
+        assert_eq!(roster.students.iter().map(|s| extensions.find(s).map(|e| e.hours)).collect::<Vec<_>>(), vec![Some(130), Some(48)]);
+    }
+}
@@ -0,0 +1,385 @@
+//! Exporting a project's submissions, test results, and grades for research, without the students'
+//! identities.  Each student and submission is given a pseudonym by hashing its ID with a secret
+//! salt, so the same student gets the same pseudonym in every project and semester exported with
+//! the same key.  The key (the salt, and which student each pseudonym belongs to) is written to a
+//! separate file, to be kept by the staff so the data can be re-identified if needed.  The results
+//! are the ones the autograder gave, with how grading adjusted them marked beside them.
+
+use crate::{roster::*, grading};
+use std::{fs::{self, File, OpenOptions}, path::Path, fmt};
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, Utc, Duration};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    KeyReadError,
+    KeyFormatError,
+    KeyWriteError,
+    SaltError,
+    AnonymizedWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::KeyReadError => write!(f, "Couldn't read the key"),
+            Error::KeyFormatError => write!(f, "The key is malformed"),
+            Error::KeyWriteError => write!(f, "Couldn't write the key"),
+            Error::SaltError => write!(f, "Couldn't generate a salt for the key"),
+            Error::AnonymizedWriteError => write!(f, "Couldn't write the anonymized data"),
+        }
+    }
+}
+
+/// The key to the pseudonyms, which must be kept apart from the anonymized data.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Key {
+    // The secret salt hashed with every ID.
+    salt: String,
+    // The student each pseudonym belongs to.
+    #[serde(default)]
+    students: Vec<KeyEntry>,
+    // Whether the key is new or has had students added since it was loaded.
+    #[serde(skip)]
+    changed: bool,
+}
+
+/// A single student in the key.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct KeyEntry {
+    pseudonym: String,
+    uid: String,
+    directory_id: String,
+}
+
+/// A single row of the anonymized submissions.
+#[derive(serde::Serialize)]
+struct SubmissionRow<'a> {
+    #[serde(rename = "Submission")]
+    submission: String,
+    #[serde(rename = "Student")]
+    student: String,
+    #[serde(rename = "Section")]
+    section: Option<&'a str>,
+    // Either the time in UTC or the hours after the student's deadline.
+    #[serde(rename = "Time")]
+    time: String,
+    #[serde(rename = "Status")]
+    status: &'a str,
+    #[serde(rename = "Active")]
+    active: bool,
+    #[serde(rename = "Score")]
+    score: Option<f64>,
+    #[serde(rename = "Max")]
+    max: Option<f64>,
+    // Whether it was excluded from grading, and its penalty for being over the submission limit.
+    #[serde(rename = "Excluded")]
+    excluded: bool,
+    #[serde(rename = "Excess Penalty")]
+    excess_penalty: f64,
+    // Whether it was made without a release token.
+    #[serde(rename = "Untokened")]
+    untokened: bool,
+}
+
+/// A single row of the anonymized test results.
+#[derive(serde::Serialize)]
+struct ResultRow<'a> {
+    #[serde(rename = "Submission")]
+    submission: String,
+    #[serde(rename = "Number")]
+    number: f64,
+    #[serde(rename = "Test")]
+    test: &'a str,
+    #[serde(rename = "Score")]
+    score: f64,
+    #[serde(rename = "Max")]
+    max: f64,
+    // Whether the score was given no points when grading, since the submission had no token.
+    #[serde(rename = "Withheld")]
+    withheld: bool,
+}
+
+/// A single row of the anonymized grades.
+#[derive(serde::Serialize)]
+struct GradeRow<'a> {
+    #[serde(rename = "Student")]
+    student: String,
+    #[serde(rename = "Section")]
+    section: Option<&'a str>,
+    #[serde(rename = "Submission")]
+    submission: String,
+    #[serde(rename = "Extension")]
+    extension: u32,
+    #[serde(rename = "Score")]
+    score: f64,
+    #[serde(rename = "Max")]
+    max: f64,
+    #[serde(rename = "Penalty")]
+    penalty: f64,
+    #[serde(rename = "Final")]
+    final_score: f64,
+}
+
+impl Key {
+    /// Load the key from a file, or make a new one with a random salt if the file doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path of the key file
+    ///
+    /// # Errors
+    ///
+    /// If the file exists but cannot be read, will return `KeyReadError`, and if it isn't a valid
+    /// key, will return `KeyFormatError`.  If a new salt cannot be generated, will return
+    /// `SaltError`.
+    pub fn load(file: &str) -> Result<Key, Error> {
+        if !Path::new(file).exists() {
+            let mut salt = [0u8; 32];
+            getrandom::getrandom(&mut salt).or(Err(Error::SaltError))?;
+            return Ok(Key { salt: hex(&salt), students: Vec::new(), changed: true });
+        }
+
+        let file = File::open(file).or(Err(Error::KeyReadError))?;
+        serde_yaml::from_reader(file).or(Err(Error::KeyFormatError))
+    }
+
+    /// Write the key to a file, with every student given a pseudonym so far.  Since the key can
+    /// undo the pseudonyms, on Unix the file is only readable by its owner.  A key that was loaded
+    /// from the file and has had no students added is left as it is.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path of the key file
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be created or written, will return `KeyWriteError`.
+    pub fn write(&self, file: &str) -> Result<(), Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+
+            // A key file that already exists keeps its permissions when opened, so restrict them too
+            if Path::new(file).exists() {
+                fs::set_permissions(file, fs::Permissions::from_mode(0o600)).or(Err(Error::KeyWriteError))?;
+            }
+        }
+
+        let file = options.open(file).or(Err(Error::KeyWriteError))?;
+        serde_yaml::to_writer(file, self).or(Err(Error::KeyWriteError))
+    }
+
+    /// Get the pseudonym for an ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - A letter for the kind of ID (S for students, U for submissions)
+    /// * `id` - The ID
+    fn pseudonym(&self, prefix: &str, id: &str) -> String {
+        let hash = Sha256::digest(format!("{}:{}", self.salt, id).as_bytes());
+        format!("{}{}", prefix, &hex(&hash)[..12])
+    }
+
+    /// Get a student's pseudonym, adding them to the key if they aren't in it yet.  It's based on
+    /// their UID alone, so it stays the same if their directory ID changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `student` - The student
+    fn student(&mut self, student: &Student) -> String {
+        let pseudonym = self.pseudonym("S", &student.uid);
+
+        if !self.students.iter().any(|s| s.pseudonym == pseudonym) {
+            self.students.push(KeyEntry { pseudonym: pseudonym.clone(), uid: student.uid.clone(), directory_id: student.directory_id.clone() });
+            self.changed = true;
+        }
+
+        pseudonym
+    }
+}
+
+/// Format bytes as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write the anonymized submissions.csv, results.csv, and grades.csv for a project into a
+/// directory, which is created if needed.  Names are left out entirely, and UIDs, directory IDs,
+/// and submission IDs are replaced with their pseudonyms.  The scores of the submissions and
+/// results are the ones the autograder gave; the submissions excluded or penalized by the
+/// submission limit, or made without a release token, are marked instead, along with the results
+/// that were given no points for it.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to write to
+/// * `key` - The key, to which any new students are added
+/// * `relative` - Whether to give times as hours after the student's deadline (including any
+///   extension), rather than in UTC
+/// * `roster` - The roster
+/// * `run` - The graded project, whose policy gives each student's deadline (the policy's own,
+///   if it groups the tests)
+///
+/// # Errors
+///
+/// If the directory or any of the files cannot be created or written, will return
+/// `AnonymizedWriteError`.
+pub fn write(dir: &str, key: &mut Key, relative: bool, roster: &Roster, run: &grading::Run) -> Result<(), Error> {
+    let (policy, extensions) = (&run.policy, &run.extensions);
+    fs::create_dir_all(dir).or(Err(Error::AnonymizedWriteError))?;
+    let writer = |name: &str| csv::Writer::from_path(Path::new(dir).join(name)).or(Err(Error::AnonymizedWriteError));
+    let (mut submissions_wtr, mut results_wtr, mut grades_wtr) = (writer("submissions.csv")?, writer("results.csv")?, writer("grades.csv")?);
+
+    let time = |student: &Student, time: &DateTime<Utc>| {
+        if relative {
+            let hours = extensions.find(student).map_or(0, |e| e.hours);
+            let deadline = policy.deadlines_for(student)[0].0 + Duration::hours(hours as i64);
+            ((*time - deadline).num_seconds() as f64 / 3600.).to_string()
+        } else {
+            time.to_rfc3339()
+        }
+    };
+
+    let adjustments = run.adjustments();
+    for submission in run.raw.submissions.iter() {
+        let id = key.pseudonym("U", &submission.id.to_string());
+        let adjusted = adjustments.get(&submission.id).copied().unwrap_or_default();
+
+        submissions_wtr.serialize(SubmissionRow {
+            submission: id.clone(),
+            student: key.student(submission.student),
+            section: submission.student.section.as_deref(),
+            time: time(submission.student, &submission.time),
+            status: "processed",
+            active: submission.active,
+            score: Some(submission.points().0),
+            max: adjusted.max,
+            excluded: adjusted.excluded,
+            excess_penalty: adjusted.excess_penalty,
+            untokened: adjusted.untokened,
+        }).or(Err(Error::AnonymizedWriteError))?;
+
+        for test in submission.tests.iter() {
+            results_wtr.serialize(ResultRow { submission: id.clone(), number: test.number, test: &test.name, score: test.score, max: test.max, withheld: run.withheld(&adjusted, test) })
+                .or(Err(Error::AnonymizedWriteError))?;
+        }
+    }
+
+    // The submissions the autograder didn't finish have no results
+    for skipped in run.raw.skipped.iter() {
+        let adjusted = adjustments.get(&skipped.id).copied().unwrap_or_default();
+
+        submissions_wtr.serialize(SubmissionRow {
+            submission: key.pseudonym("U", &skipped.id.to_string()),
+            student: key.student(skipped.student),
+            section: skipped.student.section.as_deref(),
+            time: time(skipped.student, &skipped.time),
+            status: &skipped.status,
+            active: skipped.active,
+            score: None,
+            max: None,
+            excluded: adjusted.excluded,
+            excess_penalty: adjusted.excess_penalty,
+            untokened: false,
+        }).or(Err(Error::AnonymizedWriteError))?;
+    }
+
+    for grade in run.grades.grades.iter() {
+        let student = match roster.find_student_by_sid(&grade.uid) {
+            Some(student) => student,
+            None => continue,
+        };
+
+        grades_wtr.serialize(GradeRow {
+            student: key.student(student),
+            section: student.section.as_deref(),
+            submission: key.pseudonym("U", &grade.submission.to_string()),
+            extension: extensions.find(student).map_or(0, |e| e.hours),
+            score: grade.score,
+            max: grade.max,
+            penalty: grade.penalty,
+            final_score: grade.final_score,
+        }).or(Err(Error::AnonymizedWriteError))?;
+    }
+
+    submissions_wtr.flush().or(Err(Error::AnonymizedWriteError))?;
+    results_wtr.flush().or(Err(Error::AnonymizedWriteError))?;
+    grades_wtr.flush().or(Err(Error::AnonymizedWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accommodations::AccommodationSet, extensions::ExtensionSet, submissions::*, testing::*};
+
+    /// Anonymize one submission from each student into a directory of the test's own, with the
+    /// key in it, returning the directory.
+    fn anonymize(test: &str, roster: &Roster) -> std::path::PathBuf {
+        let dir = scratch(test);
+        let key_file = dir.join("key.yml");
+        let key_file = key_file.to_str().unwrap();
+
+        let mut submissions = SubmissionSet::new(roster);
+        for (i, student) in roster.students.iter().enumerate() {
+            submissions.submissions.push(Submission { active: true, ..submission(student, i as u64 + 1, "2021-03-01 10:00 +0000", &[1., 0.]) });
+        }
+        let run = grading::run(roster, submissions, policy("due_date: 2021-03-01 23:59 +0000\n"), &AccommodationSet::empty(), &ExtensionSet::empty(), None).unwrap();
+
+        let mut key = Key::load(key_file).unwrap();
+        write(dir.join("data").to_str().unwrap(), &mut key, false, roster, &run).unwrap();
+        key.write(key_file).unwrap();
+        dir
+    }
+
+    /// The rows of a CSV file written by `write`, which are in no particular order.
+    fn rows(file: std::path::PathBuf) -> Vec<String> {
+        let mut rows: Vec<_> = fs::read_to_string(file).unwrap().lines().map(str::to_owned).collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn pseudonyms_stay_the_same_with_the_same_key() {
+        let roster = roster(&["1", "2"]);
+        let dir = anonymize("anonymize_stable", &roster);
+        let first = rows(dir.join("data/grades.csv"));
+        let key = fs::read(dir.join("key.yml")).unwrap();
+        let modified = fs::metadata(dir.join("key.yml")).unwrap().modified().unwrap();
+
+        // The key already has every student, so it isn't written again
+        anonymize("anonymize_stable", &roster);
+        assert_eq!(rows(dir.join("data/grades.csv")), first);
+        assert_eq!(fs::read(dir.join("key.yml")).unwrap(), key);
+        assert_eq!(fs::metadata(dir.join("key.yml")).unwrap().modified().unwrap(), modified);
+
+        // A new key gives everyone new pseudonyms
+        fs::remove_file(dir.join("key.yml")).unwrap();
+        anonymize("anonymize_stable", &roster);
+        assert_ne!(rows(dir.join("data/grades.csv")), first);
+    }
+
+    #[test]
+    fn no_identities_appear_in_the_output() {
+        let roster = roster(&["uid-one", "uid-two"]);
+        let dir = anonymize("anonymize_identities", &roster);
+
+        for file in ["submissions.csv", "results.csv", "grades.csv"] {
+            let data = fs::read_to_string(dir.join("data").join(file)).unwrap();
+            for student in roster.students.iter() {
+                for identity in [student.name.as_deref().unwrap(), &student.uid, &student.directory_id] {
+                    assert!(!data.contains(identity), "{} is in {}", identity, file);
+                }
+            }
+        }
+    }
+}
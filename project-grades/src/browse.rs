@@ -0,0 +1,408 @@
+//! An interactive terminal browser for looking up a student's submissions, for answering grade
+//! questions in office hours.  Students can be searched for by UID, DID or name, and every one of
+//! their submissions is listed with its test results and penalty window, along with which one
+//! would be chosen.  The student's extension can be changed to see what would happen, and if the
+//! policy groups the tests, each group can be looked at in turn.
+
+use crate::{roster::*, extensions::*, submissions::*, policy::*, grading::{self, Part}};
+use std::io;
+use chrono::{DateTime, Utc};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListState, Paragraph, Row, Table, TableState},
+};
+
+/// The format times are displayed in.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// How many hours each keypress changes the what-if extension by.
+const EXTENSION_STEP: u32 = 24;
+
+/// Which pane keypresses go to.
+#[derive(PartialEq, Eq)]
+enum Focus {
+    Students,
+    Submissions,
+}
+
+/// A single submission in the list of a student's submissions.
+enum Entry<'s, 'r> {
+    Graded(&'s Submission<'r>),
+    Skipped(&'s SkippedSubmission<'r>),
+}
+
+/// The state of the browser.
+struct Browser<'s, 'r> {
+    roster: &'r Roster,
+    // The parts of the project, and the one being looked at
+    parts: &'s [Part<'r>],
+    part: usize,
+    // The search text, and the students matching it
+    search: String,
+    matches: Vec<&'r Student>,
+    students: ListState,
+    // The selected submission of the selected student
+    entries: TableState,
+    focus: Focus,
+    // The extension to try for the selected student instead of their actual one, in hours
+    what_if: Option<u32>,
+}
+
+impl<'s, 'r> Entry<'s, 'r> {
+    fn time(&self) -> DateTime<Utc> {
+        match self {
+            Entry::Graded(s) => s.time,
+            Entry::Skipped(s) => s.time,
+        }
+    }
+}
+
+/// Describe the penalty window a submission made at the given time falls in.
+fn window(time: &DateTime<Utc>, deadlines: &[(DateTime<Utc>, f64)], extension: Option<&Extension>) -> String {
+    match period(time, deadlines, extension).unwrap() {
+        Some((_, 0.)) => "on time".to_owned(),
+        Some((end, penalty)) => format!("late, {}% off (until {})", penalty * 100., end.format(TIME_FORMAT)),
+        None => "past deadline".to_owned(),
+    }
+}
+
+impl<'s, 'r> Browser<'s, 'r> {
+    fn new(roster: &'r Roster, parts: &'s [Part<'r>]) -> Browser<'s, 'r> {
+        let mut browser = Browser {
+            roster,
+            parts,
+            part: 0,
+            search: String::new(),
+            matches: Vec::new(),
+            students: ListState::default(),
+            entries: TableState::default(),
+            focus: Focus::Students,
+            what_if: None,
+        };
+        browser.update_matches();
+        browser
+    }
+
+    /// Find the students whose UID, DID or name contains the search text.
+    fn update_matches(&mut self) {
+        let search = self.search.to_lowercase();
+        self.matches = self.roster.students.iter().filter(|s| {
+            s.uid.contains(&search) || s.directory_id.to_lowercase().contains(&search) || s.name.as_ref().is_some_and(|n| n.to_lowercase().contains(&search))
+        }).collect();
+        self.select_student(if self.matches.is_empty() { None } else { Some(0) });
+    }
+
+    fn select_student(&mut self, index: Option<usize>) {
+        self.students.select(index);
+        self.entries.select(Some(0));
+        self.what_if = None;
+    }
+
+    fn student(&self) -> Option<&'r Student> {
+        self.students.selected().and_then(|i| self.matches.get(i).copied())
+    }
+
+    fn submissions(&self) -> &'s SubmissionSet<'r> {
+        &self.parts[self.part].submissions
+    }
+
+    fn policy(&self) -> &'s Policy {
+        &self.parts[self.part].policy
+    }
+
+    /// The extensions to grade with, including the what-if extension for the selected student.
+    fn extensions(&self) -> ExtensionSet {
+        let mut extensions = self.parts[self.part].extensions.clone();
+        if let (Some(student), Some(hours)) = (self.student(), self.what_if) {
+            extensions.set(&student.uid, hours);
+        }
+        extensions
+    }
+
+    /// All of a student's submissions, including ones that couldn't be graded, in order.
+    fn entries(&self, student: &Student) -> Vec<Entry<'s, 'r>> {
+        let mut entries: Vec<_> = self.submissions().get_submissions(student).into_iter().map(Entry::Graded)
+            .chain(self.submissions().skipped.iter().filter(|s| s.student == student).map(Entry::Skipped))
+            .collect();
+        entries.sort_by_key(|e| e.time());
+        entries
+    }
+
+    /// Handle a keypress.  Returns false if the browser should exit.
+    fn handle(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+
+        match self.focus {
+            Focus::Students => match code {
+                KeyCode::Esc => return false,
+                KeyCode::Char(c) => {
+                    self.search.push(c);
+                    self.update_matches();
+                }
+                KeyCode::Backspace => {
+                    self.search.pop();
+                    self.update_matches();
+                }
+                KeyCode::Up => self.select_student(self.students.selected().map(|i| i.saturating_sub(1))),
+                KeyCode::Down => self.select_student(self.students.selected().map(|i| (i + 1).min(self.matches.len() - 1))),
+                KeyCode::Tab | KeyCode::Enter if self.student().is_some() => self.focus = Focus::Submissions,
+                _ => (),
+            },
+            Focus::Submissions => match code {
+                KeyCode::Esc | KeyCode::Tab => self.focus = Focus::Students,
+                KeyCode::Up => self.entries.select_previous(),
+                KeyCode::Down => self.entries.select_next(),
+                KeyCode::Char('+') | KeyCode::Char('=') => {
+                    let hours = self.what_if.unwrap_or_else(|| self.student().and_then(|s| self.parts[self.part].extensions.find(s)).map_or(0, |e| e.hours));
+                    self.what_if = Some(hours + EXTENSION_STEP);
+                }
+                KeyCode::Char('-') => {
+                    let hours = self.what_if.unwrap_or_else(|| self.student().and_then(|s| self.parts[self.part].extensions.find(s)).map_or(0, |e| e.hours));
+                    self.what_if = Some(hours.saturating_sub(EXTENSION_STEP));
+                }
+                KeyCode::Char('r') => self.what_if = None,
+                KeyCode::Char('g') => {
+                    self.part = (self.part + 1) % self.parts.len();
+                    self.what_if = None;
+                }
+                _ => (),
+            },
+        }
+
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [left, right] = Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)]).areas(frame.area());
+        let [search, students] = Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(left);
+
+        // Search box and matching students
+        frame.render_widget(Paragraph::new(self.search.as_str()).block(Block::bordered().title("Search (UID, DID or name)")), search);
+        let items: Vec<_> = self.matches.iter().map(|s| format!("{} {} {}", s.directory_id, s.uid, s.name.as_deref().unwrap_or(""))).collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!("Students ({})", self.matches.len())))
+            .highlight_style(Style::default().add_modifier(if self.focus == Focus::Students { Modifier::REVERSED } else { Modifier::BOLD }));
+        frame.render_stateful_widget(list, students, &mut self.students);
+
+        let student = match self.student() {
+            Some(student) => student,
+            None => {
+                frame.render_widget(Paragraph::new("No student selected").block(Block::bordered()), right);
+                return;
+            }
+        };
+
+        let [info, table, tests, help] = Layout::vertical([Constraint::Length(8), Constraint::Percentage(50), Constraint::Min(0), Constraint::Length(1)]).areas(right);
+
+        // Grade the student with the (possibly what-if) extension
+        let extensions = self.extensions();
+        let extension = extensions.find(student);
+        let (submissions, policy) = (self.submissions(), self.policy());
+        let deadlines = policy.deadlines_for(student);
+        let chosen = grading::candidates_for(student, submissions, &extensions, policy).map(|c| grading::choose_for(student, &c, &extensions, policy));
+
+        // Information about the student
+        let extension_text = match (extension.map_or(0, |e| e.hours), self.what_if) {
+            (hours, Some(_)) => format!("{} hours (what-if, press r to reset)", hours),
+            (hours, None) => format!("{} hours", hours),
+        };
+        let chosen_text = match chosen {
+            Some(c) => {
+                let penalty = c.compute_penalty(&deadlines, extension).unwrap();
+                let (score, max) = c.points();
+                format!("submission {}, {}/{} with {}% penalty = {}", c.id, score, max, penalty * 100., score * (1. - penalty))
+            }
+            None => "none".to_owned(),
+        };
+        let group_text = match self.parts[self.part].group {
+            _ if self.parts.len() == 1 => "(none)".to_owned(),
+            Some(_) => format!("{} ({} of {}, press g for the next)", self.parts[self.part].name(), self.part + 1, self.parts.len()),
+            None => format!("tests in no group ({} of {}, press g for the next)", self.part + 1, self.parts.len()),
+        };
+        let lines = vec![
+            Line::from(format!("{} ({}, {})", student.name.as_deref().unwrap_or("(no name)"), student.uid, student.directory_id)),
+            Line::from(format!("Section: {}", student.section.as_deref().unwrap_or("(none)"))),
+            Line::from(format!("Group: {}", group_text)),
+            Line::from(format!("Deadlines: {}", policy.describe(student.section.as_deref()))),
+            Line::from(format!("Extension: {}", extension_text)),
+            Line::from(format!("Chosen: {}", chosen_text)),
+        ];
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Student")), info);
+
+        // The student's submissions
+        let entries = self.entries(student);
+        let rows: Vec<_> = entries.iter().map(|e| match e {
+            Entry::Graded(s) => {
+                let (score, max) = s.points();
+                let mark = match chosen {
+                    Some(c) if c.id == s.id => "*",
+                    _ if s.excluded => "x",
+                    _ => "",
+                };
+                Row::new(vec![
+                    mark.to_owned(),
+                    s.id.to_string(),
+                    s.time.format(TIME_FORMAT).to_string(),
+                    if s.active { "yes".to_owned() } else { "".to_owned() },
+                    format!("{}/{}", score, max),
+                    if s.excess_penalty > 0. {
+                        format!("{}, {}% off for going over the limit", window(&s.time, &deadlines, extension), s.excess_penalty * 100.)
+                    } else {
+                        window(&s.time, &deadlines, extension)
+                    },
+                ])
+            }
+            Entry::Skipped(s) => Row::new(vec![
+                "".to_owned(),
+                s.id.to_string(),
+                s.time.format(TIME_FORMAT).to_string(),
+                if s.active { "yes".to_owned() } else { "".to_owned() },
+                s.status.clone(),
+                window(&s.time, &deadlines, extension),
+            ]),
+        }).collect();
+        let widths = [Constraint::Length(1), Constraint::Length(10), Constraint::Length(19), Constraint::Length(6), Constraint::Length(12), Constraint::Min(0)];
+        let table_widget = Table::new(rows, widths)
+            .header(Row::new(vec!["", "ID", "Time", "Active", "Score", "Window"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::bordered().title(format!("Submissions ({}; * = chosen, x = over the limit)", entries.len())))
+            .row_highlight_style(Style::default().add_modifier(if self.focus == Focus::Submissions { Modifier::REVERSED } else { Modifier::BOLD }));
+        frame.render_stateful_widget(table_widget, table, &mut self.entries);
+
+        // Test results of the selected submission
+        let lines: Vec<_> = match self.entries.selected().and_then(|i| entries.get(i)) {
+            Some(Entry::Graded(s)) => {
+                s.results().iter().map(|t| Line::from(format!("{:>6}  {:>6}/{:<6}  {}", t.number, t.score, t.max, t.name))).collect()
+            }
+            Some(Entry::Skipped(s)) => vec![Line::from(format!("Not graded: {}", s.status))],
+            None => Vec::new(),
+        };
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Tests")), tests);
+
+        let help_text = match self.focus {
+            Focus::Students => "type to search, up/down to select, enter to view submissions, esc to quit",
+            Focus::Submissions => "up/down to select, +/- to change extension, r to reset it, g for the next group, esc to go back",
+        };
+        frame.render_widget(Paragraph::new(help_text), help);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle(key.code, key.modifiers) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Run the browser until the user quits.
+///
+/// # Arguments
+///
+/// * `roster` - The students to browse
+/// * `parts` - The parts of the project, each with its submissions, policy and extensions
+///
+/// # Errors
+///
+/// Returns any error from reading from or drawing to the terminal.
+pub fn browse(roster: &Roster, parts: &[Part]) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = Browser::new(roster, parts).run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Make a part of the project, for a group if given, where student "2" has the given extension
+    /// and made a submission a day after the due date.
+    fn part<'r>(roster: &'r Roster, group: Option<&str>, hours: u32) -> Part<'r> {
+        let mut extensions = ExtensionSet::empty();
+        extensions.set("2", hours);
+        let mut submissions = SubmissionSet::new(roster);
+        submissions.submissions.push(submission(&roster.students[1], 1, "2021-03-02 23:00 +0000", &[1.]));
+        Part {
+            group: group.map(str::to_owned),
+            submissions,
+            policy: policy("due_date: 2021-03-01 23:59 +0000\n"),
+            extensions,
+        }
+    }
+
+    /// Type the characters into the browser.
+    fn type_text(browser: &mut Browser, text: &str) {
+        for c in text.chars() {
+            assert!(browser.handle(KeyCode::Char(c), KeyModifiers::NONE));
+        }
+    }
+
+    /// The hours of extension the selected student would be graded with.
+    fn hours(browser: &Browser) -> u32 {
+        browser.extensions().find(browser.student().unwrap()).map_or(0, |e| e.hours)
+    }
+
+    /// The penalty window the selected student's first submission falls in.
+    fn first_window(browser: &Browser) -> String {
+        let student = browser.student().unwrap();
+        let extensions = browser.extensions();
+        window(&browser.entries(student)[0].time(), &browser.policy().deadlines_for(student), extensions.find(student))
+    }
+
+    #[test]
+    fn search_narrows_the_matches() {
+        let roster = roster(&["1", "2"]);
+        let parts = [part(&roster, None, 0)];
+        let mut browser = Browser::new(&roster, &parts);
+        assert_eq!(browser.matches.len(), 2);
+
+        type_text(&mut browser, "S2");
+        assert_eq!(browser.matches.iter().map(|s| s.uid.as_str()).collect::<Vec<_>>(), ["2"]);
+
+        browser.handle(KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(browser.matches.len(), 2);
+    }
+
+    #[test]
+    fn g_switches_parts() {
+        let roster = roster(&["1", "2"]);
+        let parts = [part(&roster, Some("public"), 0), part(&roster, None, 48)];
+        let mut browser = Browser::new(&roster, &parts);
+        type_text(&mut browser, "2");
+        browser.handle(KeyCode::Enter, KeyModifiers::NONE);
+
+        browser.handle(KeyCode::Char('g'), KeyModifiers::NONE);
+        assert_eq!((browser.part, hours(&browser)), (1, 48));
+        browser.handle(KeyCode::Char('g'), KeyModifiers::NONE);
+        assert_eq!((browser.part, hours(&browser)), (0, 0));
+    }
+
+    #[test]
+    fn what_if_changes_and_resets_the_extension() {
+        let roster = roster(&["1", "2"]);
+        let parts = [part(&roster, None, 48)];
+        let mut browser = Browser::new(&roster, &parts);
+        type_text(&mut browser, "2");
+        browser.handle(KeyCode::Enter, KeyModifiers::NONE);
+
+        browser.handle(KeyCode::Char('+'), KeyModifiers::NONE);
+        assert_eq!((hours(&browser), first_window(&browser).as_str()), (72, "on time"));
+        for _ in 0..4 {
+            browser.handle(KeyCode::Char('-'), KeyModifiers::NONE);
+        }
+        assert_eq!((hours(&browser), first_window(&browser).as_str()), (0, "past deadline"));
+        browser.handle(KeyCode::Char('r'), KeyModifiers::NONE);
+        assert_eq!((hours(&browser), first_window(&browser).as_str()), (48, "on time"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Writing final grades as a Canvas (ELMS) gradebook import CSV.  Canvas matches each row to a
+//! student by their SIS login, which is their directory ID, and creates the assignment if the
+//! column doesn't exist yet.  Given a gradebook exported from Canvas, only the assignment's column
+//! is filled in, and everything else in it is kept as is.
+
+use crate::roster::*;
+use csv::StringRecord;
+use std::{collections::HashMap, fmt};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    TemplateReadError,
+    TemplateFormatError(usize),
+    MissingColumnError(String),
+    CanvasWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TemplateReadError => write!(f, "Couldn't read the Canvas gradebook"),
+            Error::TemplateFormatError(line) => write!(f, "Line {} of the Canvas gradebook is malformed", line),
+            Error::MissingColumnError(column) => write!(f, "The Canvas gradebook has no {} column", column),
+            Error::CanvasWriteError => write!(f, "Couldn't write the Canvas gradebook import"),
+        }
+    }
+}
+
+/// The columns Canvas uses to identify students, in the order it expects them.
+const ID_COLUMNS: [&str; 5] = ["Student", "ID", "SIS User ID", "SIS Login ID", "Section"];
+
+/// Find the column of an assignment in a Canvas header line, which Canvas names with the
+/// assignment's ID after it (e.g. "Project 1 (12345)").
+pub fn find_assignment(headers: &StringRecord, assignment: &str) -> Option<usize> {
+    headers.iter().position(|h| {
+        let h = h.trim();
+        h == assignment || h.strip_prefix(assignment).is_some_and(|rest| rest.starts_with(" ("))
+    })
+}
+
+/// Write the grade of each student into the assignment's column of a Canvas gradebook import CSV.
+/// Returns the number of graded students who weren't in the template, who are left out.  The
+/// cells of the template's students who weren't graded are left as they are, so that scores
+/// entered by hand (for example, for a student graded late) are kept.
+///
+/// # Arguments
+///
+/// * `assignment` - The name of the assignment's column
+/// * `scores` - Each graded student's score, by directory ID
+/// * `max` - The points the assignment is out of, for the Points Possible row of a new column
+/// * `roster` - The roster, for the students' names and UIDs when there is no template
+/// * `template` - A gradebook exported from Canvas, if any, to fill in
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the template cannot be read, will return `TemplateReadError`, if a row of it is malformed,
+/// will return `TemplateFormatError` with its line number, and if it has no SIS Login ID column,
+/// will return `MissingColumnError`.  If the output cannot be created or written, will return
+/// `CanvasWriteError`.
+pub fn write(assignment: &str, scores: &HashMap<&str, f64>, max: f64, roster: &Roster, template: Option<&str>, file: &str) -> Result<usize, Error> {
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_path(file).or(Err(Error::CanvasWriteError))?;
+
+    let template = match template {
+        Some(template) => template,
+        None => {
+            // Without a template, write just the columns Canvas needs to match each student
+            let mut header: Vec<_> = ID_COLUMNS.iter().map(|c| c.to_string()).collect();
+            header.push(assignment.to_owned());
+            wtr.write_record(&header).or(Err(Error::CanvasWriteError))?;
+            wtr.write_record(["    Points Possible", "", "", "", "", &max.to_string()]).or(Err(Error::CanvasWriteError))?;
+
+            let mut students: Vec<_> = roster.students.iter().collect();
+            students.sort_by(|a, b| a.directory_id.cmp(&b.directory_id));
+            for s in students {
+                let score = scores.get(s.directory_id.as_str()).map(f64::to_string).unwrap_or_default();
+                wtr.write_record([s.name.as_deref().unwrap_or_default(), "", &s.uid, &s.directory_id, s.section.as_deref().unwrap_or_default(), &score])
+                    .or(Err(Error::CanvasWriteError))?;
+            }
+
+            wtr.flush().or(Err(Error::CanvasWriteError))?;
+            return Ok(0);
+        }
+    };
+
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_path(template).or(Err(Error::TemplateReadError))?;
+    let mut headers = rdr.headers().or(Err(Error::TemplateReadError))?.clone();
+    let login = headers.iter().position(|h| h.trim() == "SIS Login ID").ok_or(Error::MissingColumnError("SIS Login ID".to_owned()))?;
+
+    // Add the assignment as a new column if the gradebook doesn't have it yet
+    let column = match find_assignment(&headers, assignment) {
+        Some(column) => column,
+        None => {
+            headers.push_field(assignment);
+            headers.len() - 1
+        }
+    };
+    wtr.write_record(&headers).or(Err(Error::CanvasWriteError))?;
+
+    let mut found = 0;
+    for (i, record) in rdr.records().enumerate() {
+        let record = record.or(Err(Error::TemplateFormatError(i + 2)))?;
+        let mut fields: Vec<String> = record.iter().map(str::to_owned).collect();
+        if fields.len() < headers.len() {
+            fields.resize(headers.len(), String::new());
+        }
+
+        // The rows that aren't students (Points Possible, and the muted row of older exports) have
+        // no login, and a login that is an email address is matched by its username
+        let did = fields[login].trim();
+        let did = did.split_once('@').map_or(did, |(username, _)| username).to_owned();
+        if did.is_empty() {
+            if fields[0].trim() == "Points Possible" && fields[column].is_empty() {
+                fields[column] = max.to_string();
+            }
+        } else if let Some(score) = scores.get(did.as_str()) {
+            fields[column] = score.to_string();
+            found += 1;
+        }
+
+        wtr.write_record(&fields).or(Err(Error::CanvasWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::CanvasWriteError))?;
+    Ok(scores.len().saturating_sub(found))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::fs;
+
+    const TEMPLATE: &str = "Student,ID,SIS User ID,SIS Login ID,Section,Quiz 1 (100),Project 1 (12345)
+    Points Possible,,,,,10,
+\"One, Student\",1,1,s1,0101,9.5,
+\"Two, Student\",2,2,s2@umd.edu,0102,,
+\"Three, Student\",3,3,s3,0101,8,7
+";
+
+    /// Fill in the template with the scores, returning what was written and how many graded
+    /// students were left out.
+    fn fill(test: &str, assignment: &str, scores: &[(&str, f64)]) -> (String, usize) {
+        let template = file(test, "template.csv", TEMPLATE);
+        let output = scratch(test).join("canvas.csv");
+        let scores: HashMap<_, _> = scores.iter().copied().collect();
+
+        let left_out = write(assignment, &scores, 10., &roster(&[]), Some(&template), output.to_str().unwrap()).unwrap();
+        (fs::read_to_string(output).unwrap(), left_out)
+    }
+
+    #[test]
+    fn fills_in_only_the_assignment_column() {
+        let (output, left_out) = fill("canvas_existing", "Project 1", &[("s1", 10.), ("s2", 4.5), ("s9", 1.)]);
+
+        // The login given as an email is matched by its username, the student who wasn't graded
+        // keeps their score, and the Points Possible row already had one
+        assert_eq!(output, "Student,ID,SIS User ID,SIS Login ID,Section,Quiz 1 (100),Project 1 (12345)
+    Points Possible,,,,,10,10
+\"One, Student\",1,1,s1,0101,9.5,10
+\"Two, Student\",2,2,s2@umd.edu,0102,,4.5
+\"Three, Student\",3,3,s3,0101,8,7
+");
+        assert_eq!(left_out, 1);
+    }
+
+    #[test]
+    fn appends_a_new_assignment() {
+        let (output, left_out) = fill("canvas_new", "Project 2", &[("s3", 6.)]);
+
+        assert_eq!(output, "Student,ID,SIS User ID,SIS Login ID,Section,Quiz 1 (100),Project 1 (12345),Project 2
+    Points Possible,,,,,10,,10
+\"One, Student\",1,1,s1,0101,9.5,,
+\"Two, Student\",2,2,s2@umd.edu,0102,,,
+\"Three, Student\",3,3,s3,0101,8,7,6
+");
+        assert_eq!(left_out, 0);
+    }
+}
@@ -0,0 +1,312 @@
+//! Manually graded components of a project (e.g. a style or design grade), graded in a separate
+//! Gradescope assignment or a spreadsheet, and combined with the autograder score by weight.
+
+use crate::{roster::*, grades::*};
+use std::{collections::HashMap, fmt};
+use csv::StringRecord;
+
+/// The name of the file the combined grades are written to in the output directory.
+pub const COMPONENTS_FILE: &str = "components.csv";
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    ComponentReadError(String),
+    ComponentFormatError(String, usize),
+    MissingColumnError(String, String),
+    InvalidComponentError(String),
+    InvalidWeightError,
+    ComponentsWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ComponentReadError(name) => write!(f, "Couldn't read the scores for {}", name),
+            Error::ComponentFormatError(name, line) => write!(f, "Line {} of the scores for {} is malformed", line, name),
+            Error::MissingColumnError(name, column) => write!(f, "The scores for {} have no {} column", name, column),
+            Error::InvalidComponentError(component) => write!(f, "Invalid component \"{}\", expected NAME,WEIGHT,FILE[,penalized]", component),
+            Error::InvalidWeightError => write!(f, "The components' weights must be positive and add up to at most 1"),
+            Error::ComponentsWriteError => write!(f, "Couldn't write the combined grades"),
+        }
+    }
+}
+
+/// A manually graded component of a project.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Component {
+    // The name of the component, used as its column in the output.
+    pub name: String,
+    // The CSV file of scores, which must have a column identifying the student (UID, SID, DID, or
+    // Email) and a Score or Total Score column.  A Gradescope assignment export works as is.
+    pub file: String,
+    // The fraction of the project's grade the component is worth.  The autograder is worth what
+    // is left over.
+    pub weight: f64,
+    // The points the component is out of, if the file has no Max or Max Points column.
+    #[serde(default)]
+    pub max: Option<f64>,
+    // Whether the component is combined with the autograder score before the late penalty (so
+    // that it is penalized too), rather than after.
+    #[serde(default)]
+    pub penalized: bool,
+}
+
+/// The scores loaded for a single component.
+struct ComponentScores {
+    component: Component,
+    // The points and maximum points of each student, by UID.
+    scores: HashMap<String, (f64, f64)>,
+}
+
+/// The scores of all of a project's components.
+pub struct ComponentSet {
+    components: Vec<ComponentScores>,
+    // The number of rows in each component's file that don't match a student on the roster.
+    pub unmatched: Vec<(String, usize)>,
+}
+
+/// A student's combined grade.  Each score is a fraction of that part's maximum.
+pub struct Combined<'r> {
+    pub student: &'r Student,
+    pub autograder: f64,
+    // The student's score on each component, in the same order as the components, or None if
+    // they have no score for it.
+    pub components: Vec<Option<f64>>,
+    pub penalty: f64,
+    pub total: f64,
+}
+
+impl Component {
+    /// Parse a component given on the command line as NAME,WEIGHT,FILE, optionally followed by
+    /// ",penalized" to combine it before the late penalty.
+    ///
+    /// # Arguments
+    ///
+    /// * `component` - The string to parse.
+    ///
+    /// # Errors
+    ///
+    /// If the string is not in the expected format, will return `InvalidComponentError`.
+    pub fn parse(component: &str) -> Result<Component, Error> {
+        let parts: Vec<_> = component.split(',').map(str::trim).collect();
+        let penalized = match parts.get(3) {
+            None => false,
+            Some(&"penalized") if parts.len() == 4 => true,
+            _ => return Err(Error::InvalidComponentError(component.to_owned())),
+        };
+        if parts.len() < 3 {
+            return Err(Error::InvalidComponentError(component.to_owned()));
+        }
+
+        let weight = parts[1].parse::<f64>().or(Err(Error::InvalidComponentError(component.to_owned())))?;
+
+        Ok(Component { name: parts[0].to_owned(), file: parts[2].to_owned(), weight, max: None, penalized })
+    }
+}
+
+/// Find the first of several headers in a header line, ignoring case.
+fn find(headers: &StringRecord, candidates: &[&str]) -> Option<usize> {
+    candidates.iter().find_map(|c| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(c)))
+}
+
+impl ComponentScores {
+    /// Load the scores of a component from its file.
+    ///
+    /// # Arguments
+    ///
+    /// * `component` - The component to load
+    /// * `roster` - The roster, for matching each row to a student
+    /// * `unmatched` - Incremented for each row that doesn't match a student on the roster
+    fn load(component: &Component, roster: &Roster, unmatched: &mut usize) -> Result<ComponentScores, Error> {
+        let name = &component.name;
+        let mut rdr = csv::Reader::from_path(&component.file).or(Err(Error::ComponentReadError(name.clone())))?;
+        let headers = rdr.headers().or(Err(Error::ComponentReadError(name.clone())))?.clone();
+
+        // Students may be identified either by UID or by directory ID
+        let uid = find(&headers, &["UID", "SID"]);
+        let directory_id = find(&headers, &["DID", "Directory ID", "Email"]);
+        if uid.is_none() && directory_id.is_none() {
+            return Err(Error::MissingColumnError(name.clone(), "UID".to_owned()));
+        }
+
+        let score = find(&headers, &["Score", "Total Score"]).ok_or(Error::MissingColumnError(name.clone(), "Score".to_owned()))?;
+        let max = find(&headers, &["Max", "Max Points"]);
+        if max.is_none() && component.max.is_none() {
+            return Err(Error::MissingColumnError(name.clone(), "Max".to_owned()));
+        }
+
+        let mut scores = HashMap::new();
+        for (i, record) in rdr.records().enumerate() {
+            let record = record.or(Err(Error::ComponentFormatError(name.clone(), i + 1)))?;
+            let field = |i: Option<usize>| i.and_then(|i| record.get(i)).map(str::trim).filter(|f| !f.is_empty());
+
+            let student = match (field(uid), field(directory_id)) {
+                (Some(uid), _) => roster.find_student_by_sid(uid),
+                (None, Some(did)) => {
+                    let did = did.split_once('@').map_or(did, |(username, _)| username);
+                    roster.students.iter().find(|s| s.directory_id == did)
+                }
+                (None, None) => None,
+            };
+            let student = match student {
+                Some(student) => student,
+                None => {
+                    *unmatched += 1;
+                    continue;
+                }
+            };
+
+            // A blank score (e.g. a missing Gradescope submission) is left out, and counts as zero
+            if let Some(points) = field(Some(score)) {
+                let points = points.parse::<f64>().or(Err(Error::ComponentFormatError(name.clone(), i + 1)))?;
+                let max = match field(max) {
+                    Some(max) => max.parse::<f64>().or(Err(Error::ComponentFormatError(name.clone(), i + 1)))?,
+                    None => component.max.ok_or(Error::ComponentFormatError(name.clone(), i + 1))?,
+                };
+                scores.insert(student.uid.clone(), (points, max));
+            }
+        }
+
+        Ok(ComponentScores { component: component.clone(), scores })
+    }
+
+    /// A student's score as a fraction of the maximum, if they have one.
+    fn fraction(&self, student: &Student) -> Option<f64> {
+        self.scores.get(&student.uid).map(|&(score, max)| if max > 0. { score / max } else { 0. })
+    }
+}
+
+impl ComponentSet {
+    /// Load the scores of every component.
+    ///
+    /// # Arguments
+    ///
+    /// * `components` - The components of the project
+    /// * `roster` - The roster, for matching each row to a student
+    ///
+    /// # Errors
+    ///
+    /// If the weights add up to more than 1, will return `InvalidWeightError`.  If a file cannot
+    /// be read, will return `ComponentReadError` with the name of the component.  If a file is
+    /// missing a needed column, will return `MissingColumnError` with the component and column,
+    /// and if a row is malformed, will return `ComponentFormatError` with the component and the
+    /// line number of the first error.
+    pub fn load(components: &[Component], roster: &Roster) -> Result<ComponentSet, Error> {
+        if components.iter().map(|c| c.weight).sum::<f64>() > 1. || components.iter().any(|c| c.weight < 0.) {
+            return Err(Error::InvalidWeightError);
+        }
+
+        let mut unmatched = Vec::new();
+        let components: Result<Vec<_>, _> = components.iter().map(|component| {
+            let mut count = 0;
+            let scores = ComponentScores::load(component, roster, &mut count)?;
+            unmatched.push((component.name.clone(), count));
+            Ok(scores)
+        }).collect();
+
+        Ok(ComponentSet { components: components?, unmatched })
+    }
+
+    /// Combine each student's autograder grade with their components.  The autograder score and
+    /// the penalized components are weighted and the late penalty is applied to their sum, then
+    /// the other components are added.  Students without an autograder grade get 0 for it, with no
+    /// penalty.
+    ///
+    /// # Arguments
+    ///
+    /// * `roster` - The students to combine grades for
+    /// * `grades` - The autograder grades
+    pub fn combine<'r>(&self, roster: &'r Roster, grades: &GradeSet) -> Vec<Combined<'r>> {
+        let weight = 1. - self.components.iter().map(|c| c.component.weight).sum::<f64>();
+
+        let mut combined: Vec<_> = roster.students.iter().map(|student| {
+            let grade = grades.find(&student.uid);
+            let autograder = grade.map_or(0., |g| if g.max > 0. { g.score / g.max } else { 0. });
+            let penalty = grade.map_or(0., |g| g.penalty);
+            let components: Vec<_> = self.components.iter().map(|c| c.fraction(student)).collect();
+
+            // Split the components by whether they are penalized
+            let (mut penalized, mut unpenalized) = (weight * autograder, 0.);
+            for (c, score) in self.components.iter().zip(components.iter()) {
+                let part = c.component.weight * score.unwrap_or(0.);
+                if c.component.penalized {
+                    penalized += part;
+                } else {
+                    unpenalized += part;
+                }
+            }
+
+            Combined { student, autograder, components, penalty, total: penalized * (1. - penalty) + unpenalized }
+        }).collect();
+
+        combined.sort_by(|a, b| a.student.directory_id.cmp(&b.student.directory_id));
+        combined
+    }
+
+    /// Write the combined grades to a CSV file, with the autograder score, each component, and
+    /// the total as percentages.
+    ///
+    /// # Arguments
+    ///
+    /// * `combined` - The grades from `combine`
+    /// * `file` - The path of the file to write
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be created or written, will return `ComponentsWriteError`.
+    pub fn write(&self, combined: &[Combined], file: &str) -> Result<(), Error> {
+        let mut wtr = csv::Writer::from_path(file).or(Err(Error::ComponentsWriteError))?;
+
+        let mut header = vec!["UID".to_owned(), "DID".to_owned(), "Name".to_owned(), "Autograder".to_owned()];
+        header.extend(self.components.iter().map(|c| c.component.name.clone()));
+        header.extend(["Penalty".to_owned(), "Total".to_owned()]);
+        wtr.write_record(&header).or(Err(Error::ComponentsWriteError))?;
+
+        let percent = |f: f64| (f * 100.).to_string();
+        for c in combined.iter() {
+            let mut row = vec![c.student.uid.clone(), c.student.directory_id.clone(), c.student.name.clone().unwrap_or_default(), percent(c.autograder)];
+            row.extend(c.components.iter().map(|score| score.map(percent).unwrap_or_default()));
+            row.extend([c.penalty.to_string(), percent(c.total)]);
+            wtr.write_record(&row).or(Err(Error::ComponentsWriteError))?;
+        }
+
+        wtr.flush().or(Err(Error::ComponentsWriteError))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn combines_components_before_and_after_the_penalty() {
+        let roster = roster(&["1", "2"]);
+        let style = file("components_combine", "style.csv", "UID,Score,Max\n1,5,10\n999,10,10\n");
+        let design = file("components_combine", "design.csv", "Email,Total Score\ns1@umd.edu,4\n");
+        let components = [
+            Component { name: "Style".to_owned(), file: style, weight: 0.2, max: None, penalized: false },
+            Component { name: "Design".to_owned(), file: design, weight: 0.1, max: Some(4.), penalized: true },
+        ];
+        let set = ComponentSet::load(&components, &roster).unwrap();
+        assert_eq!(set.unmatched, vec![("Style".to_owned(), 1), ("Design".to_owned(), 0)]);
+
+        let combined = set.combine(&roster, &GradeSet { grades: vec![grade("1", 1, 8., 0.5)] });
+
+        // The autograder's 80% and the design's 100% are penalized, and the style's 50% isn't
+        assert!((combined[0].total - ((0.7 * 0.8 + 0.1) * 0.5 + 0.2 * 0.5)).abs() < 1e-9);
+        assert_eq!(combined[0].components, vec![Some(0.5), Some(1.)]);
+
+        // Without an autograder grade or any component scores, there's nothing to add up
+        assert_eq!((combined[1].autograder, combined[1].total, combined[1].components.clone()), (0., 0., vec![None, None]));
+    }
+
+    #[test]
+    fn rejects_weights_over_one() {
+        let roster = roster(&["1"]);
+        let component = |name: &str| Component { name: name.to_owned(), file: String::new(), weight: 0.6, max: None, penalized: false };
+        assert!(matches!(ComponentSet::load(&[component("A"), component("B")], &roster), Err(Error::InvalidWeightError)));
+    }
+}
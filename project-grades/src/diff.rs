@@ -0,0 +1,231 @@
+//! Comparison of two grading runs, used after changing the late policy, extensions, etc. to see
+//! which students' grades changed and why.
+
+use crate::grades::*;
+use std::{fmt, collections::BTreeMap};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    DiffWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DiffWriteError => write!(f, "Couldn't write the comparison"),
+        }
+    }
+}
+
+/// The direction a student's final score moved between the two runs.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Direction {
+    Up,
+    Down,
+    Same,
+}
+
+/// The grades of a single student in each of the two runs.  A student is missing from a run if
+/// they had no gradable submission in it.
+pub struct GradeChange<'g> {
+    pub old: Option<&'g Grade>,
+    pub new: Option<&'g Grade>,
+}
+
+/// A single row of the CSV written by `write`.
+#[derive(serde::Serialize)]
+struct DiffRow<'g> {
+    #[serde(rename = "UID")]
+    uid: &'g str,
+    #[serde(rename = "DID")]
+    directory_id: &'g str,
+    #[serde(rename = "Old Submission")]
+    old_submission: Option<u64>,
+    #[serde(rename = "New Submission")]
+    new_submission: Option<u64>,
+    #[serde(rename = "Old Penalty")]
+    old_penalty: Option<f64>,
+    #[serde(rename = "New Penalty")]
+    new_penalty: Option<f64>,
+    #[serde(rename = "Old Final")]
+    old_final: Option<f64>,
+    #[serde(rename = "New Final")]
+    new_final: Option<f64>,
+    #[serde(rename = "Change")]
+    direction: String,
+    #[serde(rename = "Reason")]
+    reason: String,
+}
+
+impl<'g> GradeChange<'g> {
+    /// Get whichever of the two grades exists, for identifying the student.
+    fn any(&self) -> &'g Grade {
+        self.new.or(self.old).unwrap()
+    }
+
+    /// The student's UID.
+    pub fn uid(&self) -> &'g str {
+        &self.any().uid
+    }
+
+    /// The student's Directory ID.
+    pub fn directory_id(&self) -> &'g str {
+        &self.any().directory_id
+    }
+
+    /// Which direction the student's final score moved.  A student missing from a run is treated
+    /// as having a score of 0 in that run.
+    pub fn direction(&self) -> Direction {
+        let old = self.old.map_or(0., |g| g.final_score);
+        let new = self.new.map_or(0., |g| g.final_score);
+
+        if new > old {
+            Direction::Up
+        } else if new < old {
+            Direction::Down
+        } else {
+            Direction::Same
+        }
+    }
+
+    /// The reasons the student's grade differs between the two runs, or an empty list if nothing
+    /// changed.
+    pub fn reasons(&self) -> Vec<&'static str> {
+        let mut reasons = Vec::new();
+
+        match (self.old, self.new) {
+            (None, Some(_)) => reasons.push("added"),
+            (Some(_), None) => reasons.push("removed"),
+            (Some(old), Some(new)) => {
+                if old.submission != new.submission {
+                    reasons.push("submission");
+                } else if old.score != new.score {
+                    reasons.push("score");
+                }
+                if old.penalty != new.penalty {
+                    reasons.push("penalty");
+                }
+                if reasons.is_empty() && old.final_score != new.final_score {
+                    reasons.push("final");
+                }
+            }
+            (None, None) => (),
+        }
+
+        reasons
+    }
+
+    /// Whether anything about the student's grade differs between the two runs.
+    pub fn is_changed(&self) -> bool {
+        !self.reasons().is_empty()
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Up => write!(f, "up"),
+            Direction::Down => write!(f, "down"),
+            Direction::Same => write!(f, "same"),
+        }
+    }
+}
+
+impl fmt::Display for GradeChange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn field<T: fmt::Display>(grade: Option<&Grade>, get: impl Fn(&Grade) -> T) -> String {
+            grade.map_or("-".to_owned(), |g| get(g).to_string())
+        }
+
+        write!(f, "{} ({}): submission {} -> {}, penalty {} -> {}, final {} -> {} [{}]",
+            self.directory_id(), self.uid(),
+            field(self.old, |g| g.submission), field(self.new, |g| g.submission),
+            field(self.old, |g| g.penalty), field(self.new, |g| g.penalty),
+            field(self.old, |g| g.final_score), field(self.new, |g| g.final_score),
+            self.reasons().join(", "))
+    }
+}
+
+/// Pair up the grades of each student from two runs, sorted by directory ID.
+///
+/// # Arguments
+///
+/// * `old` - The grades from the earlier run
+/// * `new` - The grades from the later run
+pub fn compare<'g>(old: &'g GradeSet, new: &'g GradeSet) -> Vec<GradeChange<'g>> {
+    let mut changes: BTreeMap<(&str, &str), GradeChange> = BTreeMap::new();
+
+    for grade in old.grades.iter() {
+        changes.entry((&grade.directory_id, &grade.uid)).or_insert(GradeChange { old: None, new: None }).old = Some(grade);
+    }
+    for grade in new.grades.iter() {
+        changes.entry((&grade.directory_id, &grade.uid)).or_insert(GradeChange { old: None, new: None }).new = Some(grade);
+    }
+
+    changes.into_values().collect()
+}
+
+/// Write every student's change to a CSV file.
+///
+/// # Arguments
+///
+/// * `changes` - The changes produced by `compare`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `DiffWriteError`.
+pub fn write(changes: &[GradeChange], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::DiffWriteError))?;
+
+    for change in changes.iter() {
+        wtr.serialize(DiffRow {
+            uid: change.uid(),
+            directory_id: change.directory_id(),
+            old_submission: change.old.map(|g| g.submission),
+            new_submission: change.new.map(|g| g.submission),
+            old_penalty: change.old.map(|g| g.penalty),
+            new_penalty: change.new.map(|g| g.penalty),
+            old_final: change.old.map(|g| g.final_score),
+            new_final: change.new.map(|g| g.final_score),
+            direction: change.direction().to_string(),
+            reason: change.reasons().join(" "),
+        }).or(Err(Error::DiffWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::DiffWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn gives_the_reasons_for_each_change() {
+        let old = GradeSet { grades: vec![grade("1", 1, 10., 0.), grade("2", 2, 10., 0.), grade("3", 3, 8., 0.), grade("4", 4, 10., 0.1), grade("5", 5, 10., 0.)] };
+        let new = GradeSet { grades: vec![grade("1", 1, 10., 0.), grade("2", 7, 9., 0.5), grade("3", 3, 9., 0.), grade("4", 4, 10., 0.2), grade("6", 6, 5., 0.)] };
+
+        let changes = compare(&old, &new);
+        let reasons: Vec<_> = changes.iter().map(|c| (c.uid(), c.reasons(), c.direction())).collect();
+
+        assert_eq!(reasons, vec![
+            ("1", vec![], Direction::Same),
+            ("2", vec!["submission", "penalty"], Direction::Down),
+            ("3", vec!["score"], Direction::Up),
+            ("4", vec!["penalty"], Direction::Down),
+            ("5", vec!["removed"], Direction::Down),
+            ("6", vec!["added"], Direction::Up),
+        ]);
+        assert_eq!(changes.iter().filter(|c| c.is_changed()).count(), 5);
+    }
+
+    #[test]
+    fn a_final_score_changed_by_hand_is_still_a_change() {
+        let old = GradeSet { grades: vec![grade("1", 1, 10., 0.)] };
+        let new = GradeSet { grades: vec![Grade { final_score: 9., ..grade("1", 1, 10., 0.) }] };
+
+        assert_eq!(compare(&old, &new)[0].reasons(), vec!["final"]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Exporting everything loaded and computed for a project into a SQLite database, so that ad-hoc
+//! questions (e.g. who passed a test only after the deadline) can be answered with SQL.  Every
+//! project of a semester is exported into the same database, with each row keyed by the project's
+//! name; exporting a project again replaces its rows.  The results are the ones the autograder
+//! gave, and how grading adjusted each submission (the submission limit and release tokens) is
+//! recorded beside them.
+
+use crate::{roster::*, grading};
+use std::fmt;
+use rusqlite::{Connection, params};
+use chrono::Duration;
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    DatabaseOpenError(String),
+    DatabaseWriteError(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DatabaseOpenError(e) => write!(f, "Couldn't open the database: {}", e),
+            Error::DatabaseWriteError(e) => write!(f, "Couldn't write to the database: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::DatabaseWriteError(e.to_string())
+    }
+}
+
+/// The schema of the database.  Times are stored as text in UTC, so they can be compared directly.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS projects (
+    project TEXT PRIMARY KEY,
+    release_date TEXT,
+    due_date TEXT NOT NULL,
+    exported TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS students (
+    uid TEXT PRIMARY KEY,
+    directory_id TEXT NOT NULL,
+    name TEXT,
+    section TEXT
+);
+CREATE TABLE IF NOT EXISTS deadlines (
+    project TEXT NOT NULL REFERENCES projects(project),
+    uid TEXT NOT NULL REFERENCES students(uid),
+    tier INTEGER NOT NULL,
+    deadline TEXT NOT NULL,
+    penalty REAL NOT NULL,
+    PRIMARY KEY (project, uid, tier)
+);
+CREATE TABLE IF NOT EXISTS extensions (
+    project TEXT NOT NULL REFERENCES projects(project),
+    uid TEXT NOT NULL REFERENCES students(uid),
+    hours INTEGER NOT NULL,
+    PRIMARY KEY (project, uid)
+);
+CREATE TABLE IF NOT EXISTS submissions (
+    project TEXT NOT NULL REFERENCES projects(project),
+    id INTEGER NOT NULL,
+    uid TEXT NOT NULL REFERENCES students(uid),
+    time TEXT NOT NULL,
+    active INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    score REAL,
+    max REAL,
+    excluded INTEGER NOT NULL,
+    excess_penalty REAL NOT NULL,
+    untokened INTEGER NOT NULL,
+    PRIMARY KEY (project, id)
+);
+CREATE TABLE IF NOT EXISTS results (
+    project TEXT NOT NULL,
+    submission INTEGER NOT NULL,
+    number REAL NOT NULL,
+    name TEXT NOT NULL,
+    score REAL NOT NULL,
+    max REAL NOT NULL,
+    withheld INTEGER NOT NULL,
+    FOREIGN KEY (project, submission) REFERENCES submissions(project, id)
+);
+CREATE INDEX IF NOT EXISTS results_submission ON results(project, submission);
+CREATE TABLE IF NOT EXISTS grades (
+    project TEXT NOT NULL REFERENCES projects(project),
+    uid TEXT NOT NULL REFERENCES students(uid),
+    submission INTEGER NOT NULL,
+    score REAL NOT NULL,
+    max REAL NOT NULL,
+    penalty REAL NOT NULL,
+    final REAL NOT NULL,
+    PRIMARY KEY (project, uid)
+);
+";
+
+/// The tables that hold rows for a single project, in the order they are cleared.
+const PROJECT_TABLES: [&str; 6] = ["grades", "results", "submissions", "extensions", "deadlines", "projects"];
+
+/// Write a project into the database, creating the database and its tables if needed.  Any rows
+/// already exported for a project of the same name are replaced, while the students are updated
+/// in place so that they are shared between projects.  The deadlines recorded for each student
+/// are numbered by tier (0 being the due date) and are the last moment a submission counts for
+/// that tier, including the five minute grace period and their extension.  If the policy groups
+/// the tests, the deadlines and extensions recorded are the policy's own, while the grades combine
+/// every group's.
+///
+/// Each submission's score is the points the autograder gave it, and each result's score is the
+/// test's; the submissions excluded or penalized by the submission limit, or made without a
+/// release token, are marked instead, along with the results that were given no points for it.
+/// The max is the points possible as graded, without the extra credit tests.
+///
+/// # Arguments
+///
+/// * `file` - The path of the database
+/// * `project` - The name of the project
+/// * `roster` - The students in the course
+/// * `run` - The graded project
+///
+/// # Errors
+///
+/// If the database cannot be opened, will return `DatabaseOpenError`.  If any of the rows cannot
+/// be written, will return `DatabaseWriteError`, and nothing will have been changed.
+pub fn export(file: &str, project: &str, roster: &Roster, run: &grading::Run) -> Result<(), Error> {
+    let (policy, extensions) = (&run.policy, &run.extensions);
+    let mut conn = Connection::open(file).map_err(|e| Error::DatabaseOpenError(e.to_string()))?;
+    conn.execute_batch(SCHEMA)?;
+
+    // Write everything in one transaction, so a failure leaves the previous export intact
+    let tx = conn.transaction()?;
+
+    for table in PROJECT_TABLES.iter() {
+        tx.execute(&format!("DELETE FROM {} WHERE project = ?1", table), params![project])?;
+    }
+
+    tx.execute("INSERT INTO projects VALUES (?1, ?2, ?3, ?4)", params![project, policy.release_date, policy.due_date, chrono::Utc::now()])?;
+
+    for student in roster.students.iter() {
+        tx.execute(
+            "INSERT INTO students VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(uid) DO UPDATE SET directory_id = excluded.directory_id, name = excluded.name, section = excluded.section",
+            params![student.uid, student.directory_id, student.name, student.section],
+        )?;
+
+        let extension = extensions.find(student);
+        if let Some(extension) = extension {
+            tx.execute("INSERT INTO extensions VALUES (?1, ?2, ?3)", params![project, student.uid, extension.hours])?;
+        }
+
+        let grace = Duration::seconds(300) + Duration::hours(extension.map_or(0, |e| e.hours) as i64);
+        for (tier, (deadline, penalty)) in policy.deadlines_for(student).into_iter().enumerate() {
+            tx.execute("INSERT INTO deadlines VALUES (?1, ?2, ?3, ?4, ?5)", params![project, student.uid, tier as u32, deadline + grace, penalty])?;
+        }
+    }
+
+    let adjustments = run.adjustments();
+    for submission in run.raw.submissions.iter() {
+        let adjusted = adjustments.get(&submission.id).copied().unwrap_or_default();
+        tx.execute(
+            "INSERT INTO submissions VALUES (?1, ?2, ?3, ?4, ?5, 'processed', ?6, ?7, ?8, ?9, ?10)",
+            params![project, submission.id, submission.student.uid, submission.time, submission.active, submission.points().0, adjusted.max, adjusted.excluded, adjusted.excess_penalty, adjusted.untokened],
+        )?;
+
+        for test in submission.tests.iter() {
+            tx.execute(
+                "INSERT INTO results VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![project, submission.id, test.number, test.name, test.score, test.max, run.withheld(&adjusted, test)],
+            )?;
+        }
+    }
+
+    // The submissions the autograder didn't finish have no results
+    for skipped in run.raw.skipped.iter() {
+        let adjusted = adjustments.get(&skipped.id).copied().unwrap_or_default();
+        tx.execute(
+            "INSERT INTO submissions VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL, NULL, ?7, ?8, 0)",
+            params![project, skipped.id, skipped.student.uid, skipped.time, skipped.active, skipped.status, adjusted.excluded, adjusted.excess_penalty],
+        )?;
+    }
+
+    for grade in run.grades.grades.iter() {
+        tx.execute(
+            "INSERT INTO grades VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![project, grade.uid, grade.submission, grade.score, grade.max, grade.penalty, grade.final_score],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accommodations::AccommodationSet, extensions::ExtensionSet, submissions::*, testing::*};
+
+    const POLICY: &str = "due_date: 2021-03-01 23:59 +0000\nrelease_tokens:\n  tests: [t2]\n  count: 1\n  regeneration_hours: 24\n";
+
+    /// Grade two submissions from student "1", the second made without a release token.
+    fn run(roster: &Roster) -> grading::Run<'_> {
+        let student = &roster.students[0];
+        let mut submissions = SubmissionSet::new(roster);
+        submissions.submissions.push(submission(student, 1, "2021-03-01 10:00 +0000", &[1., 0.]));
+        submissions.submissions.push(Submission { active: true, ..submission(student, 2, "2021-03-01 11:00 +0000", &[1., 1.]) });
+        grading::run(roster, submissions, policy(POLICY), &AccommodationSet::empty(), &ExtensionSet::empty(), None).unwrap()
+    }
+
+    #[test]
+    fn results_are_raw_with_the_adjustments_beside_them() {
+        let roster = roster(&["1"]);
+        let file = scratch("export_raw").join("grades.db");
+        let _ = std::fs::remove_file(&file);
+        export(file.to_str().unwrap(), "p1", &roster, &run(&roster)).unwrap();
+
+        let conn = Connection::open(&file).unwrap();
+        let submission: (f64, f64, bool) = conn.query_row("SELECT score, max, untokened FROM submissions WHERE id = 2", [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?))).unwrap();
+        let result: (f64, bool) = conn.query_row("SELECT score, withheld FROM results WHERE submission = 2 AND name = 't2'", [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+        assert_eq!((submission, result), ((2., 2., true), (1., true)));
+    }
+
+    #[test]
+    fn projects_accumulate_in_one_database() {
+        let roster = roster(&["1"]);
+        let file = scratch("export_projects").join("grades.db");
+        let _ = std::fs::remove_file(&file);
+        let run = run(&roster);
+
+        for project in ["p1", "p2", "p1"] {
+            export(file.to_str().unwrap(), project, &roster, &run).unwrap();
+        }
+
+        let conn = Connection::open(&file).unwrap();
+        for table in ["projects", "deadlines", "grades"] {
+            let count: i64 = conn.query_row(&format!("SELECT COUNT(DISTINCT project) FROM {}", table), [], |r| r.get(0)).unwrap();
+            assert_eq!(count, 2, "{}", table);
+        }
+        let submissions: i64 = conn.query_row("SELECT COUNT(*) FROM submissions", [], |r| r.get(0)).unwrap();
+        assert_eq!(submissions, 4);
+    }
+}
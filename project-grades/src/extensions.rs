@@ -1,7 +1,7 @@
 //! Load and lookup individual students' extensions for the project.
 
 use crate::roster::*;
-use std::fs::File;
+use std::{fs::File, fmt};
 
 /// The types of errors that can be produced within and returned from this module.
 #[derive(Debug)]
@@ -10,8 +10,17 @@ pub enum Error {
     ExtensionsFormatError(usize),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ExtensionsReadError => write!(f, "Couldn't read the extensions file"),
+            Error::ExtensionsFormatError(line) => write!(f, "Line {} of the extensions file is malformed", line),
+        }
+    }
+}
+
 /// Represents a single row from the extensions CSV.
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Clone)]
 pub struct Extension {
     #[serde(rename = "UID")]
     pub uid: String,
@@ -21,6 +30,7 @@ pub struct Extension {
 
 /// Contains a list of extensions, each of which maps the student's UID to the number of hours
 /// their deadline was extended.
+#[derive(Clone)]
 pub struct ExtensionSet {
     extensions: Vec<Extension>,
 }
@@ -72,4 +82,30 @@ impl ExtensionSet {
     pub fn find(&self, student: &Student) -> Option<&Extension> {
         self.extensions.iter().find(|e| e.uid == student.uid)
     }
+
+    /// Set the number of hours a student's deadline is extended by, replacing any extension they
+    /// already have.
+    ///
+    /// # Arguments
+    ///
+    /// * `uid` - The UID of the student whose deadline to extend.
+    /// * `hours` - The number of hours to extend it by.
+    pub fn set(&mut self, uid: &str, hours: u32) {
+        self.extensions.retain(|e| e.uid != uid);
+        self.extensions.push(Extension { uid: uid.to_owned(), hours });
+    }
+
+    /// Extend a student's deadline by some number of hours, on top of any extension they already
+    /// have.
+    ///
+    /// # Arguments
+    ///
+    /// * `uid` - The UID of the student whose deadline to extend.
+    /// * `hours` - The number of hours to extend it by.
+    pub fn add(&mut self, uid: &str, hours: u32) {
+        match self.extensions.iter_mut().find(|e| e.uid == uid) {
+            Some(extension) => extension.hours += hours,
+            None => self.extensions.push(Extension { uid: uid.to_owned(), hours }),
+        }
+    }
 }
@@ -0,0 +1,352 @@
+//! Per-student feedback: a text file for each graded student, rendered from a template, explaining
+//! which submission was chosen and how their score was computed.  The files can then be emailed to
+//! the students through an SMTP server.
+
+use crate::{roster::*, extensions::*, submissions::*, grades::*, policy::*};
+use std::{fs, collections::HashMap, path::Path, thread, time::Duration, fmt};
+use lettre::{Message, SmtpTransport, Transport, message::{Mailbox, header::ContentType}, transport::smtp::authentication::Credentials};
+
+/// The name of the directory within an output directory that the feedback files are written to.
+pub const FEEDBACK_DIR: &str = "feedback";
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    TemplateReadError,
+    FeedbackReadError,
+    FeedbackWriteError,
+    SmtpConfigReadError,
+    SmtpConfigFormatError(String),
+    InvalidAddressError(String),
+    SmtpConnectError(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TemplateReadError => write!(f, "Couldn't read the feedback template"),
+            Error::FeedbackReadError => write!(f, "Couldn't read the feedback files"),
+            Error::FeedbackWriteError => write!(f, "Couldn't write the feedback files"),
+            Error::SmtpConfigReadError => write!(f, "Couldn't read the SMTP configuration"),
+            Error::SmtpConfigFormatError(e) => write!(f, "The SMTP configuration is invalid: {}", e),
+            Error::InvalidAddressError(address) => write!(f, "Invalid address \"{}\"", address),
+            Error::SmtpConnectError(e) => write!(f, "Couldn't connect to the SMTP server: {}", e),
+        }
+    }
+}
+
+/// How to secure the connection to the SMTP server.
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    // Connect over TLS from the start (usually port 465).
+    Tls,
+    // Connect in plain text and upgrade with STARTTLS (usually port 587).
+    Starttls,
+    // Never encrypt the connection, e.g. for a local SMTP sink used for testing.
+    None,
+}
+
+/// The SMTP server to send feedback through, and how to address the messages.
+#[derive(serde::Deserialize, Debug)]
+pub struct SmtpConfig {
+    // The hostname of the SMTP server.
+    pub host: String,
+    // The port to connect to, if not the default for the security mode.
+    #[serde(default)]
+    pub port: Option<u16>,
+    // How to secure the connection.
+    #[serde(default = "default_security")]
+    pub security: Security,
+    // The username and password to log in with, if the server requires it.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // The address the messages are sent from, e.g. "CMSC 330 <cmsc330@umd.edu>".
+    pub from: String,
+    // The domain each student's directory ID is combined with to form their address.
+    #[serde(default = "default_domain")]
+    pub domain: String,
+    // The subject of messages whose feedback file doesn't give one.
+    #[serde(default = "default_subject")]
+    pub subject: String,
+}
+
+fn default_security() -> Security {
+    Security::Starttls
+}
+
+fn default_domain() -> String {
+    "umd.edu".to_owned()
+}
+
+fn default_subject() -> String {
+    "Project feedback".to_owned()
+}
+
+/// A feedback template.  Any of the following placeholders in the template are replaced with the
+/// student's values; anything else is left as it is.
+///
+/// * `{name}`, `{uid}`, `{did}`, `{section}` - The student, from the roster
+/// * `{submission}`, `{time}` - The ID and time of the submission chosen for grading
+/// * `{score}`, `{max}` - The points earned on that submission before any penalty, out of the max
+/// * `{penalty}` - The late penalty, as a percentage
+/// * `{final}` - The points after the penalty is applied
+/// * `{extension}` - The number of hours the student's deadlines were extended by
+/// * `{tests}` - One line per test, with the points earned and possible
+///
+/// If the first line of the template starts with "Subject:", it is used as the subject of the
+/// message when the feedback is emailed.
+pub struct Template {
+    text: String,
+}
+
+impl Template {
+    /// Load a template from a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the template.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read, will return `TemplateReadError`.
+    pub fn load(file: &str) -> Result<Template, Error> {
+        let text = fs::read_to_string(file).or(Err(Error::TemplateReadError))?;
+        Ok(Template { text })
+    }
+
+    /// Render the feedback for a single student.  Scores are given to two decimal places, and the
+    /// penalty as a percentage to one.
+    ///
+    /// # Arguments
+    ///
+    /// * `submission` - The submission chosen for the student
+    /// * `grade` - The student's grade, computed from that submission
+    /// * `extension` - The student's extension, if they have one
+    pub fn render(&self, submission: &Submission, grade: &Grade, extension: Option<&Extension>) -> String {
+        let student = submission.student;
+
+        // List the tests in the order they appear on Gradescope
+        let tests = submission.results();
+        let tests: Vec<_> = tests.iter().map(|t| format!("  {}: {:.2} / {:.2}", t.name, t.score, t.max)).collect();
+
+        let values = [
+            ("name", student.name.clone().unwrap_or_else(|| student.directory_id.clone())),
+            ("uid", student.uid.clone()),
+            ("did", student.directory_id.clone()),
+            ("section", student.section.clone().unwrap_or_default()),
+            ("submission", submission.id.to_string()),
+            ("time", submission.time.format(DATE_FORMAT).to_string()),
+            ("score", format!("{:.2}", grade.score)),
+            ("max", format!("{:.2}", grade.max)),
+            ("penalty", format!("{:.1}", grade.penalty * 100.)),
+            ("final", format!("{:.2}", grade.final_score)),
+            ("extension", extension.map_or(0, |e| e.hours).to_string()),
+            ("tests", tests.join("\n")),
+        ];
+
+        values.iter().fold(self.text.clone(), |text, (key, value)| text.replace(&format!("{{{}}}", key), value))
+    }
+}
+
+/// Render the feedback for every graded student and write it to a file named after their
+/// directory ID within `dir`, which is created if needed.  Returns the number of files written.
+///
+/// # Arguments
+///
+/// * `template` - The feedback template
+/// * `chosen` - The submission chosen for each student
+/// * `grades` - The students' grades
+/// * `extensions` - The students' extensions
+/// * `dir` - The directory to write the files to
+///
+/// # Errors
+///
+/// If the directory or any of the files cannot be written, will return `FeedbackWriteError`.
+pub fn write(template: &Template, chosen: &HashMap<&Student, &Submission>, grades: &GradeSet, extensions: &ExtensionSet, dir: &str) -> Result<usize, Error> {
+    fs::create_dir_all(dir).or(Err(Error::FeedbackWriteError))?;
+
+    let mut count = 0;
+    for (student, submission) in chosen.iter() {
+        if let Some(grade) = grades.find(&student.uid) {
+            let feedback = template.render(submission, grade, extensions.find(student));
+            fs::write(Path::new(dir).join(format!("{}.txt", student.directory_id)), feedback).or(Err(Error::FeedbackWriteError))?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+impl SmtpConfig {
+    /// Load the SMTP configuration from a YAML file, for example:
+    ///
+    /// ```yaml
+    /// host: smtp.umd.edu
+    /// port: 587
+    /// security: starttls
+    /// username: cmsc330
+    /// password: hunter2
+    /// from: CMSC 330 <cmsc330@umd.edu>
+    /// subject: Project 3 feedback
+    /// ```
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the YAML file.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read, will return `SmtpConfigReadError`.  If the file does not
+    /// describe a valid configuration, will return `SmtpConfigFormatError` with the parser's
+    /// message.
+    pub fn load(file: &str) -> Result<SmtpConfig, Error> {
+        let file = fs::File::open(file).or(Err(Error::SmtpConfigReadError))?;
+        serde_yaml::from_reader(file).map_err(|e| Error::SmtpConfigFormatError(e.to_string()))
+    }
+
+    /// Build the transport for sending messages through this server.
+    fn transport(&self) -> Result<SmtpTransport, Error> {
+        let builder = match self.security {
+            Security::Tls => SmtpTransport::relay(&self.host),
+            Security::Starttls => SmtpTransport::starttls_relay(&self.host),
+            Security::None => Ok(SmtpTransport::builder_dangerous(&self.host)),
+        }.map_err(|e| Error::SmtpConnectError(e.to_string()))?;
+
+        let builder = match self.port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+
+        let builder = match (&self.username, &self.password) {
+            (Some(username), Some(password)) => builder.credentials(Credentials::new(username.clone(), password.clone())),
+            _ => builder,
+        };
+
+        Ok(builder.build())
+    }
+}
+
+/// Split a feedback file into its subject, if the first line gives one, and its body.
+fn split_subject(feedback: &str) -> (Option<&str>, &str) {
+    match feedback.strip_prefix("Subject:") {
+        Some(rest) => {
+            let (subject, body) = rest.split_once('\n').unwrap_or((rest, ""));
+            (Some(subject.trim()), body.trim_start_matches(['\r', '\n']))
+        }
+        None => (None, feedback),
+    }
+}
+
+/// Email every feedback file in a directory to the student it was written for, at their directory
+/// ID at the configured domain.  Messages that fail to send are reported and skipped, so that one
+/// bad address doesn't stop the rest.  Returns the number of messages sent and the directory IDs
+/// of the students whose messages failed.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of feedback files written by `write`
+/// * `config` - The SMTP server to send through
+/// * `dry_run` - If true, only print what would be sent, without connecting to the server
+/// * `rate` - The most messages to send per minute, if limited
+///
+/// # Errors
+///
+/// If the directory or any of the files cannot be read, will return `FeedbackReadError`.  If the
+/// from address is malformed, will return `InvalidAddressError`, and if the server's settings are
+/// invalid, will return `SmtpConnectError`.
+pub fn send(dir: &str, config: &SmtpConfig, dry_run: bool, rate: Option<u32>) -> Result<(usize, Vec<String>), Error> {
+    // Collect the feedback files, in a consistent order
+    let mut files: Vec<_> = fs::read_dir(dir).or(Err(Error::FeedbackReadError))?
+        .map(|entry| entry.map(|e| e.path()).or(Err(Error::FeedbackReadError)))
+        .collect::<Result<_, _>>()?;
+    files.retain(|f| f.extension().is_some_and(|e| e == "txt"));
+    files.sort();
+
+    let from: Mailbox = config.from.parse().or(Err(Error::InvalidAddressError(config.from.clone())))?;
+    let transport = if dry_run { None } else { Some(config.transport()?) };
+    let delay = rate.filter(|&r| r > 0).map(|r| Duration::from_secs_f64(60. / r as f64));
+
+    let mut sent = 0;
+    let mut failed = Vec::new();
+
+    for (i, file) in files.iter().enumerate() {
+        let directory_id = file.file_stem().unwrap().to_string_lossy().into_owned();
+        let feedback = fs::read_to_string(file).or(Err(Error::FeedbackReadError))?;
+        let (subject, body) = split_subject(&feedback);
+        let subject = subject.unwrap_or(&config.subject);
+        let to = format!("{}@{}", directory_id, config.domain);
+
+        let transport = match &transport {
+            Some(transport) => transport,
+            None => {
+                println!("Would send \"{}\" to {} ({} bytes)", subject, to, body.len());
+                sent += 1;
+                continue;
+            }
+        };
+
+        // Wait between messages so as not to exceed the rate limit
+        if let Some(delay) = delay.filter(|_| i > 0) {
+            thread::sleep(delay);
+        }
+
+        let message = to.parse().map_err(|e: lettre::address::AddressError| e.to_string()).and_then(|to| {
+            Message::builder()
+                .from(from.clone())
+                .to(to)
+                .subject(subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(body.to_owned())
+                .map_err(|e| e.to_string())
+        });
+
+        match message.and_then(|m| transport.send(&m).map_err(|e| e.to_string())) {
+            Ok(_) => sent += 1,
+            Err(e) => {
+                eprintln!("Could not send feedback to {}: {}", to, e);
+                failed.push(directory_id);
+            }
+        }
+    }
+
+    Ok((sent, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn renders_the_placeholders_and_leaves_unknown_ones() {
+        let roster = roster(&["1"]);
+        let submission = submission(&roster.students[0], 7, "2021-03-01 12:00 +0000", &[1., 0.5]);
+        let template = Template { text: "{name} ({did}), submission {submission}: {score}/{max} - {penalty}% = {final}, {extension}h {unknown}\n{tests}".to_owned() };
+        let extension = Extension { uid: "1".to_owned(), hours: 24 };
+
+        let feedback = template.render(&submission, &grade("1", 7, 8., 0.1), Some(&extension));
+
+        assert_eq!(feedback, "Student 1 (s1), submission 7: 8.00/10.00 - 10.0% = 7.20, 24h {unknown}\n  t1: 1.00 / 1.00\n  t2: 0.50 / 1.00");
+    }
+
+    #[test]
+    fn dry_run_counts_every_file_without_connecting() {
+        let dir = scratch("feedback_dry_run");
+        fs::write(dir.join("s1.txt"), "Subject: Project 1\n\nHi").unwrap();
+        fs::write(dir.join("s2.txt"), "Hi").unwrap();
+        fs::write(dir.join("notes.md"), "Not feedback").unwrap();
+        let config: SmtpConfig = serde_yaml::from_str("host: smtp.invalid\nfrom: CMSC 330 <cmsc330@umd.edu>\n").unwrap();
+
+        assert_eq!(send(dir.to_str().unwrap(), &config, true, Some(1)).unwrap(), (2, Vec::new()));
+    }
+
+    #[test]
+    fn subject_comes_from_the_first_line() {
+        assert_eq!(split_subject("Subject: Project 1\r\n\r\nHi"), (Some("Project 1"), "Hi"));
+        assert_eq!(split_subject("Hi\nSubject: no"), (None, "Hi\nSubject: no"));
+    }
+}
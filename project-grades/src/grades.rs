@@ -0,0 +1,187 @@
+//! The per-student summary of a grading run.  Alongside the grades.csv that gets uploaded to
+//! Gradescope, each run writes a summary.csv recording which submission was chosen for each
+//! student and how their final score was computed, so that runs can be inspected and compared
+//! after the fact.
+
+use crate::submissions::*;
+use std::{fs::File, path::Path, fmt};
+use chrono::{DateTime, Utc};
+
+/// The name of the summary file within an output directory.
+pub const SUMMARY_FILE: &str = "summary.csv";
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    GradesReadError,
+    GradesWriteError,
+    GradesFormatError(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::GradesReadError => write!(f, "Couldn't read the summary"),
+            Error::GradesWriteError => write!(f, "Couldn't write the summary"),
+            Error::GradesFormatError(line) => write!(f, "Line {} of the summary is malformed", line),
+        }
+    }
+}
+
+/// The final grade of a single student, along with the submission it was computed from.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Grade {
+    // The student's University ID.
+    #[serde(rename = "UID")]
+    pub uid: String,
+    // The student's Directory ID.
+    #[serde(rename = "DID")]
+    pub directory_id: String,
+    // The student's name, if the roster has one.
+    #[serde(rename = "Name")]
+    pub name: Option<String>,
+    // The ID of the submission that was chosen for grading.
+    #[serde(rename = "Submission")]
+    pub submission: u64,
+    // The time that submission was made.
+    #[serde(rename = "Time")]
+    pub time: DateTime<Utc>,
+    // The number of points earned on the chosen submission, before any penalty.
+    #[serde(rename = "Score")]
+    pub score: f64,
+    // The maximum number of points possible.
+    #[serde(rename = "Max")]
+    pub max: f64,
+    // The late penalty applied, between 0 (no penalty) and 1 (no credit).
+    #[serde(rename = "Penalty")]
+    pub penalty: f64,
+    // The number of points after the penalty is applied.
+    #[serde(rename = "Final")]
+    pub final_score: f64,
+}
+
+/// The grades of every student graded in a single run.
+pub struct GradeSet {
+    pub grades: Vec<Grade>,
+}
+
+impl Grade {
+    /// Create the grade for a student from their chosen submission and the penalty applied to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `submission` - The submission chosen for grading
+    /// * `penalty` - The late penalty applied to that submission
+    pub fn new(submission: &Submission, penalty: f64) -> Grade {
+        let (score, max) = submission.points();
+
+        Grade {
+            uid: submission.student.uid.clone(),
+            directory_id: submission.student.directory_id.clone(),
+            name: submission.student.name.clone(),
+            submission: submission.id,
+            time: submission.time,
+            score,
+            max,
+            penalty,
+            final_score: score * (1. - penalty),
+        }
+    }
+}
+
+impl GradeSet {
+    /// Load the grades from a previous run.  The path may either be the output directory of that
+    /// run, in which case its summary.csv is read, or the path to a summary file itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The output directory or summary file of the run.
+    ///
+    /// # Errors
+    ///
+    /// If the summary file cannot be read, will return `GradesReadError`.  If there is an error
+    /// during deserialization, will return `GradesFormatError` with the line number of the first
+    /// error.
+    pub fn load(path: &str) -> Result<GradeSet, Error> {
+        // Find the summary file if we were given a directory.
+        let path = Path::new(path);
+        let path = if path.is_dir() { path.join(SUMMARY_FILE) } else { path.to_path_buf() };
+
+        // Open the file (this will fail if the file doesn't exist or we can't read it).
+        let file = File::open(path).or(Err(Error::GradesReadError))?;
+
+        // Create a CSV reader over this file.
+        let mut rdr = csv::Reader::from_reader(file);
+
+        // Map each row into an instance of Grade.
+        let grades: Result<Vec<_>, _> = rdr.deserialize().enumerate().map(|(i, row)| row.or(Err(Error::GradesFormatError(i + 1)))).collect();
+
+        Ok(GradeSet {
+            grades: grades?,
+        })
+    }
+
+    /// Write these grades to a summary file, sorted by directory ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path of the file to write.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be created or written, will return `GradesWriteError`.
+    pub fn write(&self, file: &str) -> Result<(), Error> {
+        let mut grades: Vec<_> = self.grades.iter().collect();
+        grades.sort_by(|a, b| a.directory_id.cmp(&b.directory_id));
+
+        let mut wtr = csv::Writer::from_path(file).or(Err(Error::GradesWriteError))?;
+        for grade in grades {
+            wtr.serialize(grade).or(Err(Error::GradesWriteError))?;
+        }
+        wtr.flush().or(Err(Error::GradesWriteError))
+    }
+
+    /// Combine the grades from grading each group of a project's tests on its own into one grade
+    /// per student.  The scores are summed, with a student missing from a group getting no points
+    /// for it, and the penalty is the fraction of the points lost to penalties.  The submission
+    /// recorded is the latest of the ones chosen for the student's groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `parts` - The grades of each group.
+    pub fn combine(parts: &[GradeSet]) -> GradeSet {
+        // Every grade in a group is out of the same number of points
+        let max: f64 = parts.iter().filter_map(|p| p.grades.first()).map(|g| g.max).sum();
+
+        let mut grades: Vec<Grade> = Vec::new();
+        for grade in parts.iter().flat_map(|p| p.grades.iter()) {
+            match grades.iter_mut().find(|g| g.uid == grade.uid) {
+                Some(combined) => {
+                    if grade.time > combined.time {
+                        combined.submission = grade.submission;
+                        combined.time = grade.time;
+                    }
+                    combined.score += grade.score;
+                    combined.final_score += grade.final_score;
+                }
+                None => grades.push(Grade { max, ..grade.clone() }),
+            }
+        }
+
+        for grade in grades.iter_mut() {
+            grade.penalty = if grade.score > 0. { 1. - grade.final_score / grade.score } else { 0. };
+        }
+
+        GradeSet { grades }
+    }
+
+    /// Lookup the grade of a student by their university id (UID).
+    ///
+    /// # Arguments
+    ///
+    /// * `uid` - The UID of the student to look for.
+    pub fn find(&self, uid: &str) -> Option<&Grade> {
+        self.grades.iter().find(|g| g.uid == uid)
+    }
+}
@@ -0,0 +1,417 @@
+//! Choosing which of each student's submissions to grade, and computing their grades from it.
+
+use crate::{roster::*, extensions::*, accommodations::{self, AccommodationSet}, submissions::*, grades::*, policy::*, limits, tokens};
+use std::collections::HashMap;
+use chrono::Duration;
+
+/// What was found while preparing the submissions to be graded under a policy.
+pub struct Prepared<'r> {
+    // The submissions over the submission limit
+    pub over_limit: Vec<limits::OverLimit<'r>>,
+    // The submissions made without a release token
+    pub untokened: Vec<tokens::Untokened<'r>>,
+    // The IDs of the submissions with no points possible, which score 0
+    pub empty: Vec<u64>,
+    // The IDs of the submissions without per-test results, and whether they were scored on their
+    // top-level score rather than excluded
+    pub totals: Vec<u64>,
+    pub scored: bool,
+}
+
+/// Prepare the submissions to be graded under a policy, the same way for every command: exclude
+/// or penalize the ones over the submission limit, leave out the release test results of the ones
+/// made without a release token, mark the extra credit tests, and score or exclude the ones
+/// without per-test results.
+///
+/// # Arguments
+///
+/// * `roster` - The students to grade
+/// * `submissions` - All of the submissions for the project, which are changed in place
+/// * `policy` - The late policy
+/// * `exempt` - A submitter the submission limit and release tokens don't apply to, whose active
+///   submission has full points, such as the canonical submitter
+pub fn prepare<'r>(roster: &'r Roster, submissions: &mut SubmissionSet<'r>, policy: &Policy, exempt: Option<&Student>) -> Prepared<'r> {
+    let over_limit = limits::apply(roster, submissions, policy, exempt);
+    let untokened = tokens::apply(roster, submissions, policy, exempt);
+    let mut empty = policy.extra_credit.apply(submissions);
+    let (totals, scored) = submissions.apply_totals(policy.max, exempt);
+    empty.retain(|id| !totals.contains(id));
+
+    Prepared { over_limit, untokened, empty, totals, scored }
+}
+
+/// The submissions that could be chosen for a student: their active submission (unless the
+/// autograder failed on it), and the others the policy's strategy allows, which may include the
+/// active one as well.
+pub struct Candidates<'s, 'r> {
+    pub active: Option<&'s Submission<'r>>,
+    pub others: Vec<&'s Submission<'r>>,
+}
+
+/// Get the submissions that could be chosen for a student under the policy's strategy:
+///
+/// * `Default` - The active submission and the latest submission in each penalty period
+/// * `Active` - Only the active submission
+/// * `Latest` - Only the latest submission
+/// * `Best` - Every submission
+///
+/// Submissions excluded by the submission limit are never candidates.  Under `Default` (and
+/// `Active`), a student whose active submission failed or hasn't finished isn't graded at all, as
+/// their history may not reflect what they meant to submit; `Latest` and `Best` still grade their
+/// other submissions.  Returns None if the student has no gradable submission.
+///
+/// # Arguments
+///
+/// * `student` - The student to find submissions for
+/// * `submissions` - All of the submissions for the project
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+pub fn candidates_for<'s, 'r>(student: &Student, submissions: &'s SubmissionSet<'r>, extensions: &ExtensionSet, policy: &Policy) -> Option<Candidates<'s, 'r>> {
+    // Get the student's active submission
+    let active = submissions.get_active_submission(student);
+
+    // Without a usable active submission, there's nothing to grade unless the strategy looks past it
+    let active_failed = submissions.skipped.iter().any(|s| s.student == student && s.active);
+    if active_failed && matches!(policy.strategy, Strategy::Default | Strategy::Active) {
+        return None;
+    }
+
+    let others: Vec<_> = match policy.strategy {
+        Strategy::Default => {
+            // Get the student's latest submission in each penalty period
+            let ext = if let Some(extension) = extensions.find(student) {
+                Duration::seconds(300) + Duration::seconds((extension.hours * 3600) as i64)
+            } else {
+                Duration::seconds(300)
+            };
+            policy.deadlines_for(student).iter().filter_map(|(d, _)| submissions.get_latest_submission(student, Some(&(*d + ext)))).collect()
+        }
+        Strategy::Active => Vec::new(),
+        Strategy::Latest => submissions.get_latest_submission::<chrono::Utc>(student, None).into_iter().collect(),
+        Strategy::Best => submissions.get_submissions(student).into_iter().filter(|s| !s.excluded).collect(),
+    };
+
+    // Only the latest submission counts under that strategy, even if it isn't the active one
+    let active = if policy.strategy == Strategy::Latest { None } else { active };
+
+    if active.is_some() || !others.is_empty() {
+        Some(Candidates { active, others })
+    } else {
+        None
+    }
+}
+
+/// For each student, get their candidate submissions as described in `candidates_for`.  Students
+/// without any gradable submission are left out.
+///
+/// # Arguments
+///
+/// * `roster` - The students to find submissions for
+/// * `submissions` - All of the submissions for the project
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+pub fn candidates<'s, 'r>(roster: &'r Roster, submissions: &'s SubmissionSet<'r>, extensions: &ExtensionSet, policy: &Policy) -> HashMap<&'r Student, Candidates<'s, 'r>> {
+    roster.students.iter().filter_map(|student| {
+        candidates_for(student, submissions, extensions, policy).map(|c| (student, c))
+    }).collect()
+}
+
+/// Of a student's candidate submissions, find the best scoring one.  Ties are broken by the
+/// policy's tie-break rule.
+///
+/// # Arguments
+///
+/// * `student` - The student whose submission to choose
+/// * `candidates` - The student's candidate submissions, from `candidates_for`
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+pub fn choose_for<'s, 'r>(student: &Student, candidates: &Candidates<'s, 'r>, extensions: &ExtensionSet, policy: &Policy) -> &'s Submission<'r> {
+    let extension = extensions.find(student);
+    let deadlines = policy.deadlines_for(student);
+
+    // Whether the first submission wins a tie against the second
+    let preferred = |a: &Submission, b: &Submission| match policy.tie_break {
+        TieBreak::Active => a.active && !b.active || a.active == b.active && a.time < b.time,
+        TieBreak::Earliest => a.time < b.time,
+        TieBreak::Latest => a.time > b.time,
+    };
+
+    let mut best: Option<(&Submission, f64)> = None;
+    for l in candidates.active.iter().chain(candidates.others.iter()) {
+        let new_score = l.score(&deadlines, extension).unwrap();
+        best = match best {
+            Some((b, score)) if score > new_score || score == new_score && !preferred(l, b) => Some((b, score)),
+            _ => Some((l, new_score)),
+        };
+    }
+    best.unwrap().0
+}
+
+/// Of the candidate submissions, find the best scoring one for each student, as described in
+/// `choose_for`.
+///
+/// # Arguments
+///
+/// * `candidates` - The candidate submissions for each student, from `candidates`
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+pub fn choose<'s, 'r>(candidates: &HashMap<&'r Student, Candidates<'s, 'r>>, extensions: &ExtensionSet, policy: &Policy) -> HashMap<&'r Student, &'s Submission<'r>> {
+    candidates.iter().map(|(student, c)| (*student, choose_for(student, c, extensions, policy))).collect()
+}
+
+/// Compute the grade of each student from their chosen submission.
+///
+/// # Arguments
+///
+/// * `chosen` - The submission chosen for each student, from `choose`
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+pub fn grades(chosen: &HashMap<&Student, &Submission>, extensions: &ExtensionSet, policy: &Policy) -> GradeSet {
+    GradeSet {
+        grades: chosen.values().map(|submission| {
+            Grade::new(submission, submission.compute_penalty(&policy.deadlines_for(submission.student), extensions.find(submission.student)).unwrap())
+        }).collect(),
+    }
+}
+
+/// A part of a project that is graded on its own: one of the policy's groups of tests, the tests
+/// in no group, or the whole project if the policy has no groups.
+pub struct Part<'r> {
+    // The name of the group, or None for the tests in no group
+    pub group: Option<String>,
+    // The submissions, with only the part's tests
+    pub submissions: SubmissionSet<'r>,
+    // The policy for the part, with its own deadlines
+    pub policy: Policy,
+    // The students' extensions, with their accommodations computed from the part's due date
+    pub extensions: ExtensionSet,
+}
+
+impl Part<'_> {
+    /// The name of the part, which its output directory is named after.
+    pub fn name(&self) -> &str {
+        self.group.as_deref().unwrap_or("other")
+    }
+}
+
+/// Split a project into the parts that are graded on their own, one for each of the policy's
+/// groups of tests and one for the tests in no group (if there are any), or a single part if the
+/// policy has no groups.
+///
+/// # Arguments
+///
+/// * `roster` - The students to grade
+/// * `submissions` - All of the submissions for the project, already prepared
+/// * `policy` - The late policy
+/// * `accommodations` - The students' accommodations, which are turned into extensions using each
+///   part's release and due dates
+/// * `extensions` - The students' extensions, which apply to every part
+///
+/// # Errors
+///
+/// Will return any error from applying the accommodations to a part's policy.
+pub fn parts<'r>(roster: &Roster, submissions: &SubmissionSet<'r>, policy: &Policy, accommodations: &AccommodationSet, extensions: &ExtensionSet) -> Result<Vec<Part<'r>>, accommodations::Error> {
+    if policy.groups.is_empty() {
+        let extensions = accommodations.apply(roster, policy, extensions)?;
+        return Ok(vec![Part { group: None, submissions: submissions.clone(), policy: policy.clone(), extensions }]);
+    }
+
+    let mut parts = Vec::new();
+    for group in policy.groups.iter().map(Some).chain(std::iter::once(None)) {
+        let part = submissions.restrict(|t| policy.group_of(t).map(|g| &g.name) == group.map(|g| &g.name));
+
+        // Skip the tests in no group if there aren't any
+        if part.submissions.iter().all(|s| s.tests.is_empty()) {
+            continue;
+        }
+
+        let policy = policy.for_group(group);
+        let extensions = accommodations.apply(roster, &policy, extensions)?;
+        parts.push(Part { group: group.map(|g| g.name.clone()), submissions: part, policy, extensions });
+    }
+
+    Ok(parts)
+}
+
+/// Choose each student's submission for a part and compute their grades, returning both.
+///
+/// # Arguments
+///
+/// * `roster` - The students to grade
+/// * `part` - The part to grade
+pub fn grade_part<'p, 'r>(roster: &'r Roster, part: &'p Part<'r>) -> (HashMap<&'r Student, &'p Submission<'r>>, GradeSet) {
+    let candidates = candidates(roster, &part.submissions, &part.extensions, &part.policy);
+    let chosen = choose(&candidates, &part.extensions, &part.policy);
+    let grades = grades(&chosen, &part.extensions, &part.policy);
+    (chosen, grades)
+}
+
+/// Grade every part of a project, combining the grades of the parts into one per student if there
+/// are several.
+///
+/// # Arguments
+///
+/// * `roster` - The students to grade
+/// * `parts` - The parts of the project, from `parts`
+pub fn grade_parts(roster: &Roster, parts: &[Part]) -> GradeSet {
+    let mut grades: Vec<_> = parts.iter().map(|part| grade_part(roster, part).1).collect();
+    if grades.len() == 1 {
+        grades.remove(0)
+    } else {
+        GradeSet::combine(&grades)
+    }
+}
+
+/// A project graded for writing out along with everything it was graded from, keeping the
+/// submissions as they were loaded as well as how they were prepared.
+pub struct Run<'r> {
+    // The submissions as they were loaded, with every test's raw result
+    pub raw: SubmissionSet<'r>,
+    // The submissions as they were graded
+    pub submissions: SubmissionSet<'r>,
+    // What was found while preparing them
+    pub prepared: Prepared<'r>,
+    // The late policy
+    pub policy: Policy,
+    // The students' extensions, including their accommodations under the policy's due date
+    pub extensions: ExtensionSet,
+    // The grades, combining every part's
+    pub grades: GradeSet,
+}
+
+/// How preparing a submission changed the way it was graded, to record alongside its raw results.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Adjustments {
+    // Whether it was excluded from grading, by the submission limit or for having no per-test
+    // results when others do
+    pub excluded: bool,
+    // The penalty for being over the submission limit
+    pub excess_penalty: f64,
+    // Whether it was made without a release token, so its release tests were given no points
+    pub untokened: bool,
+    // The points possible, without the extra credit tests, or for a submission without per-test
+    // results, what its top-level score is out of
+    pub max: Option<f64>,
+}
+
+/// Prepare the submissions and grade every part of the project, as `prepare` and `grade_parts`
+/// do, keeping a copy of the submissions as they were loaded.
+///
+/// # Arguments
+///
+/// * `roster` - The students to grade
+/// * `submissions` - All of the submissions for the project
+/// * `policy` - The late policy
+/// * `accommodations` - The students' accommodations
+/// * `extensions` - The students' extensions
+/// * `exempt` - The canonical submitter, if known, as described in `prepare`
+///
+/// # Errors
+///
+/// Will return any error from applying the accommodations.
+pub fn run<'r>(roster: &'r Roster, submissions: SubmissionSet<'r>, policy: Policy, accommodations: &AccommodationSet, extensions: &ExtensionSet, exempt: Option<&Student>) -> Result<Run<'r>, accommodations::Error> {
+    let raw = submissions.clone();
+    let mut submissions = submissions;
+    let prepared = prepare(roster, &mut submissions, &policy, exempt);
+
+    let parts = parts(roster, &submissions, &policy, accommodations, extensions)?;
+    let grades = grade_parts(roster, &parts);
+    let extensions = accommodations.apply(roster, &policy, extensions)?;
+
+    Ok(Run { raw, submissions, prepared, policy, extensions, grades })
+}
+
+impl Run<'_> {
+    /// Get how each submission was adjusted while being prepared, by ID, including the ones the
+    /// autograder didn't finish.
+    pub fn adjustments(&self) -> HashMap<u64, Adjustments> {
+        let mut adjustments: HashMap<u64, Adjustments> = HashMap::new();
+
+        for s in self.submissions.submissions.iter() {
+            adjustments.insert(s.id, Adjustments { excluded: s.excluded, excess_penalty: s.excess_penalty, untokened: false, max: Some(s.points().1) });
+        }
+        for o in self.prepared.over_limit.iter() {
+            let a = adjustments.entry(o.id).or_default();
+            a.excluded |= o.excluded;
+            a.excess_penalty = o.penalty;
+        }
+        for u in self.prepared.untokened.iter() {
+            adjustments.entry(u.id).or_default().untokened = true;
+        }
+
+        adjustments
+    }
+
+    /// Whether a test's result was given no points when grading, for being a release test of a
+    /// submission made without a token.
+    ///
+    /// # Arguments
+    ///
+    /// * `adjustments` - How the test's submission was adjusted
+    /// * `test` - The test, as it was loaded
+    pub fn withheld(&self, adjustments: &Adjustments, test: &TestCase) -> bool {
+        adjustments.untokened && self.policy.release_tokens.as_ref().is_some_and(|tokens| tokens.is_release_test(test))
+    }
+}
+
+/// Combine the submissions chosen for each student in every part into one, for showing them all
+/// together.  Each student's is the latest of the ones chosen for them, with the tests of every
+/// one of them.
+///
+/// # Arguments
+///
+/// * `chosen` - The submissions chosen in each part
+pub fn combine<'r>(chosen: &[HashMap<&'r Student, &Submission<'r>>]) -> HashMap<&'r Student, Submission<'r>> {
+    let mut combined: HashMap<&'r Student, Submission<'r>> = HashMap::new();
+    for (student, submission) in chosen.iter().flat_map(|c| c.iter()) {
+        let mut c = match combined.remove(student) {
+            Some(c) if c.time >= submission.time => c,
+            Some(c) => Submission { tests: c.tests, ..(*submission).clone() },
+            None => Submission { tests: Vec::new(), ..(*submission).clone() },
+        };
+        c.tests.extend(submission.tests.iter().cloned());
+        combined.insert(student, c);
+    }
+
+    combined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const GROUPED: &str = "release_date: 2021-03-01 00:00 +0000\ndue_date: 2021-03-11 00:00 +0000\ngroups:\n  - name: early\n    numbers: [1, 1]\n    due_date: 2021-03-03 00:00 +0000\n";
+
+    #[test]
+    fn each_group_is_graded_under_its_own_due_date_and_accommodations() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(Submission { active: true, ..submission(student, 1, "2021-03-04 06:00 +0000", &[1., 1.]) });
+        let accommodations = AccommodationSet::load(&file("grading_groups", "accommodations.csv", "UID,Multiplier,Cap\n1,1.5,\n")).unwrap();
+
+        let parts = parts(&roster, &submissions, &policy(GROUPED), &accommodations, &ExtensionSet::empty()).unwrap();
+
+        assert_eq!(parts.iter().map(|p| (p.name(), p.submissions.submissions[0].tests.len())).collect::<Vec<_>>(), vec![("early", 1), ("other", 1)]);
+        assert_eq!(parts.iter().map(|p| p.extensions.find(student).map_or(0, |e| e.hours)).collect::<Vec<_>>(), vec![24, 120]);
+
+        // The first group's test was late even with the accommodation, and there are no
+        // later deadlines, so only the other test counts
+        let grades = grade_parts(&roster, &parts);
+        assert_eq!((grades.grades[0].score, grades.grades[0].final_score, grades.grades[0].max), (2., 1., 2.));
+    }
+
+    #[test]
+    fn combined_submission_is_the_latest_with_every_test() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let (early, late) = (submission(student, 1, "2021-03-01 00:00 +0000", &[1.]), submission(student, 2, "2021-03-02 00:00 +0000", &[0., 1.]));
+        let late = Submission { tests: late.tests[1..].to_vec(), ..late };
+
+        let combined = combine(&[HashMap::from([(student, &late)]), HashMap::from([(student, &early)])]);
+
+        assert_eq!(combined[student].id, 2);
+        assert_eq!(combined[student].tests.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["t2", "t1"]);
+    }
+}
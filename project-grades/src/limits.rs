@@ -0,0 +1,229 @@
+//! Enforcing the policy's limit on the number of submissions.  Every submission a student makes
+//! counts towards the limit, including the ones the autograder failed on, and each submission
+//! beyond it is either excluded from grading or penalized.
+
+use crate::{roster::*, submissions::*, policy::*};
+use std::{collections::{HashMap, HashSet}, fmt};
+use chrono::{DateTime, Utc};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    LimitsWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LimitsWriteError => write!(f, "Couldn't write the submissions over the limit"),
+        }
+    }
+}
+
+/// A submission over the limit.
+pub struct OverLimit<'r> {
+    pub id: u64,
+    pub student: &'r Student,
+    pub time: DateTime<Utc>,
+    // The submission's status in Gradescope.
+    pub status: String,
+    // Which of the student's submissions this was (that day, if the limit is per day).
+    pub number: usize,
+    // Whether it was excluded from grading, rather than penalized.
+    pub excluded: bool,
+    // The penalty it was given, if it was not excluded.
+    pub penalty: f64,
+}
+
+/// A single row of the CSV written by `write`.
+#[derive(serde::Serialize)]
+struct OverLimitRow<'r> {
+    #[serde(rename = "Submission")]
+    id: u64,
+    #[serde(rename = "UID")]
+    uid: &'r str,
+    #[serde(rename = "DID")]
+    directory_id: &'r str,
+    #[serde(rename = "Time")]
+    time: DateTime<Utc>,
+    #[serde(rename = "Status")]
+    status: &'r str,
+    #[serde(rename = "Number")]
+    number: usize,
+    #[serde(rename = "Excluded")]
+    excluded: bool,
+    #[serde(rename = "Penalty")]
+    penalty: f64,
+}
+
+/// Apply the policy's submission limit, if it has one, marking the graded submissions over it as
+/// excluded or setting their penalty.  Failed submissions over the limit are dropped when they
+/// would be excluded, since re-running them could not change anything.  Returns every submission
+/// that was over the limit, ordered by time.
+///
+/// # Arguments
+///
+/// * `roster` - The students the limit applies to
+/// * `submissions` - All of the submissions for the project
+/// * `policy` - The late policy, whose due date the days of a per-day limit are aligned to
+/// * `exempt` - A submitter the limit does not apply to, such as the canonical submitter
+pub fn apply<'r>(roster: &'r Roster, submissions: &mut SubmissionSet<'r>, policy: &Policy, exempt: Option<&Student>) -> Vec<OverLimit<'r>> {
+    let limit = match &policy.submission_limit {
+        Some(limit) => limit,
+        None => return Vec::new(),
+    };
+
+    // Every submission each student made, graded or not
+    let mut made: HashMap<_, Vec<_>> = HashMap::new();
+    for s in submissions.submissions.iter() {
+        made.entry(s.student).or_default().push((s.time, s.id, "processed"));
+    }
+    for s in submissions.skipped.iter() {
+        made.entry(s.student).or_default().push((s.time, s.id, s.status.as_str()));
+    }
+
+    let mut over = Vec::new();
+    for student in roster.students.iter().filter(|&s| exempt != Some(s)) {
+        // The student's submissions, in the order they were made
+        let times = match made.get_mut(student) {
+            Some(times) => times,
+            None => continue,
+        };
+        times.sort();
+
+        // Days end at the time of day of the student's due date
+        let due_date = policy.deadlines_for(student)[0].0;
+        let day = |time: &DateTime<Utc>| if limit.per_day { (*time - due_date).num_seconds().div_euclid(86400) } else { 0 };
+
+        let mut number = 0;
+        for (i, (time, id, status)) in times.iter().enumerate() {
+            number = if i > 0 && day(&times[i - 1].0) == day(time) { number + 1 } else { 1 };
+
+            if number > limit.count {
+                let penalty = limit.penalty.map_or(0., |p| p * (number - limit.count) as f64);
+                over.push(OverLimit { id: *id, student, time: *time, status: status.to_string(), number, excluded: limit.penalty.is_none(), penalty });
+            }
+        }
+    }
+
+    // Mark the graded submissions, and drop the failed ones that won't be graded anyway
+    let over_by_id: HashMap<_, _> = over.iter().map(|o| (o.id, (o.excluded, o.penalty))).collect();
+    for submission in submissions.submissions.iter_mut() {
+        if let Some(&(excluded, penalty)) = over_by_id.get(&submission.id) {
+            submission.excluded = excluded;
+            submission.excess_penalty = penalty;
+        }
+    }
+    let excluded: HashSet<_> = over.iter().filter(|o| o.excluded).map(|o| o.id).collect();
+    submissions.skipped.retain(|s| !excluded.contains(&s.id));
+
+    over.sort_by_key(|o| o.time);
+    over
+}
+
+/// Write the submissions over the limit to a CSV file.
+///
+/// # Arguments
+///
+/// * `over` - The submissions from `apply`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `LimitsWriteError`.
+pub fn write(over: &[OverLimit], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::LimitsWriteError))?;
+
+    for o in over.iter() {
+        wtr.serialize(OverLimitRow {
+            id: o.id,
+            uid: &o.student.uid,
+            directory_id: &o.student.directory_id,
+            time: o.time,
+            status: &o.status,
+            number: o.number,
+            excluded: o.excluded,
+            penalty: o.penalty,
+        }).or(Err(Error::LimitsWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::LimitsWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn excludes_submissions_over_the_limit_counting_failed_ones() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let policy = policy("due_date: 2021-03-01 23:59 +0000\nsubmission_limit:\n  count: 2\n");
+
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(submission(student, 1, "2021-03-01 10:00 +0000", &[1.]));
+        submissions.skipped.push(failed(student, 2, "2021-03-01 11:00 +0000"));
+        submissions.submissions.push(submission(student, 3, "2021-03-01 12:00 +0000", &[1.]));
+        submissions.skipped.push(failed(student, 4, "2021-03-01 13:00 +0000"));
+
+        let over = apply(&roster, &mut submissions, &policy, None);
+
+        assert_eq!(over.iter().map(|o| (o.id, o.number, o.excluded)).collect::<Vec<_>>(), vec![(3, 3, true), (4, 4, true)]);
+        assert!(!submissions.submissions[0].excluded);
+        assert!(submissions.submissions[1].excluded);
+        assert!(submissions.skipped.iter().all(|s| s.id != 4));
+    }
+
+    #[test]
+    fn penalizes_each_submission_over_the_limit_more() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let policy = policy("due_date: 2021-03-01 23:59 +0000\nsubmission_limit:\n  count: 1\n  penalty: 0.1\n");
+
+        let mut submissions = SubmissionSet::new(&roster);
+        for (id, at) in [(1, "2021-03-01 10:00 +0000"), (2, "2021-03-01 11:00 +0000"), (3, "2021-03-01 12:00 +0000")] {
+            submissions.submissions.push(submission(student, id, at, &[1.]));
+        }
+
+        let over = apply(&roster, &mut submissions, &policy, None);
+
+        assert!(over.iter().all(|o| !o.excluded));
+        let penalties: Vec<_> = submissions.submissions.iter().map(|s| s.excess_penalty).collect();
+        assert_eq!(penalties.len(), 3);
+        assert_eq!(penalties[0], 0.);
+        assert!((penalties[1] - 0.1).abs() < 1e-9 && (penalties[2] - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn per_day_limit_resets_at_the_due_time() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let policy = policy("due_date: 2021-03-05 18:00 +0000\nsubmission_limit:\n  count: 1\n  per_day: true\n");
+
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(submission(student, 1, "2021-03-01 17:00 +0000", &[1.]));
+        submissions.submissions.push(submission(student, 2, "2021-03-01 19:00 +0000", &[1.]));
+        submissions.submissions.push(submission(student, 3, "2021-03-02 17:00 +0000", &[1.]));
+
+        let over = apply(&roster, &mut submissions, &policy, None);
+
+        assert_eq!(over.iter().map(|o| o.id).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn exempt_submitter_is_not_limited() {
+        let roster = roster(&["1", "2"]);
+        let policy = policy("due_date: 2021-03-01 23:59 +0000\nsubmission_limit:\n  count: 1\n");
+
+        let mut submissions = SubmissionSet::new(&roster);
+        for student in roster.students.iter() {
+            submissions.submissions.push(submission(student, student.uid.parse::<u64>().unwrap() * 10, "2021-03-01 10:00 +0000", &[1.]));
+            submissions.submissions.push(submission(student, student.uid.parse::<u64>().unwrap() * 10 + 1, "2021-03-01 11:00 +0000", &[1.]));
+        }
+
+        let over = apply(&roster, &mut submissions, &policy, Some(&roster.students[0]));
+
+        assert_eq!(over.iter().map(|o| o.id).collect::<Vec<_>>(), vec![21]);
+    }
+}
@@ -0,0 +1,334 @@
+//! Running an autograder locally over sample submissions, so a project's grading can be tried out
+//! before it is released.  The autograder is either a Docker image set up the way Gradescope runs
+//! them (e.g. one built from autograder-images), or a script.  Its results are written to a
+//! submission_metadata.yml in the same shape as Gradescope's export, with each submission given to
+//! a student on the roster and a made-up submission time.
+
+use crate::roster::*;
+use std::{fs, path::{Path, PathBuf}, process::Command, fmt};
+use serde_yaml::{Value, Mapping};
+use chrono::{DateTime, Utc, Duration};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    SubmissionsReadError(String),
+    ScriptNotFoundError(String),
+    EmptyRosterError,
+    WorkspaceError,
+    MetadataWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::SubmissionsReadError(dir) => write!(f, "Couldn't read the submissions in {}", dir),
+            Error::ScriptNotFoundError(script) => write!(f, "Couldn't find the autograder script {}", script),
+            Error::EmptyRosterError => write!(f, "The roster has no students to give the submissions to"),
+            Error::WorkspaceError => write!(f, "Couldn't copy a submission to run the autograder on it"),
+            Error::MetadataWriteError => write!(f, "Couldn't write the submission metadata"),
+        }
+    }
+}
+
+/// How to run the autograder.
+pub enum Autograder<'a> {
+    // A Docker image whose /autograder/run_autograder reads the submission from
+    // /autograder/submission and writes /autograder/results/results.json, as on Gradescope
+    Image(&'a str),
+    // A script run with the submission's directory and the directory to write results.json to
+    Script(&'a str),
+}
+
+/// The outcome of running the autograder on one sample submission.
+pub struct Run<'r> {
+    // The submission's directory
+    pub dir: PathBuf,
+    pub student: &'r Student,
+    pub time: DateTime<Utc>,
+    // The parsed results.json, or None if the autograder didn't produce one
+    pub results: Option<serde_json::Value>,
+}
+
+impl Autograder<'_> {
+    /// Run the autograder on a submission, returning its results, or None if it failed to produce
+    /// a valid results.json.
+    ///
+    /// # Arguments
+    ///
+    /// * `submission` - The directory of the submission
+    /// * `workspace` - An empty directory to run in
+    fn run(&self, submission: &Path, workspace: &Path) -> Result<Option<serde_json::Value>, Error> {
+        // The autograder gets its own copy of the submission, since it may change it
+        let (copy, results) = (workspace.join("submission"), workspace.join("results"));
+        copy_dir(submission, &copy).or(Err(Error::WorkspaceError))?;
+        fs::create_dir_all(&results).or(Err(Error::WorkspaceError))?;
+
+        let status = match self {
+            Autograder::Image(image) => Command::new("docker")
+                .arg("run").arg("--rm")
+                .arg("-v").arg(format!("{}:/autograder/submission", fs::canonicalize(&copy).or(Err(Error::WorkspaceError))?.display()))
+                .arg("-v").arg(format!("{}:/autograder/results", fs::canonicalize(&results).or(Err(Error::WorkspaceError))?.display()))
+                .arg(image).arg("/autograder/run_autograder")
+                .status(),
+            // Run the script by its full path, so that one in the current directory isn't looked
+            // up on the PATH instead
+            Autograder::Script(script) => Command::new(fs::canonicalize(script).or(Err(Error::ScriptNotFoundError(script.to_string())))?).arg(&copy).arg(&results).status(),
+        };
+
+        // Gradescope uses whatever results were written, even if the autograder then failed
+        if let Err(e) = status {
+            eprintln!("Couldn't run the autograder on {}: {}", submission.display(), e);
+        }
+        let results = fs::read_to_string(results.join("results.json")).ok().and_then(|r| serde_json::from_str(&r).ok());
+        Ok(results)
+    }
+}
+
+/// Copy a directory and everything in it.
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the autograder on every submission in a directory, where each submission is a directory of
+/// its own.  They are run in order of name, with the first submitted at the start time and each
+/// after it the interval later.  A submission whose directory is named after a student's UID or
+/// directory ID is theirs, and the others are given to the students on the roster in turn.
+///
+/// # Arguments
+///
+/// * `autograder` - The autograder to run
+/// * `dir` - The directory of submissions
+/// * `roster` - The students to give the submissions to
+/// * `start` - The time of the first submission
+/// * `interval` - The time between submissions
+///
+/// # Errors
+///
+/// If the directory can't be read, will return `SubmissionsReadError` with it, and if the roster
+/// is empty, will return `EmptyRosterError`.  If a submission can't be copied to run it, will
+/// return `WorkspaceError`, and if the autograder is a script that doesn't exist, will return
+/// `ScriptNotFoundError` with it.
+pub fn run<'r>(autograder: &Autograder, dir: &str, roster: &'r Roster, start: DateTime<Utc>, interval: Duration) -> Result<Vec<Run<'r>>, Error> {
+    let mut dirs: Vec<_> = fs::read_dir(dir).or(Err(Error::SubmissionsReadError(dir.to_owned())))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_dir())
+        .collect();
+    dirs.sort();
+
+    if roster.students.is_empty() {
+        return Err(Error::EmptyRosterError);
+    }
+
+    let workspace = std::env::temp_dir().join(format!("project-grades-{}", std::process::id()));
+    let mut runs = Vec::new();
+    let mut next = 0;
+    for (i, submission) in dirs.into_iter().enumerate() {
+        let name = submission.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let student = match roster.students.iter().find(|s| s.uid == name || s.directory_id == name) {
+            Some(student) => student,
+            None => {
+                next += 1;
+                &roster.students[(next - 1) % roster.students.len()]
+            }
+        };
+
+        println!("Running the autograder on {} as {}", submission.display(), student.directory_id);
+        let run_dir = workspace.join(i.to_string());
+        let results = autograder.run(&submission, &run_dir);
+        remove_workspace(&run_dir);
+
+        runs.push(Run { dir: submission, student, time: start + interval * i as i32, results: results? });
+    }
+
+    remove_workspace(&workspace);
+    Ok(runs)
+}
+
+/// Remove a directory the autograder was run in, warning if it can't be.
+fn remove_workspace(dir: &Path) {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => eprintln!("Couldn't remove {}: {}", dir.display(), e),
+        _ => {}
+    }
+}
+
+/// Convert the results of a run to the form Gradescope exports them in.  Tests without a number
+/// are numbered in order, since the export always has one.
+fn results_yaml(results: &serde_json::Value) -> Value {
+    let mut results: Value = serde_yaml::to_value(results).unwrap_or(Value::Null);
+
+    if let Some(Value::Sequence(tests)) = results.get_mut("tests") {
+        for (i, test) in tests.iter_mut().enumerate() {
+            if let Value::Mapping(test) = test {
+                let number = match test.get(&"number".into()) {
+                    Some(Value::String(number)) => number.clone(),
+                    Some(Value::Number(number)) => number.to_string(),
+                    _ => (i + 1).to_string(),
+                };
+                test.insert("number".into(), Value::String(number));
+            }
+        }
+    }
+
+    results
+}
+
+/// Write the runs as a submission_metadata.yml.  Each student's last run is their active
+/// submission, and the rest are in its history.  Submissions are numbered from 1 in the order they
+/// were run.
+///
+/// # Arguments
+///
+/// * `runs` - The runs from `run`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `MetadataWriteError`.
+pub fn write(runs: &[Run], file: &str) -> Result<(), Error> {
+    let entry = |id: usize, run: &Run| {
+        let mut entry = Mapping::new();
+        entry.insert(":id".into(), (id as u64).into());
+        entry.insert(":created_at".into(), run.time.format("%Y-%m-%d %H:%M:%S.%6f Z").to_string().into());
+        match &run.results {
+            Some(results) => {
+                entry.insert(":status".into(), "processed".into());
+                entry.insert(":results".into(), results_yaml(results));
+            }
+            None => {
+                entry.insert(":status".into(), "failed".into());
+            }
+        }
+        entry
+    };
+
+    let mut metadata = Mapping::new();
+    let mut students: Vec<&Student> = Vec::new();
+    for run in runs.iter() {
+        if !students.contains(&run.student) {
+            students.push(run.student);
+        }
+    }
+
+    for student in students {
+        let ids: Vec<_> = runs.iter().enumerate().filter(|(_, r)| r.student == student).map(|(i, _)| i + 1).collect();
+        let (&active, history) = ids.split_last().unwrap();
+
+        let mut submitter = Mapping::new();
+        submitter.insert(":name".into(), student.name.clone().unwrap_or_default().into());
+        submitter.insert(":sid".into(), student.uid.clone().into());
+        // The roster has no email addresses, so the field is left empty
+        submitter.insert(":email".into(), "".into());
+
+        let mut data = entry(active, &runs[active - 1]);
+        data.insert(":submitters".into(), Value::Sequence(vec![Value::Mapping(submitter)]));
+        data.insert(":history".into(), Value::Sequence(history.iter().rev().map(|&id| Value::Mapping(entry(id, &runs[id - 1]))).collect()));
+
+        metadata.insert(format!("submission_{}", active).into(), Value::Mapping(data));
+    }
+
+    let file = fs::File::create(file).or(Err(Error::MetadataWriteError))?;
+    serde_yaml::to_writer(file, &metadata).or(Err(Error::MetadataWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::*, metadata::Metadata};
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Writes the score in the submission's score file as its results, or nothing if it has none,
+    /// then removes the file.
+    const SCRIPT: &str = "#!/bin/sh
+if [ -f \"$1/score\" ]; then
+    printf '{\"score\": %s, \"tests\": []}' \"$(cat \"$1/score\")\" > \"$2/results.json\"
+fi
+rm -f \"$1/score\"
+";
+
+    #[test]
+    fn script_runs_on_each_submission() {
+        let dir = scratch("local-run");
+        let script = file("local-run", "run_autograder", SCRIPT);
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let submissions = dir.join("submissions");
+        for (name, score) in [("a", Some("1.5")), ("2", Some("3")), ("c", None)] {
+            fs::create_dir_all(submissions.join(name)).unwrap();
+            if let Some(score) = score {
+                fs::write(submissions.join(name).join("score"), score).unwrap();
+            }
+        }
+
+        let roster = roster(&["1", "2"]);
+        let start = time("2021-03-01 10:00 +0000");
+        let runs = run(&Autograder::Script(&script), submissions.to_str().unwrap(), &roster, start, Duration::minutes(30)).unwrap();
+
+        // The submission named after a UID is theirs, and the others go to the students in turn
+        let outcomes: Vec<_> = runs.iter().map(|r| {
+            (r.dir.file_name().unwrap().to_str().unwrap(), r.student.uid.as_str(), r.time, r.results.as_ref().map(|r| r["score"].as_f64().unwrap()))
+        }).collect();
+        assert_eq!(outcomes, [
+            ("2", "2", start, Some(3.)),
+            ("a", "1", start + Duration::minutes(30), Some(1.5)),
+            ("c", "2", start + Duration::hours(1), None),
+        ]);
+
+        // The script only changed its own copy of the submissions
+        assert!(submissions.join("a").join("score").exists());
+    }
+
+    #[test]
+    fn written_metadata_loads_back() {
+        let roster = roster(&["1", "2"]);
+        let results = serde_json::json!({
+            "score": 1.5,
+            "tests": [
+                { "name": "first", "score": 1.0, "max_score": 1.0 },
+                { "name": "second", "number": "2.1", "score": 0.5, "max_score": 1.0 },
+            ],
+        });
+        let run = |i: usize, student: usize, results: Option<&serde_json::Value>| Run {
+            dir: PathBuf::from(i.to_string()),
+            student: &roster.students[student],
+            time: time("2021-03-01 10:00 +0000") + Duration::hours(i as i64),
+            results: results.cloned(),
+        };
+        let runs = vec![run(0, 0, Some(&results)), run(1, 1, None), run(2, 0, Some(&results)), run(3, 0, None)];
+
+        let file = scratch("local-round-trip").join("submission_metadata.yml");
+        let file = file.to_str().unwrap();
+        write(&runs, file).unwrap();
+        let metadata = Metadata::load(file).unwrap();
+
+        let mut submitters: Vec<_> = metadata.submitters.iter().collect();
+        submitters.sort_by(|a, b| a.sid.cmp(&b.sid));
+        assert_eq!(submitters.iter().map(|s| s.sid.as_str()).collect::<Vec<_>>(), ["1", "2"]);
+
+        // The last run is active, and the rest are in the history, latest first
+        let first = submitters[0];
+        assert_eq!(first.active.id, 4);
+        assert_eq!(first.active.status.as_deref(), Some("failed"));
+        assert_eq!(first.history.iter().map(|e| e.id).collect::<Vec<_>>(), [3, 1]);
+        assert_eq!(*first.history[1].time.as_ref().unwrap(), time("2021-03-01 10:00 +0000"));
+
+        // Tests without a number are numbered in order
+        let tests = first.history[0].tests.as_ref().unwrap();
+        assert_eq!(tests.iter().map(|t| (t.name.as_str(), t.number, t.score)).collect::<Vec<_>>(), [("first", 1., 1.), ("second", 2.1, 0.5)]);
+
+        assert_eq!(submitters[1].active.id, 2);
+        assert!(submitters[1].history.is_empty());
+    }
+}
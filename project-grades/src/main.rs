@@ -5,12 +5,36 @@ extern crate clap;
 mod roster;
 mod extensions;
 mod submissions;
+mod metadata;
+mod grades;
+mod diff;
+mod policy;
+mod grading;
+mod simulate;
+mod missing;
+mod triage;
+mod accommodations;
+mod browse;
+mod feedback;
+mod export;
+mod components;
+mod limits;
+mod tokens;
+mod anonymize;
+mod canvas;
+mod posted;
+mod requests;
+mod local;
+#[cfg(test)]
+mod testing;
 
 use roster::*;
 use extensions::*;
+use accommodations::*;
 use submissions::*;
-use std::{fs::File, io::Write, collections::HashMap};
-use chrono::{DateTime, Utc, Duration};
+use grades::*;
+use policy::*;
+use std::{fs::File, io::Write};
 use clap::*;
 
 fn main() {
@@ -18,118 +42,439 @@ fn main() {
     let yaml = load_yaml!("args.yml");
     let args = App::from_yaml(yaml).get_matches();
 
-    // Load the roster
-    let roster = Roster::load(args.value_of("roster").unwrap()).unwrap();
+    match args.subcommand() {
+        ("diff", Some(args)) => diff(args),
+        ("posted", Some(args)) => posted(args),
+        ("simulate", Some(args)) => simulate(args),
+        ("browse", Some(args)) => browse(args),
+        ("export", Some(args)) => export(args),
+        ("anonymize", Some(args)) => anonymize(args),
+        ("requests", Some(args)) => requests(args),
+        ("autograde", Some(args)) => autograde(args),
+        ("send-feedback", Some(args)) => send_feedback(args),
+        _ => grade(&args),
+    }
+}
 
-    // Load all of the submissions
-    let submissions = {
-        let mut submissions = SubmissionSet::new(&roster);
+/// Compare the summaries of two grading runs and print each student whose grade changed.
+fn diff(args: &ArgMatches) {
+    let old = GradeSet::load(args.value_of("old").unwrap()).or_exit();
+    let new = GradeSet::load(args.value_of("new").unwrap()).or_exit();
 
-        for in_file in args.values_of("submissions").unwrap() {
-            submissions.load(in_file).unwrap();
-        }
+    let changes = diff::compare(&old, &new);
 
-        submissions
-    };
+    for change in changes.iter().filter(|c| c.is_changed()) {
+        println!("{}", change);
+    }
+
+    // Summarize how many grades moved in each direction
+    let count = |direction| changes.iter().filter(|c| c.direction() == direction).count();
+    println!("{} up, {} down, {} same", count(diff::Direction::Up), count(diff::Direction::Down), count(diff::Direction::Same));
+
+    if let Some(output) = args.value_of("output") {
+        diff::write(&changes, output).or_exit();
+    }
+}
 
-    // Load the extensions
-    let extensions = if let Some(extensions_file) = args.value_of("extensions") {
-        ExtensionSet::load(extensions_file).unwrap()
+/// Compare a grading run against the grades already posted, and write the students whose final
+/// score changed.
+fn posted(args: &ArgMatches) {
+    let (format, posted) = posted::load(args.value_of("posted").unwrap(), args.value_of("assignment")).or_exit();
+    let new = GradeSet::load(args.value_of("new").unwrap()).or_exit();
+
+    // A Canvas gradebook has the totals with the manually graded components, if the run had any
+    let totals = if format == posted::Format::Canvas { posted::load_totals(args.value_of("new").unwrap()).or_exit() } else { None };
+
+    let changes = posted::compare(&posted, &new, totals.as_ref());
+    let output = args.value_of("output").unwrap();
+    posted::write(&changes, output).or_exit();
+    println!("{} of {} students' final scores changed; see {}", changes.len(), new.grades.len(), output);
+}
+
+/// Load the roster given on the command line, along with any SID aliases.
+fn load_roster(args: &ArgMatches) -> Roster {
+    let columns = RosterColumns::parse(args.values_of("roster_columns").into_iter().flatten()).or_exit();
+    let mut roster = Roster::load(args.value_of("roster").unwrap(), &columns).or_exit();
+
+    if let Some(aliases_file) = args.value_of("aliases") {
+        roster.load_aliases(aliases_file).or_exit();
+    }
+
+    roster
+}
+
+/// Load all of the submissions given on the command line.
+fn load_submissions<'r>(args: &ArgMatches, roster: &'r Roster) -> SubmissionSet<'r> {
+    let mut submissions = SubmissionSet::new(roster);
+
+    for in_file in args.values_of("submissions").unwrap() {
+        submissions.load(in_file).or_exit();
+    }
+
+    submissions
+}
+
+/// Exits with the error's message, rather than panicking, when something the grader was given
+/// can't be used.
+trait OrExit<T> {
+    fn or_exit(self) -> T;
+}
+
+impl <T, E: std::fmt::Display> OrExit<T> for std::result::Result<T, E> {
+    fn or_exit(self) -> T {
+        self.unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            std::process::exit(1)
+        })
+    }
+}
+
+/// Find the submitter of the canonical solution given on the command line, if any.
+fn load_canonical<'r>(args: &ArgMatches, roster: &'r Roster) -> Option<&'r Student> {
+    args.value_of("canonical").map(|uid| {
+        roster.find_student_by_uid(uid.to_owned()).ok_or_else(|| format!("No submitter with UID {} found for the canonical submission", uid)).or_exit()
+    })
+}
+
+/// Find the active submission of the canonical solution's submitter.
+fn canonical_submission<'s, 'r>(submissions: &'s SubmissionSet<'r>, student: &Student) -> &'s Submission<'r> {
+    submissions.get_active_submission(student).ok_or_else(|| format!("No active submission found for the canonical submitter {}", student.uid)).or_exit()
+}
+
+/// Create an output file, overwriting any already there.
+fn create_output(path: &str) -> File {
+    File::create(path).map_err(|e| format!("Couldn't create {}: {}", path, e)).or_exit()
+}
+
+/// Load the extensions given on the command line, if any.
+fn load_extensions(args: &ArgMatches) -> ExtensionSet {
+    if let Some(extensions_file) = args.value_of("extensions") {
+        ExtensionSet::load(extensions_file).or_exit()
     } else {
         ExtensionSet::empty()
-    };
+    }
+}
+
+/// Load the accommodations given on the command line, if any.
+fn load_accommodations(args: &ArgMatches) -> AccommodationSet {
+    if let Some(accommodations_file) = args.value_of("accommodations") {
+        AccommodationSet::load(accommodations_file).or_exit()
+    } else {
+        AccommodationSet::empty()
+    }
+}
 
-    // Load the deadlines
-    let deadlines = {
-        let mut deadlines = Vec::new();
+/// Compute the grades several candidate policies would produce and compare them, without writing
+/// any grade files.
+fn simulate(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let submissions = load_submissions(args, &roster);
+    let extensions = load_extensions(args);
+    let accommodations = load_accommodations(args);
+    let policies = Policy::load_all(args.value_of("policies").unwrap()).or_exit();
+    let top = value_t!(args, "top", usize).unwrap_or_else(|e| e.exit());
+
+    let outcomes = simulate::simulate(&roster, &submissions, &extensions, &accommodations, policies, load_canonical(args, &roster)).or_exit();
+    simulate::print(&outcomes, top);
+}
 
-        // The first entry is just the normal deadline
-        let due_date = DateTime::parse_from_str(args.value_of("due_date").unwrap(), "%Y-%m-%d %H:%M %z").unwrap().with_timezone(&Utc);
-        deadlines.push((due_date, 0.));
+/// Browse students' submissions in an interactive terminal interface.
+fn browse(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let mut submissions = load_submissions(args, &roster);
+    let policy = Policy::from_args(args).or_exit();
+    grading::prepare(&roster, &mut submissions, &policy, load_canonical(args, &roster));
+    let parts = grading::parts(&roster, &submissions, &policy, &load_accommodations(args), &load_extensions(args)).or_exit();
 
-        // Add the remaining deadlines
-        match args.values_of("deadline") {
-            Some(deadline_args) => for deadline in deadline_args {
-                let parts: Vec<_> = deadline.split(",").collect();
-                if parts.len() != 2 {
-                    panic!("Invalid format for arg \"deadline\": {}", deadline);
-                }
+    browse::browse(&roster, &parts).or_exit();
+}
+
+/// Compute the grades for a project and export them, along with everything they were computed
+/// from, to a SQLite database.
+fn export(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let submissions = load_submissions(args, &roster);
+    let policy = Policy::from_args(args).or_exit();
+    let run = grading::run(&roster, submissions, policy, &load_accommodations(args), &load_extensions(args), load_canonical(args, &roster)).or_exit();
+
+    let database = args.value_of("database").unwrap();
+    let project = args.value_of("project").unwrap();
+    export::export(database, project, &roster, &run).or_exit();
+    println!("Exported {} submissions and {} grades for {} to {}", run.raw.submissions.len() + run.raw.skipped.len(), run.grades.grades.len(), project, database);
+}
 
-                // Get the hours and penalty
-                let hours = parts[0].parse::<u32>().unwrap();
-                let penalty = parts[1].parse::<f64>().unwrap();
+/// Compute the grades for a project and write them, along with the submissions and test results,
+/// with the students' identities replaced by pseudonyms.
+fn anonymize(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let submissions = load_submissions(args, &roster);
+    let policy = Policy::from_args(args).or_exit();
+    let run = grading::run(&roster, submissions, policy, &load_accommodations(args), &load_extensions(args), load_canonical(args, &roster)).or_exit();
+
+    let key_file = args.value_of("key").unwrap();
+    let mut key = anonymize::Key::load(key_file).or_exit();
+    let dir = args.value_of("dir").unwrap();
+    anonymize::write(dir, &mut key, args.is_present("relative"), &roster, &run).or_exit();
+    key.write(key_file).or_exit();
+    println!("Wrote anonymized data for {} students to {}; the key is in {}", run.grades.grades.len(), dir, key_file);
+}
 
-                // Compute the new deadline
-                let deadline = due_date + Duration::seconds((hours * 3600) as i64);
+/// Check the responses to an amnesty or extension request form, and write the accepted requests
+/// and the dropped responses.
+fn requests(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let columns = requests::FormColumns::parse(args.values_of("form_columns").into_iter().flatten()).or_exit();
+    let projects: Vec<_> = args.values_of("projects").unwrap().map(str::trim).collect();
 
-                deadlines.push((deadline, penalty));
-            }
-            _ => ()
-        }
+    let (requests, rejects) = requests::load(args.value_of("responses").unwrap(), &columns, &roster, &projects, args.is_present("per_project")).or_exit();
 
-        deadlines
+    let (output, rejects_file) = (args.value_of("output").unwrap(), args.value_of("rejects").unwrap());
+    requests::write(&requests, output).or_exit();
+    requests::write_rejects(&rejects, rejects_file).or_exit();
+    println!("{} requests accepted into {}, {} responses dropped; see {}", requests.len(), output, rejects.len(), rejects_file);
+}
+
+/// Run an autograder over sample submissions, and write the results as Gradescope's metadata.
+fn autograde(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let autograder = match args.value_of("image") {
+        Some(image) => local::Autograder::Image(image),
+        None => local::Autograder::Script(args.value_of("script").unwrap()),
     };
+    let start = args.value_of("start").map_or_else(|| Ok(chrono::Utc::now()), parse_date).or_exit();
+    let interval = value_t!(args, "interval", f64).unwrap_or_else(|e| e.exit());
+
+    let runs = local::run(&autograder, args.value_of("dir").unwrap(), &roster, start, chrono::Duration::seconds((interval * 3600.) as i64)).or_exit();
+
+    let output = args.value_of("output").unwrap();
+    local::write(&runs, output).or_exit();
+    let failed: Vec<_> = runs.iter().filter(|r| r.results.is_none()).map(|r| r.dir.display().to_string()).collect();
+    println!("Wrote the results of {} submissions to {}", runs.len(), output);
+    if !failed.is_empty() {
+        println!("{} submissions produced no results.json, and are marked as failed: {}", failed.len(), failed.join(", "));
+    }
+}
+
+/// Email the feedback files from a grading run to the students.
+fn send_feedback(args: &ArgMatches) {
+    // Accept either the output directory of the run or its feedback directory
+    let dir = args.value_of("dir").unwrap();
+    let feedback_dir = std::path::Path::new(dir).join(feedback::FEEDBACK_DIR);
+    let dir = if feedback_dir.is_dir() { feedback_dir.to_str().unwrap() } else { dir };
+
+    let config = feedback::SmtpConfig::load(args.value_of("smtp").unwrap()).or_exit();
+    let rate = value_t!(args, "rate", u32).unwrap_or_else(|e| e.exit());
+    let dry_run = args.is_present("dry_run");
+
+    let (sent, failed) = feedback::send(dir, &config, dry_run, Some(rate)).or_exit();
+    if dry_run {
+        println!("{} messages would be sent", sent);
+    } else {
+        println!("{} messages sent", sent);
+    }
+    if !failed.is_empty() {
+        println!("{} messages could not be sent: {}", failed.len(), failed.join(", "));
+    }
+}
+
+/// Compute the grade of every student in the roster and write the output files.  If the policy
+/// groups the tests, each group is graded on its own into a directory named after it (the tests in
+/// no group go in "other"), and the output directory gets the combined grades.
+fn grade(args: &ArgMatches) {
+    let roster = load_roster(args);
+    let mut submissions = load_submissions(args, &roster);
+
+    // Load the late policy, and list the deadlines that apply to each section and group
+    let policy = Policy::from_args(args).or_exit();
+    println!("Deadlines: {}", policy.describe(None));
+    for section in roster.sections() {
+        println!("  Section {}: {}", section, policy.describe(Some(section)));
+    }
+    for group in policy.groups.iter() {
+        println!("  Group {}: {}", group.name, policy.for_group(Some(group)).describe(None));
+    }
+
+    // Load the extensions, including the ones for students' accommodations under the policy's own
+    // due date (each group's are computed from its due date when it is graded)
+    let accommodations = load_accommodations(args);
+    let base_extensions = load_extensions(args);
+    let extensions = accommodations.apply(&roster, &policy, &base_extensions).or_exit();
+
+    // Exclude or penalize the submissions over the submission limit, leave out the release test
+    // results of the ones made without a release token, mark the extra credit tests, and score
+    // the ones without per-test results out of what the canonical submission scored unless the
+    // policy says (or exclude them if the others have per-test results)
+    let canonical_student = load_canonical(args, &roster).unwrap();
+    let grading::Prepared { over_limit, untokened, empty, totals, scored } = grading::prepare(&roster, &mut submissions, &policy, Some(canonical_student));
+    if !totals.is_empty() {
+        let how = if scored { "were scored on their top-level score" } else { "were excluded, since other submissions have them" };
+        println!("{} submissions have no per-test results and {}: {}", totals.len(), how, totals.iter().map(u64::to_string).collect::<Vec<_>>().join(", "));
+    }
+    if !empty.is_empty() {
+        println!("{} submissions have no points possible and score 0: {}", empty.len(), empty.iter().map(u64::to_string).collect::<Vec<_>>().join(", "));
+    }
 
     // Get output dir
     let output_dir = args.value_of("output").unwrap();
 
-    // For each student, get their latest submission in each penalty period, as well as their
-    // activated submission (which may be included in the former collection as well)
-    let submission_candidates = {
-        let mut submission_candidates: HashMap<&Student, (&Submission, Vec<Option<&Submission>>)> = HashMap::new();
+    // Split the project into the groups of tests graded on their own, if the policy has any
+    let parts = grading::parts(&roster, &submissions, &policy, &accommodations, &base_extensions).or_exit();
 
-        for student in roster.students.iter() {
-            // Get the student's active submission
-            if let Some(active) = submissions.get_active_submission(student) {
-                // Get the student's latest submission in each penalty period
-                let ext = if let Some(ref extension) = extensions.find(student) {
-                    Duration::seconds(300) + Duration::seconds((extension.hours * 3600) as i64)
-                } else {
-                    Duration::seconds(300)
-                };
-                let latest = deadlines.iter().map(|(d, _)| submissions.get_latest_submission(student, Some(&(*d + ext)))).collect();
+    let grades = if policy.groups.is_empty() {
+        let (chosen, grades) = grade_part(args, &roster, &parts[0], canonical_student, output_dir, None);
+        write_feedback(args, &chosen, &grades, &parts[0].extensions, output_dir);
+        grades
+    } else {
+        // Generate the parts.csv for all of the tests
+        let tests = canonical_submission(&submissions, canonical_student).results();
+        {
+            let mut file = create_output(&format!("{}/parts.csv", output_dir));
+            for t in tests.iter() {
+                writeln!(file, "{},{}", t.name, t.max).unwrap();
+            }
+        }
 
-                // Add to the collection
-                submission_candidates.insert(student, (active, latest));
+        // Grade each group, writing the penalized scores of its tests to the combined grades.csv
+        let mut file = create_output(&format!("{}/grades.csv", output_dir));
+        let (mut chosen, mut graded) = (Vec::new(), Vec::new());
+        for part in parts.iter() {
+            let dir = format!("{}/{}", output_dir, part.name());
+            std::fs::create_dir_all(&dir).map_err(|e| format!("Couldn't create {}: {}", dir, e)).or_exit();
+            println!("Grading {} into {}", part.name(), dir);
+            let (c, g) = grade_part(args, &roster, part, canonical_student, &dir, Some(&mut file));
+            chosen.push(c);
+            graded.push(g);
+        }
+
+        // The penalties were applied to each test, so every student with test rows in any group
+        // gets a multiplier of 1
+        let mut written = std::collections::HashSet::new();
+        for grade in graded.iter().flat_map(|g| g.grades.iter()).filter(|g| g.penalty < 1.) {
+            if written.insert(grade.directory_id.as_str()) {
+                writeln!(file, "{},*,*1,", grade.directory_id).unwrap();
             }
         }
 
-        submission_candidates
+        // Find the students without a grade in any group, giving them an explicit zero for every
+        // test if requested
+        let grades = GradeSet::combine(&graded);
+        let missing = missing::find(&roster, &submissions, &extensions, &policy, &grades);
+        if args.is_present("zero_missing") {
+            write_zeros(&mut file, &missing, &tests);
+        }
+
+        // Generate the summary.csv, with each student's grade across the groups
+        grades.write(&format!("{}/{}", output_dir, SUMMARY_FILE)).or_exit();
+
+        // Render the feedback once, from the submissions chosen for every group together
+        let combined = grading::combine(&chosen);
+        write_feedback(args, &combined.iter().map(|(student, submission)| (*student, submission)).collect(), &grades, &extensions, output_dir);
+
+        // Generate the missing.csv, listing the students without a grade in any group
+        missing::write(&missing, &format!("{}/missing.csv", output_dir)).or_exit();
+        if !missing.is_empty() {
+            println!("{} students have no gradable submission for any group; see {}/missing.csv", missing.len(), output_dir);
+        }
+
+        grades
     };
 
+    // Combine the grades with any manually graded components, which then make up the final grades
+    let mut finals: std::collections::HashMap<&str, f64> = grades.grades.iter().map(|g| (g.directory_id.as_str(), g.final_score)).collect();
+    let mut max = grades.grades.first().map_or(0., |g| g.max);
+    if args.is_present("zero_missing") {
+        // The students given explicit zeros get a final score of 0, rather than keeping their old one
+        for student in roster.students.iter() {
+            finals.entry(student.directory_id.as_str()).or_insert(0.);
+        }
+    }
+    if !policy.components.is_empty() {
+        let components = components::ComponentSet::load(&policy.components, &roster).or_exit();
+        let combined = components.combine(&roster, &grades);
+        components.write(&combined, &format!("{}/{}", output_dir, components::COMPONENTS_FILE)).or_exit();
+        for (name, count) in components.unmatched.iter().filter(|(_, count)| *count > 0) {
+            println!("{} rows of the {} scores don't match a student on the roster", count, name);
+        }
+        println!("Wrote the combined grades to {}/{}", output_dir, components::COMPONENTS_FILE);
+
+        finals = combined.iter().map(|c| (c.student.directory_id.as_str(), c.total * 100.)).collect();
+        max = 100.;
+    }
+
+    // Generate the canvas.csv, for importing the final grades into Canvas
+    if let Some(assignment) = args.value_of("canvas") {
+        let file = format!("{}/canvas.csv", output_dir);
+        let left_out = canvas::write(assignment, &finals, max, &roster, args.value_of("canvas_template"), &file).or_exit();
+        println!("Wrote the Canvas gradebook import to {}", file);
+        if left_out > 0 {
+            println!("{} graded students aren't in the Canvas gradebook, and were left out", left_out);
+        }
+    }
+
+    // Generate the limits.csv, listing the submissions over the submission limit
+    if policy.submission_limit.is_some() {
+        limits::write(&over_limit, &format!("{}/limits.csv", output_dir)).or_exit();
+        let excluded = over_limit.iter().filter(|o| o.excluded).count();
+        println!("{} submissions were over the submission limit ({} excluded); see {}/limits.csv", over_limit.len(), excluded, output_dir);
+    }
+
+    // Generate the tokens.csv, listing the submissions made without a release token
+    if policy.release_tokens.is_some() {
+        tokens::write(&untokened, &format!("{}/tokens.csv", output_dir)).or_exit();
+        println!("{} submissions were made without a release token; see {}/tokens.csv", untokened.len(), output_dir);
+    }
+
+    // Generate the totals.csv, listing the submissions scored on their top-level score
+    if !totals.is_empty() {
+        submissions.write_totals(&format!("{}/totals.csv", output_dir)).or_exit();
+    }
+
+    // Generate the unmatched.csv, listing the submitters who aren't on the roster
+    submissions.write_unmatched(&format!("{}/unmatched.csv", output_dir)).or_exit();
+    if !submissions.unmatched.is_empty() {
+        println!("{} submitters are not on the roster; see {}/unmatched.csv", submissions.unmatched.len(), output_dir);
+    }
+}
+
+/// Grade a part of the project and write the output files for it to a directory, returning the
+/// submission chosen for each student and their grades.  When grading a group of tests,
+/// `combined` is the grades.csv for all of the groups, which each test's score is written to with
+/// the penalty already applied.
+fn grade_part<'p, 'r>(args: &ArgMatches, roster: &'r Roster, part: &'p grading::Part<'r>, canonical_student: &Student, output_dir: &str, mut combined: Option<&mut File>) -> (std::collections::HashMap<&'r Student, &'p Submission<'r>>, GradeSet) {
+    let (submissions, policy, extensions) = (&part.submissions, &part.policy, &part.extensions);
+
+    // For each student, get the submissions that could be chosen under the policy's strategy, as
+    // well as their activated submission (which may be included in the former collection as well)
+    let submission_candidates = grading::candidates(roster, submissions, extensions, policy);
+
     // Compare the canonical submission to all of these submissions, ensuring that the tests match
     let canonical = {
         // Find the canonical submission
-        let canonical_id = args.value_of("canonical").unwrap();
-        let canonical_submission = submissions.get_active_submission(roster.find_student_by_uid(canonical_id.to_owned()).expect("No submitter with given ID for canonical found")).expect("No canonical submission found");
+        let canonical_submission = canonical_submission(submissions, canonical_student);
 
-        // Make sure the score is 100
-        if canonical_submission.raw_score() != 1.0 {
+        // Make sure the score is 100 (or more, with extra credit)
+        if canonical_submission.raw_score() < 1.0 {
             panic!("Canonical submission did not receive full points.");
         }
 
-        // Validate all other submissions against the canonical
+        // Validate all other submissions against the canonical, so they are graded on the same
+        // tests (or all on their top-level score)
         let mut invalid_submissions = Vec::new();
 
-        for (_, (a, ls)) in submission_candidates.iter() {
-            if !a.validate_with_canonical(&canonical_submission) {
-                invalid_submissions.push(a);
-            }
-            for l in ls.iter() {
-                if let Some(l) = l {
-                    if !l.validate_with_canonical(&canonical_submission) {
-                        invalid_submissions.push(l);
-                    }
+        for c in submission_candidates.values() {
+            for l in c.active.iter().chain(c.others.iter()) {
+                if !l.validate_with_canonical(canonical_submission) {
+                    invalid_submissions.push(l);
                 }
             }
         }
 
-        if invalid_submissions.len() > 0 {
+        if !invalid_submissions.is_empty() {
             // Write invalid submission IDs to file
             let filename = format!("{}/invalid_submission_ids", output_dir);
-            let mut file = File::create(&filename).unwrap();
+            let mut file = create_output(&filename);
             for invalid in invalid_submissions.iter() {
-                write!(file, "{}\n", invalid.id).unwrap();
+                writeln!(file, "{}", invalid.id).unwrap();
             }
             panic!("Some submissions were invalid; IDs written to {}", filename);
         }
@@ -137,51 +482,96 @@ fn main() {
         canonical_submission
     };
 
-    // Of the submissions collected above, find the best scoring one for each student.  In the case
-    // of a tie, prefer the active one, or the earliest submitted.
-    let best_submissions: HashMap<&Student, &Submission> = submission_candidates.into_iter().map(|(student, (a, ls))| {
-        let extension = extensions.find(student);
-        let (mut best, mut best_score) = (a, a.score(&deadlines, extension).unwrap());
-        for l in ls.iter() {
-            if let Some(l) = l {
-                let new_score = l.score(&deadlines, extension).unwrap();
-                if new_score > best_score {
-                    best = l;
-                    best_score = new_score;
-                }
-            }
-        }
-        (student, best)
-    }).collect();
+    // Of the submissions collected above, find the best scoring one for each student
+    let best_submissions = grading::choose(&submission_candidates, extensions, policy);
+
+    // Compute each student's grade, and find the students who don't have one
+    let grades = grading::grades(&best_submissions, extensions, policy);
+    let missing = missing::find(roster, submissions, extensions, policy, &grades);
 
     // Generate the parts.csv
+    let tests = canonical.results();
     {
-        let mut file = File::create(format!("{}/parts.csv", output_dir)).unwrap();
-        let mut tests = canonical.tests.clone();
-        tests.sort_by(|a, b| {
-            a.number.partial_cmp(&b.number).unwrap()
-        });
+        let mut file = create_output(&format!("{}/parts.csv", output_dir));
         for t in tests.iter() {
-            write!(file, "{},{}\n", t.name, t.max).unwrap();
+            writeln!(file, "{},{}", t.name, t.max).unwrap();
         }
     }
 
     // Generate the grades.csv
     {
-        let mut file = File::create(format!("{}/grades.csv", output_dir)).unwrap();
+        let mut file = create_output(&format!("{}/grades.csv", output_dir));
 
-        for (_, submission) in best_submissions.iter() {
-            let penalty = submission.compute_penalty(&deadlines, extensions.find(submission.student)).unwrap();
+        for submission in best_submissions.values() {
+            let penalty = submission.compute_penalty(&policy.deadlines_for(submission.student), extensions.find(submission.student)).or_exit();
 
             if penalty < 1. {
-                write!(file, "{}", submission.to_string()).unwrap();
+                write!(file, "{}", submission).unwrap();
+
+                let reason = match (penalty > submission.excess_penalty, submission.excess_penalty > 0.) {
+                    (true, true) => "Late; over submission limit",
+                    (false, true) => "Over submission limit",
+                    _ => "Late",
+                };
 
                 if penalty != 0. {
-                    write!(file, "{},*,*{},Late\n", submission.student.directory_id, 1. - penalty).unwrap();
+                    writeln!(file, "{},*,*{},{}", submission.student.directory_id, 1. - penalty, reason).unwrap();
                 } else {
-                    write!(file, "{},*,*1,\n", submission.student.directory_id).unwrap();
+                    writeln!(file, "{},*,*1,", submission.student.directory_id).unwrap();
+                }
+
+                // Apply the penalty to each test for the combined grades
+                if let Some(combined) = combined.as_mut() {
+                    for t in submission.results().iter() {
+                        writeln!(combined, "{},{},{},{}", submission.student.directory_id, t.name, t.score * (1. - penalty), if penalty != 0. { reason } else { "" }).unwrap();
+                    }
                 }
             }
         }
+
+        // Give an explicit zero to the students without a gradable submission, if requested
+        if args.is_present("zero_missing") {
+            write_zeros(&mut file, &missing, &tests);
+        }
+    }
+
+    // Generate the summary.csv, recording the submission chosen for each student
+    grades.write(&format!("{}/{}", output_dir, SUMMARY_FILE)).or_exit();
+
+    // Generate the missing.csv, listing the students without a gradable submission
+    missing::write(&missing, &format!("{}/missing.csv", output_dir)).or_exit();
+    if !missing.is_empty() {
+        println!("{} students have no gradable submission; see {}/missing.csv", missing.len(), output_dir);
+    }
+
+    // Generate the triage.csv, listing the submissions the autograder didn't finish
+    let triage = triage::find(submissions, extensions, policy, &best_submissions);
+    triage::write(&triage, &format!("{}/triage.csv", output_dir)).or_exit();
+    let affecting = triage.iter().filter(|t| t.could_affect).count();
+    if affecting > 0 {
+        println!("{} failed or unfinished submissions could affect grades and should be re-run; see {}/triage.csv", affecting, output_dir);
+    }
+
+    (best_submissions, grades)
+}
+
+/// Write an explicit zero for every test to a grades.csv for each student without a gradable
+/// submission, along with a multiplier of 0 giving the reason.
+fn write_zeros(file: &mut File, missing: &[missing::Missing], tests: &[TestCase]) {
+    for m in missing.iter() {
+        for t in tests.iter() {
+            writeln!(file, "{},{},0,", m.student.directory_id, t.name).unwrap();
+        }
+        writeln!(file, "{},*,*0,{}", m.student.directory_id, m.reason).unwrap();
+    }
+}
+
+/// Render each student's feedback into the output directory, if a template was given.
+fn write_feedback(args: &ArgMatches, chosen: &std::collections::HashMap<&Student, &Submission>, grades: &GradeSet, extensions: &ExtensionSet, output_dir: &str) {
+    if let Some(template) = args.value_of("feedback") {
+        let template = feedback::Template::load(template).or_exit();
+        let dir = format!("{}/{}", output_dir, feedback::FEEDBACK_DIR);
+        let count = feedback::write(&template, chosen, grades, extensions, &dir).or_exit();
+        println!("Wrote feedback for {} students to {}", count, dir);
     }
 }
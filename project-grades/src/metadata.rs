@@ -0,0 +1,519 @@
+//! Parsing the submission_metadata.yml file produced by Gradescope into the submitters and their
+//! submissions, before they are matched to the roster.  The file is read a line at a time and
+//! split into its top-level entries, one per active submission, which are each read straight into
+//! typed structs and converted in parallel, so only a few entries' text is in memory at once.
+//! Parsing is still most of the time spent, so the result is saved as a snapshot next to the file
+//! and reused as long as the file hasn't changed.
+
+use crate::submissions::{Error, TestCase, Totals};
+use std::{fs::{self, File}, io::{self, BufRead, BufReader, BufWriter, Write}, cell::Cell, fmt};
+use serde::de::{Deserializer, DeserializeSeed, Visitor, MapAccess, Error as _};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, NaiveDateTime, Utc, TimeZone};
+
+/// The version of the snapshot format, which must be changed whenever `Metadata` or the way it is
+/// parsed changes so that older snapshots are ignored.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// A single submission as recorded in the metadata.  The time and the tests are only needed for
+/// some submissions, so any error in them is kept (as its error code) to be reported if they are.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Entry {
+    // The submission ID
+    pub id: u64,
+    // The submission's status in Gradescope, which is "processed" once it's been graded
+    pub status: Option<String>,
+    // Submission time
+    pub time: Result<DateTime<Utc>, usize>,
+    // Individual test results
+    pub tests: Result<Vec<TestCase>, usize>,
+    // The top-level results, if there are no individual test results (or the list of them is empty)
+    pub totals: Option<Totals>,
+}
+
+/// A submitter, with their active submission and the ones before it.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Submitter {
+    // The SID the submitter used
+    pub sid: String,
+    // The submitter's name and email, as recorded by Gradescope
+    pub name: Option<String>,
+    pub email: Option<String>,
+    // The active submission
+    pub active: Entry,
+    // The submitter's other submissions
+    pub history: Vec<Entry>,
+}
+
+/// The contents of a submission_metadata.yml file.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Metadata {
+    pub submitters: Vec<Submitter>,
+}
+
+/// A parsed metadata file, along with what identifies the file it was parsed from.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Snapshot {
+    version: u32,
+    // The SHA-256 hash of the YAML file
+    hash: Vec<u8>,
+    metadata: Metadata,
+}
+
+/// A submission as written in the YAML file.  Every field is optional, so that a missing one is
+/// reported along with the submission it's missing from.
+#[derive(serde::Deserialize)]
+struct RawSubmission {
+    #[serde(rename = ":id")]
+    id: Option<u64>,
+    #[serde(rename = ":submitters")]
+    submitters: Option<Vec<RawSubmitter>>,
+    #[serde(rename = ":status")]
+    status: Option<String>,
+    #[serde(rename = ":created_at")]
+    created_at: Option<String>,
+    #[serde(rename = ":results")]
+    results: Option<RawResults>,
+    #[serde(rename = ":history")]
+    history: Option<Vec<RawSubmission>>,
+}
+
+/// A submitter as written in the YAML file.
+#[derive(serde::Deserialize)]
+struct RawSubmitter {
+    #[serde(rename = ":sid")]
+    sid: Option<String>,
+    #[serde(rename = ":name")]
+    name: Option<String>,
+    #[serde(rename = ":email")]
+    email: Option<String>,
+}
+
+/// The autograder's results as written in the YAML file.
+#[derive(serde::Deserialize)]
+struct RawResults {
+    tests: Option<Vec<RawTest>>,
+    score: Option<f64>,
+    execution_time: Option<f64>,
+    output: Option<String>,
+}
+
+/// A single test's result as written in the YAML file.
+#[derive(serde::Deserialize)]
+struct RawTest {
+    name: Option<String>,
+    number: Option<String>,
+    score: Option<f64>,
+    max_score: Option<f64>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    extra_credit: bool,
+}
+
+/// Reads the top-level mapping of the YAML file submission by submission, keeping track of where
+/// it is so that an error can be traced back to the submission it's in.
+struct SubmissionsSeed<'a> {
+    // The ID of the submission being read (if any) and the error code to report if reading fails
+    position: &'a Cell<(Option<u64>, usize)>,
+}
+
+impl <'de> DeserializeSeed<'de> for SubmissionsSeed<'_> {
+    type Value = Vec<(u64, RawSubmission)>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl <'de> Visitor<'de> for SubmissionsSeed<'_> {
+    type Value = Vec<(u64, RawSubmission)>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a mapping of submissions")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut submissions = Vec::new();
+
+        loop {
+            // Extract the active submission id
+            self.position.set((None, 9));
+            let name: String = match map.next_key()? {
+                Some(name) => name,
+                None => break,
+            };
+            let id = match name.strip_prefix("submission_") {
+                Some(id) => id.parse::<u64>().map_err(|_| {
+                    self.position.set((None, 7));
+                    A::Error::custom("invalid submission ID")
+                })?,
+                None => {
+                    self.position.set((None, 8));
+                    return Err(A::Error::custom("expected a submission"));
+                }
+            };
+
+            self.position.set((Some(id), 6));
+            submissions.push((id, map.next_value()?));
+        }
+
+        self.position.set((None, 6));
+        Ok(submissions)
+    }
+}
+
+impl Entry {
+    /// Convert a submission read from the YAML file into an `Entry`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The submission ID
+    /// * `raw` - The submission
+    fn parse(id: u64, raw: &RawSubmission) -> Entry {
+        // Without individual test results, fall back to the top-level ones
+        let totals = raw.results.as_ref().filter(|r| r.tests.as_ref().is_none_or(Vec::is_empty)).map(|r| Totals {
+            score: r.score,
+            execution_time: r.execution_time,
+            output: r.output.clone(),
+            max: 0.,
+        });
+
+        Entry { id, status: raw.status.clone(), time: parse_time(raw), tests: parse_tests(raw), totals }
+    }
+}
+
+/// Get the time a submission was made, or the error code if it is missing or malformed.
+///
+/// # Arguments
+///
+/// * `raw` - The submission
+fn parse_time(raw: &RawSubmission) -> Result<DateTime<Utc>, usize> {
+    let time = raw.created_at.as_ref().ok_or(1usize)?;
+    Ok(Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S.%f Z").or(Err(0usize))?))
+}
+
+/// Convert the results for a submission into the list of `TestCase`s it was graded on, or the
+/// error code if they are missing or malformed.  Results with no tests give an empty list.
+///
+/// # Arguments
+///
+/// * `raw` - The submission
+fn parse_tests(raw: &RawSubmission) -> Result<Vec<TestCase>, usize> {
+    let results = raw.results.as_ref().ok_or(5usize)?;
+    let tests = match &results.tests {
+        Some(tests) => tests,
+        None => return Ok(Vec::new()),
+    };
+
+    tests.iter().map(|t| {
+        if let (Some(name), Some(number), Some(score), Some(max)) = (&t.name, &t.number, t.score, t.max_score) {
+            let number = number.parse::<f64>().or(Err(20usize))?;
+
+            // Only the tests the autograder marks are extra credit here; a test worth no points is
+            // usually informational, and the policy can mark the rest by name or tag
+            Ok(TestCase { name: name.clone(), number, score, max, tags: t.tags.clone(), extra_credit: t.extra_credit })
+        } else {
+            Err(3)
+        }
+    }).collect()
+}
+
+impl Submitter {
+    /// Convert a submission read from the YAML file, with its history, into a `Submitter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the active submission
+    /// * `raw` - The active submission
+    ///
+    /// # Errors
+    ///
+    /// If the submission is missing its submitter or history, will return
+    /// `SubmissionFormatError` with its ID.
+    fn parse(id: u64, raw: &RawSubmission) -> Result<Submitter, Error> {
+        let format_error = |code| Error::SubmissionFormatError(Some(id), code);
+
+        // Get the submitter, of which there should only be one
+        let submitter = match &raw.submitters {
+            Some(submitters) if submitters.len() == 1 => &submitters[0],
+            Some(_) => return Err(format_error(10)),
+            None => return Err(format_error(14)),
+        };
+        let sid = submitter.sid.clone().ok_or(format_error(12))?;
+
+        // Process sub-entries
+        let history = raw.history.as_ref().ok_or(format_error(17))?.iter().map(|raw| match raw.id {
+            Some(id) => Ok(Entry::parse(id, raw)),
+            None => Err(format_error(16)),
+        }).collect::<Result<_, _>>()?;
+
+        Ok(Submitter {
+            sid,
+            name: submitter.name.clone(),
+            email: submitter.email.clone(),
+            active: Entry::parse(id, raw),
+            history,
+        })
+    }
+
+    /// Parse the text of some of the top-level entries of the YAML file into their submitters.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The entries, as they appear in the file
+    ///
+    /// # Errors
+    ///
+    /// If the entries aren't in the format Gradescope produces, will return
+    /// `SubmissionFormatError` with the ID of the first invalid submission (or None if the error
+    /// was not related to a particular submission).
+    fn parse_all(text: &str) -> Result<Vec<Submitter>, Error> {
+        // Read the submissions into their typed form, noting where any error happens
+        let position = Cell::new((None, 6));
+        let submissions = SubmissionsSeed { position: &position }.deserialize(serde_yaml::Deserializer::from_str(text)).map_err(|_| match position.get() {
+            // The text isn't valid YAML, so the error is somewhere in the entry it starts with
+            (None, 6) => Error::SubmissionFormatError(text.strip_prefix("submission_").and_then(|t| t.split(':').next()).and_then(|id| id.parse().ok()), 6),
+            (id, code) => Error::SubmissionFormatError(id, code),
+        })?;
+
+        submissions.iter().map(|(id, raw)| Submitter::parse(*id, raw)).collect()
+    }
+}
+
+impl Metadata {
+    /// Load a submission_metadata.yml file, using its snapshot if there is one for the file as it
+    /// is now, and otherwise parsing it and saving a snapshot for next time.  A snapshot that can't
+    /// be saved (for example, if the directory is read-only) is skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the YAML file
+    ///
+    /// # Errors
+    ///
+    /// If the YAML file cannot be read, will return `SubmissionReadError`.  If it isn't in the
+    /// format Gradescope produces, will return `SubmissionFormatError` with the ID of the first
+    /// invalid submission (or None if the error was not related to a particular submission).
+    pub fn load(file: &str) -> Result<Metadata, Error> {
+        // Hash the file, without reading it all into memory
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(file).or(Err(Error::SubmissionReadError))?, &mut hasher).or(Err(Error::SubmissionReadError))?;
+        let hash = hasher.finalize().to_vec();
+
+        let snapshot_file = format!("{}.snapshot", file);
+        if let Some(metadata) = Metadata::read_snapshot(&snapshot_file, &hash) {
+            return Ok(metadata);
+        }
+
+        let metadata = Metadata::parse(File::open(file).or(Err(Error::SubmissionReadError))?)?;
+        let snapshot = Snapshot { version: SNAPSHOT_VERSION, hash, metadata };
+        Metadata::write_snapshot(&snapshot_file, &snapshot);
+
+        Ok(snapshot.metadata)
+    }
+
+    /// Save a snapshot, writing it to a temporary file first and then moving it into place, so that
+    /// a run interrupted while saving it never leaves a truncated snapshot behind.  If it can't be
+    /// saved, it is skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the snapshot
+    /// * `snapshot` - The snapshot to save
+    fn write_snapshot(file: &str, snapshot: &Snapshot) {
+        let temp = format!("{}.{}.tmp", file, std::process::id());
+        let written = File::create(&temp).ok().is_some_and(|out| {
+            let mut out = BufWriter::new(out);
+            bincode::serialize_into(&mut out, snapshot).is_ok() && out.flush().is_ok()
+        });
+
+        if !written || fs::rename(&temp, file).is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+    }
+
+    /// Read a snapshot, if it exists and was made from the file with the given hash by this
+    /// version of the program.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the snapshot
+    /// * `hash` - The hash of the YAML file
+    fn read_snapshot(file: &str, hash: &[u8]) -> Option<Metadata> {
+        let snapshot: Snapshot = bincode::deserialize_from(BufReader::new(File::open(file).ok()?)).ok()?;
+
+        if snapshot.version == SNAPSHOT_VERSION && snapshot.hash == hash {
+            Some(snapshot.metadata)
+        } else {
+            None
+        }
+    }
+
+    /// Parse the YAML metadata, one top-level entry at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The YAML file
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read, will return `SubmissionReadError`.  If it isn't in the format
+    /// Gradescope produces, will return `SubmissionFormatError` with the ID of the first invalid
+    /// submission (or None if the error was not related to a particular submission).
+    fn parse(file: File) -> Result<Metadata, Error> {
+        // Parse the entries in parallel as they are read, keeping their order so that the first
+        // error in the file is the one reported
+        let mut entries: Vec<(usize, Result<Vec<Submitter>, Error>)> = Entries::new(BufReader::new(file)).enumerate().par_bridge().map(|(i, text)| {
+            (i, text.or(Err(Error::SubmissionReadError)).and_then(|text| Submitter::parse_all(&text)))
+        }).collect();
+        entries.sort_by_key(|(i, _)| *i);
+
+        let mut submitters = Vec::new();
+        for (_, entry) in entries {
+            submitters.extend(entry?);
+        }
+        Ok(Metadata { submitters })
+    }
+}
+
+/// Splits a YAML file into the text of its top-level entries, reading it a line at a time.  Each
+/// entry starts at a line that isn't indented, and the document markers and any comments before
+/// the first entry are left out.
+struct Entries<R> {
+    lines: io::Lines<R>,
+    // The first line of the next entry, if it has been read
+    next: Option<String>,
+}
+
+impl <R: BufRead> Entries<R> {
+    fn new(reader: R) -> Entries<R> {
+        Entries { lines: reader.lines(), next: None }
+    }
+}
+
+impl <R: BufRead> Iterator for Entries<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let starts_entry = |line: &str| !line.is_empty() && !line.starts_with([' ', '\t', '#']) && line != "---" && line != "...";
+
+        // Find the first line of the entry
+        let mut text = match self.next.take() {
+            Some(line) => line,
+            None => loop {
+                match self.lines.next()? {
+                    Ok(line) if starts_entry(&line) => break line,
+                    Ok(_) => continue,
+                    Err(e) => return Some(Err(e)),
+                }
+            },
+        };
+
+        // Then add every line up to the next entry
+        for line in self.lines.by_ref() {
+            match line {
+                Ok(line) if starts_entry(&line) => {
+                    self.next = Some(line);
+                    break;
+                }
+                Ok(line) if line == "---" || line == "..." => continue,
+                Ok(line) => {
+                    text.push('\n');
+                    text.push_str(&line);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        text.push('\n');
+        Some(Ok(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::path::Path;
+
+    /// Metadata with a single submission, scoring `score` on its one test.
+    fn metadata(score: f64) -> String {
+        format!("submission_1:
+  :submitters:
+  - :name: Student 1
+    :sid: '1'
+    :email: s1@umd.edu
+  :created_at: 2021-03-01 10:00:00.000000000 Z
+  :status: processed
+  :results:
+    tests:
+    - name: t1
+      number: '1'
+      score: {:.1}
+      max_score: 1.0
+  :history: []
+", score)
+    }
+
+    fn score(metadata: &Metadata) -> f64 {
+        metadata.submitters[0].active.tests.as_ref().unwrap()[0].score
+    }
+
+    #[test]
+    fn reads_each_entry_in_order() {
+        let text = format!("---\n# Exported from Gradescope\n{}{}", metadata(1.).replace("submission_1", "submission_2"), metadata(0.));
+        let file = file("metadata_entries", "submission_metadata.yml", &text);
+
+        let metadata = Metadata::load(&file).unwrap();
+        assert_eq!(metadata.submitters.iter().map(|s| s.active.id).collect::<Vec<_>>(), vec![2, 1]);
+    }
+
+    #[test]
+    fn errors_name_the_offending_submission() {
+        let missing_history = metadata(1.).replace("submission_1", "submission_2").replace("  :history: []\n", "");
+        let file = file("metadata_errors", "submission_metadata.yml", &format!("{}{}", metadata(1.), missing_history));
+        assert!(matches!(Metadata::load(&file), Err(Error::SubmissionFormatError(Some(2), 17))));
+
+        let malformed = metadata(1.).replace("submission_1", "submission_3").replace("score: 1.0", "score: [1.0");
+        fs::write(&file, format!("{}{}", metadata(1.), malformed)).unwrap();
+        assert!(matches!(Metadata::load(&file), Err(Error::SubmissionFormatError(Some(3), _))));
+    }
+
+    #[test]
+    fn a_changed_file_invalidates_its_snapshot() {
+        let file = file("snapshot_changed", "submission_metadata.yml", &metadata(0.));
+        assert_eq!(score(&Metadata::load(&file).unwrap()), 0.);
+        assert!(Path::new(&format!("{}.snapshot", file)).exists());
+
+        fs::write(&file, metadata(1.)).unwrap();
+        assert_eq!(score(&Metadata::load(&file).unwrap()), 1.);
+    }
+
+    #[test]
+    fn a_corrupt_snapshot_falls_back_to_the_file() {
+        let file = file("snapshot_corrupt", "submission_metadata.yml", &metadata(1.));
+        let snapshot = format!("{}.snapshot", file);
+        Metadata::load(&file).unwrap();
+
+        // Cut the snapshot short, as an interrupted write would have
+        let bytes = fs::read(&snapshot).unwrap();
+        fs::write(&snapshot, &bytes[..bytes.len() / 2]).unwrap();
+
+        assert_eq!(score(&Metadata::load(&file).unwrap()), 1.);
+        assert_eq!(fs::read(&snapshot).unwrap(), bytes);
+    }
+
+    #[test]
+    fn only_marked_tests_are_extra_credit() {
+        let raw: RawSubmission = serde_yaml::from_str(":results:
+  tests:
+    - {name: info, number: '1', score: 0.0, max_score: 0.0}
+    - {name: bonus, number: '2', score: 1.0, max_score: 1.0, extra_credit: true}
+").unwrap();
+
+        let tests = parse_tests(&raw).unwrap();
+        assert_eq!(tests.iter().map(|t| (t.name.as_str(), t.extra_credit)).collect::<Vec<_>>(), vec![("info", false), ("bonus", true)]);
+    }
+}
@@ -0,0 +1,171 @@
+//! Finding the students on the roster who have no gradable submission, so that they can be given
+//! an explicit zero rather than silently being left out of the grades.
+
+use crate::{roster::*, extensions::*, submissions::*, grades::*, policy::*, grading};
+use std::fmt;
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    MissingWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingWriteError => write!(f, "Couldn't write the missing students"),
+        }
+    }
+}
+
+/// Why a student has no gradable submission.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Reason {
+    // The student never submitted.
+    NoSubmission,
+    // Every submission the student made failed (or has not finished) in the autograder.
+    OnlyFailed,
+    // Every submission the student made was after the final deadline.
+    PastDeadline,
+    // The student's active submission failed (or has not finished), so the others aren't graded.
+    ActiveFailed,
+    // Every submission the strategy would grade was excluded by the submission limit, or penalized
+    // for being over it until it was worth nothing.
+    OverLimit,
+    // Every submission the student made has no per-test results, and was excluded since others
+    // have them.
+    NoTestResults,
+}
+
+/// A student with no gradable submission.
+pub struct Missing<'r> {
+    pub student: &'r Student,
+    pub reason: Reason,
+}
+
+/// A single row of the CSV written by `write`.
+#[derive(serde::Serialize)]
+struct MissingRow<'r> {
+    #[serde(rename = "UID")]
+    uid: &'r str,
+    #[serde(rename = "DID")]
+    directory_id: &'r str,
+    #[serde(rename = "Name")]
+    name: Option<&'r str>,
+    #[serde(rename = "Reason")]
+    reason: String,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::NoSubmission => write!(f, "No submission"),
+            Reason::OnlyFailed => write!(f, "Only failed runs"),
+            Reason::PastDeadline => write!(f, "Past final deadline"),
+            Reason::ActiveFailed => write!(f, "Active submission failed"),
+            Reason::OverLimit => write!(f, "Over submission limit"),
+            Reason::NoTestResults => write!(f, "No per-test results"),
+        }
+    }
+}
+
+/// Find every student on the roster who did not receive any credit, and why.  The reason for a
+/// student without a grade comes from the submissions the policy's strategy would consider.
+///
+/// # Arguments
+///
+/// * `roster` - The students who should have a grade
+/// * `submissions` - All of the submissions for the project
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+/// * `grades` - The grades computed for the project
+pub fn find<'r>(roster: &'r Roster, submissions: &SubmissionSet<'r>, extensions: &ExtensionSet, policy: &Policy, grades: &GradeSet) -> Vec<Missing<'r>> {
+    let mut missing: Vec<_> = roster.students.iter().filter_map(|student| {
+        let reason = match grades.find(&student.uid) {
+            Some(grade) if grade.penalty < 1. => return None,
+            Some(grade) => {
+                // A submission on time can only lose all of its credit to the submission limit
+                let submission = submissions.submissions.iter().find(|s| s.id == grade.submission);
+                let late = submission.and_then(|s| period(&s.time, &policy.deadlines_for(student), extensions.find(student)).ok().flatten());
+                if late.is_some_and(|(_, penalty)| penalty < 1.) { Reason::OverLimit } else { Reason::PastDeadline }
+            }
+            None => {
+                let processed = submissions.get_submissions(student);
+                let failed: Vec<_> = submissions.skipped.iter().filter(|s| s.student == student).collect();
+
+                if grading::candidates_for(student, submissions, extensions, policy).is_some() {
+                    Reason::PastDeadline
+                } else if failed.iter().any(|s| s.active) && processed.iter().any(|s| !s.excluded) {
+                    Reason::ActiveFailed
+                } else if !processed.is_empty() && processed.iter().all(|s| s.tests.is_empty()) && submissions.submissions.iter().any(|s| !s.tests.is_empty()) {
+                    Reason::NoTestResults
+                } else if !processed.is_empty() {
+                    Reason::OverLimit
+                } else if !failed.is_empty() {
+                    Reason::OnlyFailed
+                } else {
+                    Reason::NoSubmission
+                }
+            }
+        };
+
+        Some(Missing { student, reason })
+    }).collect();
+
+    missing.sort_by(|a, b| a.student.directory_id.cmp(&b.student.directory_id));
+    missing
+}
+
+/// Write the missing students to a CSV file.
+///
+/// # Arguments
+///
+/// * `missing` - The students from `find`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `MissingWriteError`.
+pub fn write(missing: &[Missing], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::MissingWriteError))?;
+
+    for m in missing.iter() {
+        wtr.serialize(MissingRow {
+            uid: &m.student.uid,
+            directory_id: &m.student.directory_id,
+            name: m.student.name.as_deref(),
+            reason: m.reason.to_string(),
+        }).or(Err(Error::MissingWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::MissingWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accommodations::AccommodationSet, testing::*};
+
+    /// Grade student "1" under a policy and find why they got no credit.
+    fn reason(yaml: &str, made: &[&str]) -> Reason {
+        let roster = roster(&["1"]);
+        let policy = policy(yaml);
+        let mut submissions = SubmissionSet::new(&roster);
+        for (i, at) in made.iter().enumerate() {
+            submissions.submissions.push(Submission { active: i == made.len() - 1, ..submission(&roster.students[0], i as u64 + 1, at, &[1.]) });
+        }
+
+        grading::prepare(&roster, &mut submissions, &policy, None);
+        let parts = grading::parts(&roster, &submissions, &policy, &AccommodationSet::empty(), &ExtensionSet::empty()).unwrap();
+        let grades = grading::grade_parts(&roster, &parts);
+        find(&roster, &submissions, &ExtensionSet::empty(), &policy, &grades)[0].reason
+    }
+
+    #[test]
+    fn no_credit_on_time_is_blamed_on_the_submission_limit() {
+        let limited = "due_date: 2021-03-01 23:59 +0000\nsubmission_limit:\n  count: 0\n  penalty: 1\n";
+        assert_eq!(reason(limited, &["2021-03-01 10:00 +0000"]), Reason::OverLimit);
+        assert_eq!(reason(limited, &["2021-03-02 10:00 +0000"]), Reason::PastDeadline);
+        assert_eq!(reason("due_date: 2021-03-01 23:59 +0000\n", &["2021-03-02 10:00 +0000"]), Reason::PastDeadline);
+    }
+}
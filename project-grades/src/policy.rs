@@ -0,0 +1,562 @@
+//! The late policy for a project: its due date, and any later deadlines after which submissions
+//! are accepted with a penalty.
+
+use crate::{roster::*, components::*, submissions::*};
+use std::{fs::File, collections::{BTreeMap, HashSet}, fmt};
+use chrono::{DateTime, Utc, Duration};
+use clap::ArgMatches;
+
+/// The format of dates given on the command line and in policy files.
+pub const DATE_FORMAT: &str = "%Y-%m-%d %H:%M %z";
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    PolicyReadError,
+    PolicyFormatError(String),
+    InvalidDateError(String),
+    InvalidTierError(String),
+    InvalidComponentError(String),
+    InvalidStrategyError(String),
+    InvalidMaxError(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PolicyReadError => write!(f, "Couldn't read the policy"),
+            Error::PolicyFormatError(e) => write!(f, "The policy is invalid: {}", e),
+            Error::InvalidDateError(date) => write!(f, "Invalid date \"{}\", expected YYYY-MM-DD HH:MM +/-ZZZZ", date),
+            Error::InvalidTierError(tier) => write!(f, "Invalid deadline \"{}\", expected HOURS,PENALTY", tier),
+            Error::InvalidComponentError(component) => write!(f, "Invalid component \"{}\", expected NAME,WEIGHT,FILE[,penalized]", component),
+            Error::InvalidStrategyError(name) => write!(f, "Unknown strategy or tie-break rule \"{}\"", name),
+            Error::InvalidMaxError(max) => write!(f, "Invalid max \"{}\", expected a number of points", max),
+        }
+    }
+}
+
+/// A later deadline, given as the number of hours after the due date, and the penalty for
+/// submitting before it (0.0 is no penalty, 1.0 is full penalty).
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Tier {
+    pub hours: u32,
+    pub penalty: f64,
+}
+
+/// Overrides of the late policy for a single lab section, for example when a holiday falls on
+/// that section's lab day.  Anything not given falls back to the policy's.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SectionPolicy {
+    // The section's due date, in the format of `DATE_FORMAT`.
+    #[serde(default, deserialize_with = "deserialize_optional_date")]
+    pub due_date: Option<DateTime<Utc>>,
+    // The section's later deadlines, relative to its due date.
+    #[serde(default)]
+    pub deadlines: Option<Vec<Tier>>,
+}
+
+/// A limit on how many times students may submit, to discourage using the autograder as a
+/// debugger.  Submissions beyond the limit are either excluded from grading, or penalized.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SubmissionLimit {
+    // The number of submissions allowed.
+    pub count: usize,
+    // Whether the count resets every day, where days end at the time of day of the due date.
+    #[serde(default)]
+    pub per_day: bool,
+    // The penalty for each submission beyond the limit, if they are penalized rather than
+    // excluded (e.g. with 0.02, the third submission over the limit gets 6% off).
+    #[serde(default)]
+    pub penalty: Option<f64>,
+}
+
+/// A group of tests with its own deadlines, for projects whose parts are graded in one assignment
+/// but due at different times.  Tests are in the group if their name starts with the prefix or
+/// their number is in the range (inclusive).
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct TestGroup {
+    // The name of the group, which its output directory is named after.
+    pub name: String,
+    // The prefix of the names of the tests in the group.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // The lowest and highest numbers of the tests in the group.
+    #[serde(default)]
+    pub numbers: Option<[f64; 2]>,
+    // The group's due date, in the format of `DATE_FORMAT`.
+    #[serde(deserialize_with = "deserialize_date")]
+    pub due_date: DateTime<Utc>,
+    // The group's later deadlines, relative to its due date.
+    #[serde(default)]
+    pub deadlines: Vec<Tier>,
+}
+
+/// Which tests are extra credit, beyond the ones the autograder marks as such, and how much the
+/// total can exceed the max.
+#[derive(serde::Deserialize, Clone, Default, Debug)]
+pub struct ExtraCredit {
+    // The names of the extra credit tests.
+    #[serde(default)]
+    pub tests: HashSet<String>,
+    // The tags of the extra credit tests in the autograder's results.
+    #[serde(default)]
+    pub tags: HashSet<String>,
+    // The most the score can be as a fraction of the max (e.g. 1.1 for 10% extra credit), if
+    // limited.
+    #[serde(default)]
+    pub cap: Option<f64>,
+}
+
+/// Release tokens, which limit how often students could see their release test results: each
+/// submission uses a token, and each token comes back some time after it is used.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ReleaseTokens {
+    // The names of the release tests.
+    #[serde(default)]
+    pub tests: HashSet<String>,
+    // The tags of the release tests in the autograder's results.
+    #[serde(default)]
+    pub tags: HashSet<String>,
+    // The number of tokens each student has.
+    pub count: usize,
+    // The number of hours after a token is used that it comes back.
+    pub regeneration_hours: f64,
+}
+
+/// How to choose which of a student's submissions to grade.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    // The best of the active submission and the latest submission in each penalty period.
+    #[default]
+    Default,
+    // Only the active submission, respecting the student's choice in Gradescope.
+    Active,
+    // Only the latest submission made.
+    Latest,
+    // The best of every submission made.
+    Best,
+}
+
+/// Which submission to choose when several score the same.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TieBreak {
+    // The active submission, or otherwise the earliest.
+    #[default]
+    Active,
+    // The earliest submission.
+    Earliest,
+    // The latest submission.
+    Latest,
+}
+
+/// A complete late policy.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Policy {
+    // A name to identify the policy by when comparing several of them.
+    #[serde(default)]
+    pub name: String,
+    // The date the project was released, in the format of `DATE_FORMAT`, if known.
+    #[serde(default, deserialize_with = "deserialize_optional_date")]
+    pub release_date: Option<DateTime<Utc>>,
+    // The normal due date, in the format of `DATE_FORMAT`.
+    #[serde(deserialize_with = "deserialize_date")]
+    pub due_date: DateTime<Utc>,
+    // The later deadlines, in order.
+    #[serde(default)]
+    pub deadlines: Vec<Tier>,
+    // Overrides for individual sections, keyed by the section as it appears in the roster.
+    #[serde(default)]
+    pub sections: BTreeMap<String, SectionPolicy>,
+    // Manually graded components to combine with the autograder score.
+    #[serde(default)]
+    pub components: Vec<Component>,
+    // The limit on the number of submissions, if any.
+    #[serde(default)]
+    pub submission_limit: Option<SubmissionLimit>,
+    // How to choose which submission to grade.
+    #[serde(default)]
+    pub strategy: Strategy,
+    // Which submission to choose when several score the same.
+    #[serde(default)]
+    pub tie_break: TieBreak,
+    // Groups of tests with their own deadlines.  Tests in no group use the policy's deadlines.
+    #[serde(default)]
+    pub groups: Vec<TestGroup>,
+    // The extra credit tests, and the cap on the total.
+    #[serde(default)]
+    pub extra_credit: ExtraCredit,
+    // The release tokens, if their use is enforced.
+    #[serde(default)]
+    pub release_tokens: Option<ReleaseTokens>,
+    // The points the project is out of, for scoring submissions the autograder gave only a
+    // top-level score for.
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+/// Parse a date in the format of `DATE_FORMAT`.
+///
+/// # Arguments
+///
+/// * `date` - The date string to parse.
+///
+/// # Errors
+///
+/// If the date is not in the expected format, will return `InvalidDateError`.
+pub fn parse_date(date: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_str(date, DATE_FORMAT).map(|d| d.with_timezone(&Utc)).or(Err(Error::InvalidDateError(date.to_owned())))
+}
+
+/// Parse the name of a strategy or tie-break rule given on the command line, the same way it is
+/// written in policy files.
+fn parse_name<T: serde::de::DeserializeOwned>(name: &str) -> Result<T, Error> {
+    serde_yaml::from_str(name).or(Err(Error::InvalidStrategyError(name.to_owned())))
+}
+
+fn deserialize_date<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let date: String = serde::Deserialize::deserialize(deserializer)?;
+    parse_date(&date).map_err(|_| serde::de::Error::custom(format!("invalid date \"{}\", expected YYYY-MM-DD HH:MM +/-ZZZZ", date)))
+}
+
+fn deserialize_optional_date<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    deserialize_date(deserializer).map(Some)
+}
+
+impl Tier {
+    /// Parse a tier given on the command line as two comma-separated values, HOURS,PENALTY.
+    ///
+    /// # Arguments
+    ///
+    /// * `tier` - The string to parse.
+    ///
+    /// # Errors
+    ///
+    /// If the string is not in the expected format, will return `InvalidTierError`.
+    pub fn parse(tier: &str) -> Result<Tier, Error> {
+        let parts: Vec<_> = tier.split(',').collect();
+        if parts.len() != 2 {
+            return Err(Error::InvalidTierError(tier.to_owned()));
+        }
+
+        // Get the hours and penalty
+        let hours = parts[0].trim().parse::<u32>().or(Err(Error::InvalidTierError(tier.to_owned())))?;
+        let penalty = parts[1].trim().parse::<f64>().or(Err(Error::InvalidTierError(tier.to_owned())))?;
+
+        Ok(Tier { hours, penalty })
+    }
+}
+
+impl ExtraCredit {
+    /// Mark the extra credit tests of every submission, and apply the cap.  Returns the IDs of the
+    /// submissions with no points possible, which all score 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `submissions` - All of the submissions for the project.
+    pub fn apply(&self, submissions: &mut SubmissionSet) -> Vec<u64> {
+        for submission in submissions.submissions.iter_mut() {
+            for test in submission.tests.iter_mut() {
+                if self.tests.contains(&test.name) || test.tags.iter().any(|t| self.tags.contains(t)) {
+                    test.extra_credit = true;
+                }
+            }
+            submission.cap = self.cap;
+        }
+
+        submissions.submissions.iter().filter(|s| s.is_empty()).map(|s| s.id).collect()
+    }
+}
+
+impl ReleaseTokens {
+    /// Whether a test is a release test.
+    ///
+    /// # Arguments
+    ///
+    /// * `test` - The test to check.
+    pub fn is_release_test(&self, test: &TestCase) -> bool {
+        self.tests.contains(&test.name) || test.tags.iter().any(|t| self.tags.contains(t))
+    }
+}
+
+impl TestGroup {
+    /// Whether a test is in this group.
+    ///
+    /// # Arguments
+    ///
+    /// * `test` - The test to check.
+    pub fn contains(&self, test: &TestCase) -> bool {
+        self.prefix.as_ref().is_some_and(|prefix| test.name.starts_with(prefix.as_str()))
+            || self.numbers.is_some_and(|[low, high]| low <= test.number && test.number <= high)
+    }
+}
+
+impl Policy {
+    /// Build the policy given on the command line, either as a policy file with the `policy` arg,
+    /// or with the `due_date` and `deadline` args.  The `release_date` arg may be given either
+    /// way, as may the `strategy`, `tie_break`, and `max` args, and they take precedence over the
+    /// policy file's.  Any `component` args are added to the policy file's components.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The command-line args.
+    ///
+    /// # Errors
+    ///
+    /// Will return any error from `load` if a policy file was given, or `InvalidDateError`,
+    /// `InvalidTierError`, `InvalidComponentError`, `InvalidStrategyError`, or `InvalidMaxError` if
+    /// any of the other args is malformed.
+    pub fn from_args(args: &ArgMatches) -> Result<Policy, Error> {
+        let mut policy = if let Some(file) = args.value_of("policy") {
+            Policy::load(file)?
+        } else {
+            let due_date = parse_date(args.value_of("due_date").unwrap())?;
+
+            let deadlines: Result<Vec<_>, _> = args.values_of("deadline").into_iter().flatten().map(Tier::parse).collect();
+
+            Policy {
+                name: "command line".to_owned(),
+                release_date: None,
+                due_date,
+                deadlines: deadlines?,
+                sections: BTreeMap::new(),
+                components: Vec::new(),
+                submission_limit: None,
+                strategy: Strategy::default(),
+                tie_break: TieBreak::default(),
+                groups: Vec::new(),
+                extra_credit: ExtraCredit::default(),
+                release_tokens: None,
+                max: None,
+            }
+        };
+
+        for component in args.values_of("component").into_iter().flatten() {
+            policy.components.push(Component::parse(component).or(Err(Error::InvalidComponentError(component.to_owned())))?);
+        }
+
+        if let Some(release_date) = args.value_of("release_date") {
+            policy.release_date = Some(parse_date(release_date)?);
+        }
+
+        if let Some(strategy) = args.value_of("strategy") {
+            policy.strategy = parse_name(strategy)?;
+        }
+
+        if let Some(tie_break) = args.value_of("tie_break") {
+            policy.tie_break = parse_name(tie_break)?;
+        }
+
+        if let Some(max) = args.value_of("max") {
+            policy.max = Some(max.parse().or(Err(Error::InvalidMaxError(max.to_owned())))?);
+        }
+
+        Ok(policy)
+    }
+
+    /// Load a single policy from a YAML file, for example:
+    ///
+    /// ```yaml
+    /// release_date: 2021-02-19 12:00 -0500
+    /// due_date: 2021-03-01 23:59 -0500
+    /// deadlines:
+    ///   - hours: 24
+    ///     penalty: 0.1
+    /// sections:
+    ///   "0104":
+    ///     due_date: 2021-03-02 23:59 -0500
+    /// components:
+    ///   - name: style
+    ///     file: style.csv
+    ///     weight: 0.2
+    ///     penalized: true
+    /// submission_limit:
+    ///   count: 5
+    ///   per_day: true
+    /// strategy: best
+    /// tie_break: latest
+    /// groups:
+    ///   - name: p1b
+    ///     prefix: p1b_
+    ///     due_date: 2021-03-08 23:59 -0500
+    /// extra_credit:
+    ///   tags: [bonus]
+    ///   cap: 1.1
+    /// release_tokens:
+    ///   tags: [release]
+    ///   count: 3
+    ///   regeneration_hours: 24
+    /// max: 100
+    /// ```
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the YAML file.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read, will return `PolicyReadError`.  If the file does not describe
+    /// a valid policy, will return `PolicyFormatError` with the parser's message.
+    pub fn load(file: &str) -> Result<Policy, Error> {
+        let file = File::open(file).or(Err(Error::PolicyReadError))?;
+        serde_yaml::from_reader(file).map_err(|e| Error::PolicyFormatError(e.to_string()))
+    }
+
+    /// Load a list of policies from a YAML file, for example:
+    ///
+    /// ```yaml
+    /// - name: one late day
+    ///   due_date: 2021-03-01 23:59 -0500
+    ///   deadlines:
+    ///     - hours: 24
+    ///       penalty: 0.1
+    /// ```
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the YAML file.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read, will return `PolicyReadError`.  If the file does not describe
+    /// a list of valid policies, will return `PolicyFormatError` with the parser's message.
+    pub fn load_all(file: &str) -> Result<Vec<Policy>, Error> {
+        let file = File::open(file).or(Err(Error::PolicyReadError))?;
+        let mut policies: Vec<Policy> = serde_yaml::from_reader(file).map_err(|e| Error::PolicyFormatError(e.to_string()))?;
+
+        // Give unnamed policies a name, so they can be told apart in the output
+        for (i, policy) in policies.iter_mut().enumerate() {
+            if policy.name.is_empty() {
+                policy.name = format!("policy {}", i + 1);
+            }
+        }
+
+        Ok(policies)
+    }
+
+    /// Get the due date and later deadlines that apply to a section, taking into account any
+    /// overrides for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `section` - The section, if known.
+    fn for_section(&self, section: Option<&str>) -> (DateTime<Utc>, &[Tier]) {
+        match section.and_then(|section| self.sections.get(section)) {
+            Some(overrides) => (overrides.due_date.unwrap_or(self.due_date), overrides.deadlines.as_ref().unwrap_or(&self.deadlines)),
+            None => (self.due_date, &self.deadlines),
+        }
+    }
+
+    /// Get the list of deadlines that apply to a student and the penalty for submitting before
+    /// each, in the format expected by `Submission::score`.  The first element is the due date,
+    /// with no penalty.
+    ///
+    /// # Arguments
+    ///
+    /// * `student` - The student, whose section may have its own deadlines.
+    pub fn deadlines_for(&self, student: &Student) -> Vec<(DateTime<Utc>, f64)> {
+        let (due_date, tiers) = self.for_section(student.section.as_deref());
+        let mut deadlines = vec![(due_date, 0.)];
+
+        for tier in tiers.iter() {
+            deadlines.push((due_date + Duration::seconds((tier.hours * 3600) as i64), tier.penalty));
+        }
+
+        deadlines
+    }
+
+    /// Get the length of time a student had to work on the project, from its release to their
+    /// due date, or None if the release date is not known.
+    ///
+    /// # Arguments
+    ///
+    /// * `student` - The student, whose section may have its own due date.
+    pub fn window_for(&self, student: &Student) -> Option<Duration> {
+        let (due_date, _) = self.for_section(student.section.as_deref());
+        self.release_date.map(|release_date| due_date - release_date)
+    }
+
+    /// Find the group a test is in, or None if it is in no group.  A test in several groups is in
+    /// the first one listed.
+    ///
+    /// # Arguments
+    ///
+    /// * `test` - The test to find the group of.
+    pub fn group_of(&self, test: &TestCase) -> Option<&TestGroup> {
+        self.groups.iter().find(|g| g.contains(test))
+    }
+
+    /// Get the policy for grading a group of tests on its own: this policy with the group's
+    /// deadlines, or for None, the tests in no group with the policy's own deadlines.  Per-section
+    /// overrides only apply to the tests in no group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The group, or None for the tests in no group.
+    pub fn for_group(&self, group: Option<&TestGroup>) -> Policy {
+        let mut policy = self.clone();
+        policy.groups = Vec::new();
+
+        if let Some(group) = group {
+            policy.name = format!("{} ({})", self.name, group.name);
+            policy.due_date = group.due_date;
+            policy.deadlines = group.deadlines.clone();
+            policy.sections = BTreeMap::new();
+        }
+
+        policy
+    }
+
+    /// Describe the deadlines that apply to a section, for the summary of a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `section` - The section, or None for the policy's own deadlines.
+    pub fn describe(&self, section: Option<&str>) -> String {
+        let (due_date, tiers) = self.for_section(section);
+        let mut description = format!("due {}", due_date.format(DATE_FORMAT));
+
+        for tier in tiers.iter() {
+            description.push_str(&format!(", then {}% off until {} hours late", tier.penalty * 100., tier.hours));
+        }
+
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn extra_credit_counts_past_the_max_up_to_the_cap() {
+        let roster = roster(&["1", "2"]);
+        let policy = policy("due_date: 2021-03-01 23:59 +0000\nextra_credit:\n  tags: [bonus]\n  cap: 1.25\n");
+        let mut submissions = SubmissionSet::new(&roster);
+        let mut bonus = submission(&roster.students[0], 1, "2021-03-01 10:00 +0000", &[1., 1., 1.]);
+        bonus.tests[2].tags.push("bonus".to_owned());
+        submissions.submissions.push(bonus);
+        submissions.submissions.push(submission(&roster.students[1], 2, "2021-03-01 10:00 +0000", &[]));
+
+        // The second submission has no points possible, so it scores 0 rather than NaN
+        assert_eq!(policy.extra_credit.apply(&mut submissions), vec![2]);
+        assert_eq!(submissions.submissions.iter().map(|s| (s.points(), s.raw_score())).collect::<Vec<_>>(), vec![((2.5, 2.), 1.25), ((0., 0.), 0.)]);
+    }
+
+    #[test]
+    fn sections_override_only_what_they_give() {
+        let policy = policy("due_date: 2021-03-01 23:59 +0000\ndeadlines:\n  - hours: 24\n    penalty: 0.1\nsections:\n  '0201':\n    due_date: 2021-03-02 23:59 +0000\n  '0301':\n    deadlines: []\n");
+        let mut roster = roster(&["1", "2", "3"]);
+        for (student, section) in roster.students.iter_mut().zip(["0101", "0201", "0301"]) {
+            student.section = Some(section.to_owned());
+        }
+
+        let deadlines: Vec<_> = roster.students.iter().map(|s| policy.deadlines_for(s)).collect();
+        assert_eq!(deadlines[0], vec![(time("2021-03-01 23:59 +0000"), 0.), (time("2021-03-02 23:59 +0000"), 0.1)]);
+        assert_eq!(deadlines[1], vec![(time("2021-03-02 23:59 +0000"), 0.), (time("2021-03-03 23:59 +0000"), 0.1)]);
+        assert_eq!(deadlines[2], vec![(time("2021-03-01 23:59 +0000"), 0.)]);
+    }
+}
@@ -0,0 +1,337 @@
+//! Comparing a grading run against the grades already posted to students, so that after regrades
+//! and extensions only the students whose final score actually changed are told about it.  The
+//! posted grades can be a summary.csv from an earlier run, a Gradescope score export, or a Canvas
+//! gradebook export.
+
+use crate::{grades::*, canvas, components::COMPONENTS_FILE};
+use std::{collections::HashMap, fmt, path::Path};
+use csv::StringRecord;
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    PostedReadError,
+    PostedFormatError(usize),
+    UnknownFormatError,
+    MissingColumnError(String),
+    ChangesWriteError,
+    ComponentsReadError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PostedReadError => write!(f, "Couldn't read the posted grades"),
+            Error::PostedFormatError(line) => write!(f, "Line {} of the posted grades is malformed", line),
+            Error::UnknownFormatError => write!(f, "The posted grades aren't a summary, Gradescope export, or Canvas gradebook"),
+            Error::MissingColumnError(column) => write!(f, "The posted grades have no {} column", column),
+            Error::ChangesWriteError => write!(f, "Couldn't write the changed grades"),
+            Error::ComponentsReadError => write!(f, "Couldn't read the combined grades in {}", COMPONENTS_FILE),
+        }
+    }
+}
+
+/// The kinds of files posted grades can be in.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Format {
+    Summary,
+    Gradescope,
+    Canvas,
+}
+
+/// A student's posted grade.  How much is known about it depends on where it came from: a
+/// summary.csv has everything, a Gradescope export has the submission, and a Canvas export only
+/// has the score.
+pub struct Posted {
+    pub uid: Option<String>,
+    pub directory_id: Option<String>,
+    pub submission: Option<u64>,
+    pub penalty: Option<f64>,
+    pub final_score: Option<f64>,
+}
+
+/// A student whose final score differs from the one posted.
+pub struct Change<'g> {
+    pub uid: String,
+    pub directory_id: String,
+    pub old: Option<f64>,
+    pub new: Option<f64>,
+    pub cause: &'static str,
+    // The new grade, if the student still has one
+    pub grade: Option<&'g Grade>,
+}
+
+/// A single row of the CSV written by `write`.
+#[derive(serde::Serialize)]
+struct ChangeRow<'g> {
+    #[serde(rename = "UID")]
+    uid: &'g str,
+    #[serde(rename = "DID")]
+    directory_id: &'g str,
+    #[serde(rename = "Old")]
+    old: Option<f64>,
+    #[serde(rename = "New")]
+    new: Option<f64>,
+    #[serde(rename = "Submission")]
+    submission: Option<u64>,
+    #[serde(rename = "Penalty")]
+    penalty: Option<f64>,
+    #[serde(rename = "Cause")]
+    cause: &'g str,
+}
+
+/// Find the first of several headers in a header line, ignoring case.
+fn find(headers: &StringRecord, candidates: &[&str]) -> Option<usize> {
+    candidates.iter().find_map(|c| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(c)))
+}
+
+/// Load the posted grades, telling which kind of file they are in from its columns, and return
+/// the kind along with them.
+///
+/// # Arguments
+///
+/// * `file` - The posted grades: a summary.csv (or the output directory it's in), a Gradescope
+///   score export, or a Canvas gradebook export
+/// * `assignment` - The assignment's column in a Canvas gradebook
+///
+/// # Errors
+///
+/// If the file cannot be read, will return `PostedReadError`, and if a row is malformed, will
+/// return `PostedFormatError` with its line number.  If the file isn't one of the kinds above,
+/// will return `UnknownFormatError`, and if it's a Canvas gradebook without the assignment's
+/// column (or no assignment was given), will return `MissingColumnError`.
+pub fn load(file: &str, assignment: Option<&str>) -> Result<(Format, Vec<Posted>), Error> {
+    // A summary from an earlier run has everything
+    if let Ok(grades) = GradeSet::load(file) {
+        return Ok((Format::Summary, grades.grades.into_iter().map(|g| Posted {
+            uid: Some(g.uid),
+            directory_id: Some(g.directory_id),
+            submission: Some(g.submission),
+            penalty: Some(g.penalty),
+            final_score: Some(g.final_score),
+        }).collect()));
+    }
+
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_path(file).or(Err(Error::PostedReadError))?;
+    let headers = rdr.headers().or(Err(Error::PostedReadError))?.clone();
+
+    // Gradescope identifies students by SID, and Canvas by login
+    let (format, uid, directory_id, score, submission) = if let Some(login) = find(&headers, &["SIS Login ID"]) {
+        let assignment = assignment.ok_or(Error::MissingColumnError("assignment".to_owned()))?;
+        let score = canvas::find_assignment(&headers, assignment).ok_or(Error::MissingColumnError(assignment.to_owned()))?;
+        (Format::Canvas, None, Some(login), score, None)
+    } else if let (Some(sid), Some(score)) = (find(&headers, &["SID"]), find(&headers, &["Total Score"])) {
+        (Format::Gradescope, Some(sid), find(&headers, &["Email"]), score, find(&headers, &["Submission ID"]))
+    } else {
+        return Err(Error::UnknownFormatError);
+    };
+
+    let mut posted = Vec::new();
+    for (i, record) in rdr.records().enumerate() {
+        let record = record.or(Err(Error::PostedFormatError(i + 2)))?;
+        let field = |i: Option<usize>| i.and_then(|i| record.get(i)).map(str::trim).filter(|f| !f.is_empty());
+
+        let directory_id = field(directory_id).map(|d| d.split_once('@').map_or(d, |(username, _)| username).to_owned());
+        let uid = field(uid).map(str::to_owned);
+
+        // The rows that aren't students, like Canvas's Points Possible, have no ID
+        if uid.is_none() && directory_id.is_none() {
+            continue;
+        }
+
+        let final_score = match field(Some(score)) {
+            Some(score) => Some(score.parse::<f64>().or(Err(Error::PostedFormatError(i + 2)))?),
+            None => None,
+        };
+        let submission = match field(submission) {
+            Some(id) => Some(id.parse::<u64>().or(Err(Error::PostedFormatError(i + 2)))?),
+            None => None,
+        };
+
+        posted.push(Posted { uid, directory_id, submission, penalty: None, final_score });
+    }
+
+    Ok((format, posted))
+}
+
+/// Load each student's total, as a percentage, from the components.csv of a run with manually
+/// graded components, which is the score its canvas.csv gives them.  Returns None if the run has
+/// no components.
+///
+/// # Arguments
+///
+/// * `run` - The output directory (or summary.csv) of the run
+///
+/// # Errors
+///
+/// If the components.csv exists but cannot be read, or has no UID or Total column, will return
+/// `ComponentsReadError`.
+pub fn load_totals(run: &str) -> Result<Option<HashMap<String, f64>>, Error> {
+    let run = Path::new(run);
+    let file = if run.is_dir() { run.join(COMPONENTS_FILE) } else { run.with_file_name(COMPONENTS_FILE) };
+    if !file.exists() {
+        return Ok(None);
+    }
+
+    let mut rdr = csv::Reader::from_path(file).or(Err(Error::ComponentsReadError))?;
+    let headers = rdr.headers().or(Err(Error::ComponentsReadError))?.clone();
+    let (uid, total) = find(&headers, &["UID"]).zip(find(&headers, &["Total"])).ok_or(Error::ComponentsReadError)?;
+
+    let mut totals = HashMap::new();
+    for record in rdr.records() {
+        let record = record.or(Err(Error::ComponentsReadError))?;
+        let score = record[total].trim().parse::<f64>().or(Err(Error::ComponentsReadError))?;
+        totals.insert(record[uid].trim().to_owned(), score);
+    }
+
+    Ok(Some(totals))
+}
+
+impl Posted {
+    /// Whether this posted grade belongs to the same student as a grade.
+    fn matches(&self, grade: &Grade) -> bool {
+        match (&self.uid, &self.directory_id) {
+            (Some(uid), _) => *uid == grade.uid,
+            (None, Some(did)) => *did == grade.directory_id,
+            (None, None) => false,
+        }
+    }
+}
+
+/// Find the students whose final score differs from the one posted, and why, sorted by directory
+/// ID.  A student with no posted score or no new grade is treated as having 0.
+///
+/// # Arguments
+///
+/// * `posted` - The posted grades
+/// * `grades` - The new grades
+/// * `totals` - The new totals from `load_totals`, if the posted grades are the percentages a
+///   canvas.csv with manually graded components gives, to compare against instead of the final
+///   scores
+pub fn compare<'g>(posted: &[Posted], grades: &'g GradeSet, totals: Option<&HashMap<String, f64>>) -> Vec<Change<'g>> {
+    let mut changes = Vec::new();
+
+    for grade in grades.grades.iter() {
+        let old = posted.iter().find(|p| p.matches(grade));
+        let old_score = old.and_then(|p| p.final_score);
+        let new_score = totals.map_or(grade.final_score, |t| t.get(&grade.uid).copied().unwrap_or(0.));
+        if (old_score.unwrap_or(0.) - new_score).abs() < 1e-9 {
+            continue;
+        }
+
+        let cause = match old {
+            None => "not posted",
+            Some(old) if old.final_score.is_none() => "not posted",
+            Some(old) if old.submission.is_some_and(|s| s != grade.submission) => "different submission",
+            Some(old) if old.penalty.is_some_and(|p| (p - grade.penalty).abs() > 1e-9) => "penalty change",
+            // Without the old penalty, a posted score that is the new one before the penalty must
+            // have had no penalty (which can't be told from a total with components)
+            Some(old) if old.penalty.is_none() && totals.is_none() && grade.penalty != 0. && (old_score.unwrap() - grade.score).abs() < 1e-9 => "penalty change",
+            Some(_) => "adjustment",
+        };
+
+        changes.push(Change {
+            uid: grade.uid.clone(),
+            directory_id: grade.directory_id.clone(),
+            old: old_score,
+            new: Some(new_score),
+            cause,
+            grade: Some(grade),
+        });
+    }
+
+    // The students who had a posted score but no longer have a grade
+    for p in posted.iter().filter(|p| p.final_score.is_some_and(|s| s != 0.)) {
+        if !grades.grades.iter().any(|g| p.matches(g)) {
+            changes.push(Change {
+                uid: p.uid.clone().unwrap_or_default(),
+                directory_id: p.directory_id.clone().unwrap_or_default(),
+                old: p.final_score,
+                new: None,
+                cause: "no longer graded",
+                grade: None,
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| a.directory_id.cmp(&b.directory_id));
+    changes
+}
+
+/// Write the changes to a CSV file.
+///
+/// # Arguments
+///
+/// * `changes` - The changes from `compare`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `ChangesWriteError`.
+pub fn write(changes: &[Change], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::ChangesWriteError))?;
+
+    for c in changes.iter() {
+        wtr.serialize(ChangeRow {
+            uid: &c.uid,
+            directory_id: &c.directory_id,
+            old: c.old,
+            new: c.new,
+            submission: c.grade.map(|g| g.submission),
+            penalty: c.grade.map(|g| g.penalty),
+            cause: c.cause,
+        }).or(Err(Error::ChangesWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::ChangesWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::fs;
+
+    fn causes(changes: &[Change]) -> Vec<(String, &'static str)> {
+        changes.iter().map(|c| (c.uid.clone(), c.cause)).collect()
+    }
+
+    #[test]
+    fn tells_the_cause_of_each_change() {
+        let old = GradeSet { grades: vec![grade("1", 1, 10., 0.), grade("2", 2, 10., 0.), grade("3", 3, 8., 0.), grade("4", 4, 10., 0.), grade("6", 6, 5., 0.)] };
+        let path = scratch("posted_causes").join(SUMMARY_FILE);
+        old.write(path.to_str().unwrap()).unwrap();
+        let (format, posted) = load(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(format, Format::Summary);
+
+        let new = GradeSet { grades: vec![grade("1", 1, 10., 0.), grade("2", 7, 9., 0.), grade("3", 3, 8., 0.5), grade("4", 4, 9., 0.), grade("5", 5, 4., 0.)] };
+        let changes = compare(&posted, &new, None);
+
+        assert_eq!(causes(&changes), vec![
+            ("2".to_owned(), "different submission"),
+            ("3".to_owned(), "penalty change"),
+            ("4".to_owned(), "adjustment"),
+            ("5".to_owned(), "not posted"),
+            ("6".to_owned(), "no longer graded"),
+        ]);
+    }
+
+    #[test]
+    fn canvas_scores_are_compared_against_the_components_total() {
+        let gradebook = file("posted_canvas", "gradebook.csv", "Student,ID,SIS User ID,SIS Login ID,Section,Project 1 (12345)\n    Points Possible,,,,,100\nA,1,1,s1,,90\nB,2,2,s2,,80\n");
+        let (format, posted) = load(&gradebook, Some("Project 1")).unwrap();
+        assert_eq!(format, Format::Canvas);
+
+        let run = scratch("posted_canvas_run");
+        fs::write(run.join(COMPONENTS_FILE), "UID,DID,Name,Autograder,Style,Penalty,Total\n1,s1,A,100,50,0,90\n2,s2,B,100,100,0.5,85\n").unwrap();
+        let totals = load_totals(run.to_str().unwrap()).unwrap().unwrap();
+
+        // On their own, both autograder scores differ from what was posted
+        let new = GradeSet { grades: vec![grade("1", 1, 10., 0.), grade("2", 2, 10., 0.5)] };
+        let changes = compare(&posted, &new, Some(&totals));
+
+        assert_eq!(causes(&changes), vec![("2".to_owned(), "adjustment")]);
+        assert_eq!((changes[0].old, changes[0].new), (Some(80.), Some(85.)));
+    }
+}
@@ -0,0 +1,296 @@
+//! Ingesting amnesty and extension requests from a form's responses (e.g. a Google Form exported
+//! to CSV).  Every response is checked against the roster and the course's projects, and when a
+//! student responded more than once only their latest response is kept.  Every response that is
+//! dropped is written to a rejects file along with the reason, so none disappear silently.
+
+use crate::roster::*;
+use std::{collections::HashMap, fmt};
+use csv::StringRecord;
+use chrono::NaiveDateTime;
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    ResponsesReadError,
+    MissingColumnError(String),
+    InvalidColumnMappingError(String),
+    RequestsWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ResponsesReadError => write!(f, "Couldn't read the form responses"),
+            Error::MissingColumnError(column) => write!(f, "The form responses have no {} column", column),
+            Error::InvalidColumnMappingError(mapping) => write!(f, "Invalid column mapping \"{}\", expected FIELD=HEADER", mapping),
+            Error::RequestsWriteError => write!(f, "Couldn't write the requests"),
+        }
+    }
+}
+
+/// The columns of the form's responses to read each field from, where they aren't under the
+/// default headers.  Each field defaults to the first column whose header contains its name.
+#[derive(Default)]
+pub struct FormColumns {
+    pub timestamp: Option<String>,
+    pub uid: Option<String>,
+    pub project: Option<String>,
+    pub hours: Option<String>,
+}
+
+/// A request that was accepted.
+pub struct Request<'r> {
+    pub student: &'r Student,
+    pub project: String,
+    // The hours of extension requested, if the form asks for them.
+    pub hours: Option<String>,
+    pub timestamp: NaiveDateTime,
+    // The line of the responses the request is from.
+    pub line: usize,
+}
+
+/// A response that was dropped, and why.
+#[derive(serde::Serialize)]
+pub struct Reject {
+    #[serde(rename = "Line")]
+    pub line: usize,
+    #[serde(rename = "Timestamp")]
+    pub timestamp: String,
+    #[serde(rename = "UID")]
+    pub uid: String,
+    #[serde(rename = "Project")]
+    pub project: String,
+    #[serde(rename = "Reason")]
+    pub reason: String,
+}
+
+/// The formats of the timestamps of the forms we know of, tried in order.
+const TIMESTAMP_FORMATS: [&str; 3] = ["%m/%d/%Y %H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+
+impl FormColumns {
+    /// Parse the column mapping given on the command line, as FIELD=HEADER pairs where FIELD is
+    /// one of timestamp, uid, project, or hours, e.g. `uid=Your UID`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mappings` - The pairs to parse.
+    ///
+    /// # Errors
+    ///
+    /// If a pair is not in the expected format or names an unknown field, will return
+    /// `InvalidColumnMappingError` with that pair.
+    pub fn parse<'a, I: IntoIterator<Item = &'a str>>(mappings: I) -> Result<FormColumns, Error> {
+        let mut columns = FormColumns::default();
+
+        for mapping in mappings {
+            let (field, header) = mapping.split_once('=').ok_or(Error::InvalidColumnMappingError(mapping.to_owned()))?;
+            let header = Some(header.trim().to_owned());
+
+            match field.trim().to_lowercase().as_str() {
+                "timestamp" => columns.timestamp = header,
+                "uid" => columns.uid = header,
+                "project" => columns.project = header,
+                "hours" => columns.hours = header,
+                _ => return Err(Error::InvalidColumnMappingError(mapping.to_owned())),
+            }
+        }
+
+        Ok(columns)
+    }
+
+    /// Find the column of a field in the header line: the mapped header exactly (ignoring case),
+    /// or otherwise the first header containing the field's name.
+    ///
+    /// # Errors
+    ///
+    /// If a mapped header doesn't exist, will return `MissingColumnError` with it.
+    fn find(headers: &StringRecord, mapped: &Option<String>, name: &str) -> Result<Option<usize>, Error> {
+        match mapped {
+            Some(header) => headers.iter().position(|h| h.trim().eq_ignore_ascii_case(header)).map(Some).ok_or(Error::MissingColumnError(header.clone())),
+            None => Ok(headers.iter().position(|h| h.to_lowercase().contains(name))),
+        }
+    }
+}
+
+/// Parse a form's timestamp.
+fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    TIMESTAMP_FORMATS.iter().find_map(|f| NaiveDateTime::parse_from_str(timestamp, f).ok())
+}
+
+/// Load the responses to a form, keeping each student's latest valid response (or latest for each
+/// project, if `per_project`).  Returns the requests, ordered by UID and project, and the rejected
+/// responses, ordered by line.
+///
+/// # Arguments
+///
+/// * `file` - The form's responses, as a CSV file with a header line
+/// * `columns` - The column mapping
+/// * `roster` - The roster, which every UID must be on
+/// * `projects` - The course's projects, one of which every response must name (ignoring case)
+/// * `per_project` - Whether a student may have a request for each project, rather than only one
+///
+/// # Errors
+///
+/// If the file cannot be read, will return `ResponsesReadError`.  If the timestamp, UID, or
+/// project column can't be found, will return `MissingColumnError` with its name.
+pub fn load<'r>(file: &str, columns: &FormColumns, roster: &'r Roster, projects: &[&str], per_project: bool) -> Result<(Vec<Request<'r>>, Vec<Reject>), Error> {
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_path(file).or(Err(Error::ResponsesReadError))?;
+    let headers = rdr.headers().or(Err(Error::ResponsesReadError))?.clone();
+
+    let timestamp = FormColumns::find(&headers, &columns.timestamp, "timestamp")?.ok_or(Error::MissingColumnError("timestamp".to_owned()))?;
+    let uid = FormColumns::find(&headers, &columns.uid, "uid")?.ok_or(Error::MissingColumnError("uid".to_owned()))?;
+    let project = FormColumns::find(&headers, &columns.project, "project")?.ok_or(Error::MissingColumnError("project".to_owned()))?;
+    let hours = FormColumns::find(&headers, &columns.hours, "hours")?;
+
+    // The valid responses, along with what to write if they're superseded
+    let mut valid: Vec<(Request, Reject)> = Vec::new();
+    let mut rejects = Vec::new();
+    for (i, record) in rdr.records().enumerate() {
+        let line = i + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(_) => {
+                rejects.push(Reject { line, timestamp: String::new(), uid: String::new(), project: String::new(), reason: "malformed row".to_owned() });
+                continue;
+            }
+        };
+        let field = |i: usize| record.get(i).map(str::trim).unwrap_or_default().to_owned();
+        let mut reject = Reject { line, timestamp: field(timestamp), uid: field(uid), project: field(project), reason: String::new() };
+
+        let parsed = parse_timestamp(&reject.timestamp);
+        let student = roster.find_student_by_sid(&reject.uid);
+        let name = projects.iter().find(|p| p.eq_ignore_ascii_case(&reject.project));
+        let hours = hours.map(field).filter(|h| !h.is_empty());
+
+        reject.reason = match (parsed, student, name) {
+            (None, _, _) => "invalid timestamp".to_owned(),
+            (_, None, _) if reject.uid.is_empty() => "missing UID".to_owned(),
+            (_, None, _) => "UID not on the roster".to_owned(),
+            (_, _, None) => format!("unknown project (expected one of {})", projects.join(", ")),
+            (_, _, _) if hours.as_ref().is_some_and(|h| h.parse::<u32>().is_err()) => format!("invalid hours {}", hours.unwrap_or_default()),
+            (Some(timestamp), Some(student), Some(name)) => {
+                valid.push((Request { student, project: name.to_string(), hours, timestamp, line }, reject));
+                continue;
+            }
+        };
+
+        rejects.push(reject);
+    }
+
+    // Keep only the latest of each student's responses (for each project, if per project), taking
+    // the later line when two have the same timestamp
+    let key = |r: &Request<'r>| (r.student.uid.clone(), if per_project { Some(r.project.clone()) } else { None });
+    let mut latest_of = HashMap::new();
+    for (r, _) in valid.iter() {
+        let latest = latest_of.entry(key(r)).or_insert((r.timestamp, r.line));
+        *latest = (*latest).max((r.timestamp, r.line));
+    }
+    let latest: Vec<usize> = valid.iter().map(|(r, _)| latest_of[&key(r)].1).collect();
+
+    let mut kept = Vec::new();
+    for ((request, mut reject), latest) in valid.into_iter().zip(latest) {
+        if latest == request.line {
+            kept.push(request);
+        } else {
+            reject.reason = format!("superseded by the response on line {}", latest);
+            rejects.push(reject);
+        }
+    }
+
+    kept.sort_by(|a, b| (&a.student.uid, &a.project).cmp(&(&b.student.uid, &b.project)));
+    rejects.sort_by_key(|r| r.line);
+    Ok((kept, rejects))
+}
+
+/// Write the accepted requests to a CSV file with no header line, as UID,Project (and Hours, if
+/// the form has them), which is what the amnesty scripts read.
+///
+/// # Arguments
+///
+/// * `requests` - The requests from `load`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `RequestsWriteError`.
+pub fn write(requests: &[Request], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_path(file).or(Err(Error::RequestsWriteError))?;
+
+    for r in requests.iter() {
+        let mut row = vec![r.student.uid.as_str(), r.project.as_str()];
+        row.extend(r.hours.as_deref());
+        wtr.write_record(&row).or(Err(Error::RequestsWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::RequestsWriteError))
+}
+
+/// Write the rejected responses to a CSV file.
+///
+/// # Arguments
+///
+/// * `rejects` - The rejected responses from `load`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `RequestsWriteError`.
+pub fn write_rejects(rejects: &[Reject], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::RequestsWriteError))?;
+
+    for r in rejects.iter() {
+        wtr.serialize(r).or(Err(Error::RequestsWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::RequestsWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const RESPONSES: &str = "Timestamp,Your UID,Which Project?
+5/4/2021 20:00:00,1,4a
+5/4/2021 21:00:00,2,4a
+5/5/2021 09:00:00,1,4b
+5/4/2021 21:00:00,2,4b
+not a time,3,4a
+5/4/2021 22:00:00,9,4a
+5/4/2021 22:00:00,3,6
+5/4/2021 23:00:00,3,4a
+";
+
+    /// Load the responses, giving the requests as UID,Project and the rejects as Line: Reason.
+    fn load_responses(test: &str, per_project: bool) -> (Vec<String>, Vec<String>) {
+        let roster = roster(&["1", "2", "3"]);
+        let file = file(test, "responses.csv", RESPONSES);
+
+        let (requests, rejects) = load(&file, &FormColumns::default(), &roster, &["4a", "4b"], per_project).unwrap();
+        (requests.iter().map(|r| format!("{},{}", r.student.uid, r.project)).collect(), rejects.iter().map(|r| format!("{}: {}", r.line, r.reason)).collect())
+    }
+
+    #[test]
+    fn keeps_each_students_latest_response() {
+        let (requests, rejects) = load_responses("latest", false);
+
+        // Student 2's responses have the same timestamp, so the later line wins
+        assert_eq!(requests, ["1,4b", "2,4b", "3,4a"]);
+        assert_eq!(rejects, [
+            "2: superseded by the response on line 4",
+            "3: superseded by the response on line 5",
+            "6: invalid timestamp",
+            "7: UID not on the roster",
+            "8: unknown project (expected one of 4a, 4b)",
+        ]);
+    }
+
+    #[test]
+    fn keeps_the_latest_response_for_each_project() {
+        let (requests, rejects) = load_responses("per-project", true);
+
+        assert_eq!(requests, ["1,4a", "1,4b", "2,4a", "2,4b", "3,4a"]);
+        assert!(rejects.iter().all(|r| !r.contains("superseded")));
+    }
+}
@@ -1,66 +1,301 @@
 //! Code for reading the students' information from the roster file and processing it for easy
 //! lookup and storage of basic information.
 
-use std::fs::File;
+use std::{fs::File, io::{BufRead, BufReader}, hash::{Hash, Hasher}, fmt};
+use csv::{ReaderBuilder, StringRecord};
 
 /// The types of errors that can be produced within and returned from this module.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     RosterReadError,
     RosterFormatError(usize),
+    MissingColumnError(String),
+    InvalidColumnMappingError(String),
+    AliasesReadError,
+    AliasesFormatError(usize),
+    UnknownAliasError(String),
 }
 
-/// Represents a single student, with the information from the roster CSV file.
-#[derive(serde::Deserialize, Hash, Debug)]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RosterReadError => write!(f, "Couldn't read the roster"),
+            Error::RosterFormatError(line) => write!(f, "Line {} of the roster is malformed", line),
+            Error::MissingColumnError(column) => write!(f, "The roster has no {} column", column),
+            Error::InvalidColumnMappingError(mapping) => write!(f, "Invalid column mapping \"{}\", expected FIELD=HEADER", mapping),
+            Error::AliasesReadError => write!(f, "Couldn't read the aliases file"),
+            Error::AliasesFormatError(line) => write!(f, "Line {} of the aliases file is malformed", line),
+            Error::UnknownAliasError(uid) => write!(f, "An alias refers to {}, who isn't on the roster", uid),
+        }
+    }
+}
+
+/// Represents a single student, with the information from the roster file.
+#[derive(Debug)]
 pub struct Student {
     // The student's name, which is optional.
-    #[serde(rename = "Name")]
     pub name: Option<String>,
     // The student's University ID.
-    #[serde(rename = "UID")]
     pub uid: String,
     // The student's Directory ID (their login username).
-    #[serde(rename = "DID")]
     pub directory_id: String,
+    // The student's section, which is optional.
+    pub section: Option<String>,
+}
+
+/// Maps an alternate SID to a student's UID on the roster, for students whose SID was entered
+/// incorrectly in Gradescope.
+#[derive(serde::Deserialize)]
+pub struct Alias {
+    // The SID the student submitted under.
+    #[serde(rename = "SID")]
+    pub sid: String,
+    // The student's actual University ID.
+    #[serde(rename = "UID")]
+    pub uid: String,
 }
 
 /// Represents the course roster which contains all of the students.  Basically imported
 /// automatically from the CSV file.
 pub struct Roster {
     pub students: Vec<Student>,
+    aliases: Vec<Alias>,
+}
+
+/// The header of the roster column to read each of a student's fields from, for rosters whose
+/// headers aren't recognized.  Any field left as None is found by its usual headers.
+#[derive(Default, Debug)]
+pub struct RosterColumns {
+    pub name: Option<String>,
+    pub uid: Option<String>,
+    pub directory_id: Option<String>,
+    pub section: Option<String>,
+}
+
+/// Where each of a student's fields is in the rows of a roster.
+struct Layout {
+    // The columns of the name, which are joined by spaces (e.g. first and last name).
+    name: Vec<usize>,
+    uid: usize,
+    // The column of the directory ID, which may be given as an email address.
+    directory_id: usize,
+    section: Option<usize>,
+    // The column of each person's role on a Gradescope roster, for skipping TAs and instructors.
+    role: Option<usize>,
+}
+
+/// The headers each field may appear under in the known formats, in order of preference.  Headers
+/// are compared ignoring case.
+const NAME_HEADERS: [&str; 2] = ["Name", "Full Name"];
+const UID_HEADERS: [&str; 2] = ["UID", "SID"];
+const DID_HEADERS: [&str; 3] = ["DID", "Directory ID", "Email"];
+const SECTION_HEADERS: [&str; 2] = ["Section", "Sections"];
+
+impl RosterColumns {
+    /// Parse the column mapping given on the command line, as FIELD=HEADER pairs where FIELD is
+    /// one of name, uid, did, or section, e.g. `uid=Student ID`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mappings` - The pairs to parse.
+    ///
+    /// # Errors
+    ///
+    /// If a pair is not in the expected format or names an unknown field, will return
+    /// `InvalidColumnMappingError` with that pair.
+    pub fn parse<'a, I: IntoIterator<Item = &'a str>>(mappings: I) -> Result<RosterColumns, Error> {
+        let mut columns = RosterColumns::default();
+
+        for mapping in mappings {
+            let (field, header) = mapping.split_once('=').ok_or(Error::InvalidColumnMappingError(mapping.to_owned()))?;
+            let header = Some(header.trim().to_owned());
+
+            match field.trim().to_lowercase().as_str() {
+                "name" => columns.name = header,
+                "uid" => columns.uid = header,
+                "did" => columns.directory_id = header,
+                "section" => columns.section = header,
+                _ => return Err(Error::InvalidColumnMappingError(mapping.to_owned())),
+            }
+        }
+
+        Ok(columns)
+    }
 }
 
+impl Layout {
+    /// The layout of the tab-separated roster downloaded from UMEG, which has no header line and
+    /// the columns Section, UID, "Last, First", DID, and Email.
+    fn umeg() -> Layout {
+        Layout { name: vec![2], uid: 1, directory_id: 3, section: Some(0), role: None }
+    }
+
+    /// Work out the layout of a roster from its headers, using the explicit column mapping for any
+    /// field it gives.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers` - The header line of the roster
+    /// * `columns` - The explicit column mapping
+    ///
+    /// # Errors
+    ///
+    /// If the UID or DID column can't be found, or a column given in the mapping doesn't exist,
+    /// will return `MissingColumnError` with the name of the column.
+    fn from_headers(headers: &StringRecord, columns: &RosterColumns) -> Result<Layout, Error> {
+        let find = |candidates: &[&str]| candidates.iter().find_map(|c| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(c)));
+        let find_mapped = |mapped: &Option<String>, candidates: &[&str]| match mapped {
+            Some(header) => find(&[header.as_str()]).map(Some).ok_or(Error::MissingColumnError(header.clone())),
+            None => Ok(find(candidates)),
+        };
+
+        let uid = find_mapped(&columns.uid, &UID_HEADERS)?.ok_or(Error::MissingColumnError("UID".to_owned()))?;
+        let directory_id = find_mapped(&columns.directory_id, &DID_HEADERS)?.ok_or(Error::MissingColumnError("DID".to_owned()))?;
+        let section = find_mapped(&columns.section, &SECTION_HEADERS)?;
+
+        // Gradescope and the rosters tool split the name into first and last
+        let name = match find_mapped(&columns.name, &NAME_HEADERS)? {
+            Some(name) => vec![name],
+            None => find(&["First Name"]).into_iter().chain(find(&["Last Name"])).collect(),
+        };
+
+        Ok(Layout { name, uid, directory_id, section, role: find(&["Role"]) })
+    }
+
+    /// Whether a row of the roster is for a student, rather than a TA or instructor.
+    fn is_student(&self, record: &StringRecord) -> bool {
+        self.role.and_then(|i| record.get(i)).is_none_or(|role| role.trim().eq_ignore_ascii_case("student"))
+    }
+
+    /// Read a student from a row of the roster, or None if the UID or DID is missing.
+    fn student(&self, record: &StringRecord) -> Option<Student> {
+        let field = |i: usize| record.get(i).map(str::trim).filter(|f| !f.is_empty());
+
+        // UMEG gives names as "Last, First"
+        let name: Vec<_> = self.name.iter().filter_map(|&i| field(i)).map(|name| match name.split_once(',') {
+            Some((last, first)) => format!("{} {}", first.trim(), last.trim()),
+            None => name.to_owned(),
+        }).collect();
+
+        // Only keep the username of an email address
+        let directory_id = field(self.directory_id)?;
+        let directory_id = directory_id.split_once('@').map_or(directory_id, |(username, _)| username);
+
+        Some(Student {
+            name: if name.is_empty() { None } else { Some(name.join(" ")) },
+            uid: field(self.uid)?.to_owned(),
+            directory_id: directory_id.to_owned(),
+            section: self.section.and_then(field).map(str::to_owned),
+        })
+    }
+}
+
+#[cfg(test)]
 impl Roster {
-    /// Given the name of the CSV file containing the roster, loads all of the students into an
-    /// instance of `Roster`.  Note that the format of the roster is a CSV with the columns
-    /// Name,UID,DID (or just UID,DID as the Name column is optional, and not used in this
-    /// implementation).  The corresponding header must be included at the top of the file.
+    /// Make a roster of the given students, without reading it from a file.
+    pub fn from_students(students: Vec<Student>) -> Roster {
+        Roster { students, aliases: Vec::new() }
+    }
+}
+
+impl Roster {
+    /// Given the name of the file containing the roster, loads all of the students into an
+    /// instance of `Roster`.  The format of the roster is detected from its headers, and may be
+    /// any of:
+    ///
+    /// * A CSV with the columns Name,UID,DID,Section (the Name and Section columns are optional),
+    ///   such as the `-idmap.csv` written by the rosters tool
+    /// * The `-gradescope.csv` written by the rosters tool, or a roster exported from Gradescope,
+    ///   with the columns First Name,Last Name,SID,Email and optionally Section(s) and Role (in
+    ///   which case only the students are kept)
+    /// * The tab-separated roster downloaded from UMEG, which has no header line
+    ///
+    /// Directory IDs given as email addresses are reduced to the username.
     ///
     /// # Arguments
     ///
     /// * `file` - The name of the roster file.
+    /// * `columns` - The headers of any columns that aren't under their usual names.
     ///
     /// # Errors
     ///
     /// If the roster file cannot be read (for example, if it doesn't exist or the appropriate
-    /// permissions are not set), will return `RosterReadError`.  If there is an error during
-    /// deserialization, will reuturn `RosterFormatError` with the line number of the first error.
-    pub fn load(file: &str) -> Result<Roster, Error> {
-        // Open the file (this will fail if the file doesn't exist or we can't read it).
-        let file = File::open(file).or(Err(Error::RosterReadError))?;
+    /// permissions are not set), will return `RosterReadError`.  If the UID or DID column can't be
+    /// found, will return `MissingColumnError`.  If a row is malformed or has no UID or DID, will
+    /// reuturn `RosterFormatError` with the line number of the first error.
+    pub fn load(file: &str, columns: &RosterColumns) -> Result<Roster, Error> {
+        // Look at the first line to tell whether this is the tab-separated UMEG roster.
+        let mut first_line = String::new();
+        BufReader::new(File::open(file).or(Err(Error::RosterReadError))?).read_line(&mut first_line).or(Err(Error::RosterReadError))?;
+        let umeg = first_line.split('\t').count() >= 5;
 
         // Create a CSV reader over this file.
-        let mut rdr = csv::Reader::from_reader(file);
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(!umeg)
+            .delimiter(if umeg { b'\t' } else { b',' })
+            .flexible(true)
+            .from_path(file)
+            .or(Err(Error::RosterReadError))?;
 
-        // Map each row into an instance of Student.
-        let students: Result<Vec<_>, _> = rdr.deserialize().enumerate().map(|(i, row)| row.or(Err(Error::RosterFormatError(i + 1)))).collect();
+        let layout = if umeg {
+            Layout::umeg()
+        } else {
+            Layout::from_headers(rdr.headers().or(Err(Error::RosterReadError))?, columns)?
+        };
+
+        // Map each student's row into an instance of Student.
+        let mut students = Vec::new();
+        for (i, record) in rdr.records().enumerate() {
+            let record = record.or(Err(Error::RosterFormatError(i + 1)))?;
+
+            if layout.is_student(&record) {
+                students.push(layout.student(&record).ok_or(Error::RosterFormatError(i + 1))?);
+            }
+        }
 
         // Create a Roster with all of those entries.
         Ok(Roster {
-            students: students?,
+            students,
+            aliases: Vec::new(),
         })
     }
 
+    /// Given the name of a CSV file mapping alternate SIDs to UIDs on the roster, adds those
+    /// aliases to this roster so that `find_student_by_sid` will resolve them.  The format of the
+    /// file must be SID,UID, and the header line must be included at the top of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The name of the aliases file.
+    ///
+    /// # Errors
+    ///
+    /// If the aliases file cannot be read, will return `AliasesReadError`.  If there is an error
+    /// during deserialization, will return `AliasesFormatError` with the line number of the first
+    /// error.  If an alias refers to a UID that is not on the roster, will return
+    /// `UnknownAliasError` with that UID.
+    pub fn load_aliases(&mut self, file: &str) -> Result<(), Error> {
+        // Open the file (this will fail if the file doesn't exist or we can't read it).
+        let file = File::open(file).or(Err(Error::AliasesReadError))?;
+
+        // Create a CSV reader over this file.
+        let mut rdr = csv::Reader::from_reader(file);
+
+        // Map each row into an instance of Alias.
+        let aliases: Result<Vec<Alias>, _> = rdr.deserialize().enumerate().map(|(i, row)| row.or(Err(Error::AliasesFormatError(i + 1)))).collect();
+
+        // Make sure every alias points at a student we know about.
+        for alias in aliases? {
+            if self.find_student_by_uid(alias.uid.clone()).is_none() {
+                return Err(Error::UnknownAliasError(alias.uid));
+            }
+            self.aliases.push(alias);
+        }
+
+        Ok(())
+    }
+
     /// Lookup a Student by their university id (UID).
     ///
     /// # Arguments
@@ -69,6 +304,27 @@ impl Roster {
     pub fn find_student_by_uid(&self, uid: String) -> Option<&Student> {
         self.students.iter().find(|s| s.uid == uid)
     }
+
+    /// Get the distinct sections of the students on the roster, in sorted order.
+    pub fn sections(&self) -> Vec<&str> {
+        let mut sections: Vec<_> = self.students.iter().filter_map(|s| s.section.as_deref()).collect();
+        sections.sort();
+        sections.dedup();
+        sections
+    }
+
+    /// Lookup a Student by the SID they submitted under in Gradescope, which is either their UID
+    /// or one of the aliases loaded with `load_aliases`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sid` - The SID to look for.
+    pub fn find_student_by_sid(&self, sid: &str) -> Option<&Student> {
+        match self.aliases.iter().find(|a| a.sid == sid) {
+            Some(alias) => self.find_student_by_uid(alias.uid.clone()),
+            None => self.find_student_by_uid(sid.to_owned()),
+        }
+    }
 }
 
 impl PartialEq for Student {
@@ -78,3 +334,50 @@ impl PartialEq for Student {
 }
 
 impl Eq for Student {}
+
+impl Hash for Student {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.uid.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn load_students(name: &str, contents: &str, columns: &RosterColumns) -> Vec<(String, String, Option<String>, Option<String>)> {
+        let roster = Roster::load(&file("roster_layouts", name, contents), columns).unwrap();
+        roster.students.into_iter().map(|s| (s.uid, s.directory_id, s.name, s.section)).collect()
+    }
+
+    fn alice(section: Option<&str>) -> (String, String, Option<String>, Option<String>) {
+        ("111".to_owned(), "alice".to_owned(), Some("Alice A".to_owned()), section.map(str::to_owned))
+    }
+
+    #[test]
+    fn reads_the_idmap_layout() {
+        assert_eq!(load_students("idmap.csv", "Name,UID,DID,Section\nAlice A,111,alice,0101\n", &RosterColumns::default()), vec![alice(Some("0101"))]);
+    }
+
+    #[test]
+    fn reads_a_gradescope_roster_keeping_only_students() {
+        let roster = "First Name,Last Name,SID,Email,Role,Sections\nAlice,A,111,alice@umd.edu,Student,0101\nTa,T,999,ta@umd.edu,TA,\n";
+        assert_eq!(load_students("gradescope.csv", roster, &RosterColumns::default()), vec![alice(Some("0101"))]);
+    }
+
+    #[test]
+    fn detects_the_umeg_roster_by_its_tabs() {
+        let roster = "0101\t111\tA, Alice\talice\talice@umd.edu\n";
+        assert_eq!(load_students("umeg.tsv", roster, &RosterColumns::default()), vec![alice(Some("0101"))]);
+    }
+
+    #[test]
+    fn reads_mapped_columns() {
+        let columns = RosterColumns::parse(["uid=Student ID", "did=Login", "name=Full Name"]).unwrap();
+        assert_eq!(load_students("custom.csv", "Student ID,Login,Full Name\n111,alice,Alice A\n", &columns), vec![alice(None)]);
+
+        let missing = Roster::load(&file("roster_layouts", "unmapped.csv", "Student ID,Login\n111,alice\n"), &RosterColumns::default());
+        assert!(matches!(missing, Err(Error::MissingColumnError(column)) if column == "UID"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Trying out candidate late policies before announcing one, by computing the grades each policy
+//! would produce over the same set of submissions and comparing them side by side.
+
+use crate::{roster::*, extensions::*, accommodations::{self, AccommodationSet}, submissions::*, grades::*, grading, policy::*};
+use std::fmt;
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    InvalidPolicyError(String, accommodations::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPolicyError(name, e) => write!(f, "Can't grade under the policy \"{}\": {}", name, e),
+        }
+    }
+}
+
+/// The grades produced by a single candidate policy.
+pub struct Outcome {
+    pub policy: Policy,
+    pub grades: GradeSet,
+}
+
+/// A student whose grade differs between the baseline policy and another policy.
+pub struct Move<'o> {
+    pub grade: &'o Grade,
+    pub old: f64,
+    pub new: f64,
+}
+
+impl Outcome {
+    /// Each student's final score as a percentage of the maximum.
+    fn percentages(&self) -> Vec<f64> {
+        self.grades.grades.iter().map(percentage).collect()
+    }
+
+    /// The mean final score, as a percentage.
+    pub fn mean(&self) -> f64 {
+        let percentages = self.percentages();
+        percentages.iter().sum::<f64>() / percentages.len() as f64
+    }
+
+    /// The median final score, as a percentage.
+    pub fn median(&self) -> f64 {
+        let mut percentages = self.percentages();
+        percentages.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let n = percentages.len();
+        if n == 0 {
+            f64::NAN
+        } else if n.is_multiple_of(2) {
+            (percentages[n / 2 - 1] + percentages[n / 2]) / 2.
+        } else {
+            percentages[n / 2]
+        }
+    }
+
+    /// The number of students who received any late penalty.
+    pub fn penalized(&self) -> usize {
+        self.grades.grades.iter().filter(|g| g.penalty > 0.).count()
+    }
+
+    /// The students whose grade under this policy differs from their grade under the baseline,
+    /// ordered from the largest change to the smallest.
+    ///
+    /// # Arguments
+    ///
+    /// * `baseline` - The outcome to compare against
+    pub fn moves<'o>(&'o self, baseline: &'o Outcome) -> Vec<Move<'o>> {
+        let mut moves: Vec<_> = self.grades.grades.iter().filter_map(|grade| {
+            let old = baseline.grades.find(&grade.uid).map_or(0., percentage);
+            let new = percentage(grade);
+
+            if old != new {
+                Some(Move { grade, old, new })
+            } else {
+                None
+            }
+        }).collect();
+
+        moves.sort_by(|a, b| (b.new - b.old).abs().partial_cmp(&(a.new - a.old).abs()).unwrap());
+        moves
+    }
+}
+
+/// A student's final score as a percentage of the maximum, or 0 if there are no points possible.
+fn percentage(grade: &Grade) -> f64 {
+    if grade.max > 0. { grade.final_score / grade.max * 100. } else { 0. }
+}
+
+/// Compute the grades each policy would produce, preparing the submissions under each one the same
+/// way grading does (its submission limit, release tokens, extra credit, and points possible) and
+/// grading each of its groups of tests under their own deadlines.  Nothing is written to disk.
+///
+/// # Arguments
+///
+/// * `roster` - The students to grade
+/// * `submissions` - All of the submissions for the project, which a copy of is prepared for each
+///   policy
+/// * `extensions` - The students' extensions, which are applied under every policy
+/// * `accommodations` - The students' accommodations, which are turned into extensions using
+///   each policy's (or group's) release and due dates
+/// * `policies` - The candidate policies
+/// * `canonical` - The submitter of the canonical solution, if known, who is prepared the same way
+///   as when grading; without it, submissions with only a top-level score are out of the best such
+///   score rather than the canonical one
+///
+/// # Errors
+///
+/// If the accommodations can't be applied under one of the policies (or its groups), will return
+/// `InvalidPolicyError` with its name.
+pub fn simulate<'r>(roster: &'r Roster, submissions: &SubmissionSet<'r>, extensions: &ExtensionSet, accommodations: &AccommodationSet, policies: Vec<Policy>, canonical: Option<&Student>) -> Result<Vec<Outcome>, Error> {
+    policies.into_iter().map(|policy| {
+        let mut submissions = submissions.clone();
+        grading::prepare(roster, &mut submissions, &policy, canonical);
+
+        let parts = grading::parts(roster, &submissions, &policy, accommodations, extensions).map_err(|e| Error::InvalidPolicyError(policy.name.clone(), e))?;
+        let grades = grading::grade_parts(roster, &parts);
+
+        Ok(Outcome { policy, grades })
+    }).collect()
+}
+
+/// Print the outcomes side by side.  The first outcome is treated as the baseline, and for each of
+/// the others the students whose grade moves the most relative to it are listed.
+///
+/// # Arguments
+///
+/// * `outcomes` - The outcomes from `simulate`
+/// * `top` - How many of the largest moves to list for each policy
+pub fn print(outcomes: &[Outcome], top: usize) {
+    let baseline = match outcomes.first() {
+        Some(baseline) => baseline,
+        None => return,
+    };
+
+    let width = outcomes.iter().map(|o| o.policy.name.len()).max().unwrap_or(0).max(10);
+
+    print!("{:<12}", "");
+    for o in outcomes.iter() {
+        print!("  {:>width$}", o.policy.name, width = width);
+    }
+    println!();
+
+    print!("{:<12}", "Mean");
+    for o in outcomes.iter() {
+        print!("  {:>width$.2}", o.mean(), width = width);
+    }
+    println!();
+
+    print!("{:<12}", "Median");
+    for o in outcomes.iter() {
+        print!("  {:>width$.2}", o.median(), width = width);
+    }
+    println!();
+
+    print!("{:<12}", "Penalized");
+    for o in outcomes.iter() {
+        print!("  {:>width$}", o.penalized(), width = width);
+    }
+    println!();
+
+    print!("{:<12}", "Changed");
+    for o in outcomes.iter() {
+        print!("  {:>width$}", o.moves(baseline).len(), width = width);
+    }
+    println!();
+
+    for o in outcomes.iter().skip(1) {
+        let moves = o.moves(baseline);
+        if moves.is_empty() {
+            continue;
+        }
+
+        println!();
+        println!("Largest changes under \"{}\" (from \"{}\"):", o.policy.name, baseline.policy.name);
+        for m in moves.iter().take(top) {
+            println!("  {} ({}): {:.2} -> {:.2} ({:+.2})", m.grade.directory_id, m.grade.uid, m.old, m.new, m.new - m.old);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn each_policy_applies_its_own_submission_limit() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(submission(student, 1, "2021-03-01 10:00 +0000", &[0.]));
+        submissions.submissions.push(Submission { active: true, ..submission(student, 2, "2021-03-01 11:00 +0000", &[1.]) });
+
+        let policies = vec![
+            policy("name: unlimited\ndue_date: 2021-03-01 23:59 +0000\n"),
+            policy("name: limited\ndue_date: 2021-03-01 23:59 +0000\nsubmission_limit:\n  count: 1\n"),
+        ];
+        let outcomes = simulate(&roster, &submissions, &ExtensionSet::empty(), &AccommodationSet::empty(), policies, None).unwrap();
+
+        assert_eq!(outcomes.iter().map(|o| o.grades.grades[0].final_score).collect::<Vec<_>>(), vec![1., 0.]);
+        assert!(!submissions.submissions[1].excluded);
+    }
+
+    #[test]
+    fn names_the_policy_that_cant_be_graded() {
+        let roster = roster(&["1"]);
+        let accommodations = AccommodationSet::load(&file("simulate_invalid", "accommodations.csv", "UID,Multiplier,Cap\n1,1.5,\n")).unwrap();
+
+        // Accommodations need a release date, which only the first policy has
+        let policies = vec![
+            policy("name: released\nrelease_date: 2021-03-01 00:00 +0000\ndue_date: 2021-03-01 23:59 +0000\n"),
+            policy("name: unreleased\ndue_date: 2021-03-01 23:59 +0000\n"),
+        ];
+        let result = simulate(&roster, &SubmissionSet::new(&roster), &ExtensionSet::empty(), &accommodations, policies, None);
+
+        assert!(matches!(result, Err(Error::InvalidPolicyError(name, accommodations::Error::MissingReleaseDateError)) if name == "unreleased"));
+    }
+}
@@ -1,29 +1,49 @@
 //! Code for importing submission data from the yaml file produced by Gradescope.
 
-use crate::{roster::*, extensions::*};
-use std::fs::File;
-use serde_yaml::Value;
-use chrono::{DateTime, NaiveDateTime, Utc, Duration, TimeZone};
+use crate::{roster::*, extensions::*, metadata::*};
+use std::{fmt, iter, collections::HashMap};
+use chrono::{DateTime, Utc, Duration, TimeZone};
 
 /// The types of errors that can be produced within and returned from this module.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     SubmissionReadError,
     SubmissionFormatError(Option<u64>, usize),
+    SubmissionWriteError,
     InvalidDeadlineError,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::SubmissionReadError => write!(f, "Couldn't read the submission metadata"),
+            Error::SubmissionFormatError(Some(id), code) => write!(f, "Submission {} in the metadata is malformed (error {})", id, code),
+            Error::SubmissionFormatError(None, code) => write!(f, "The submission metadata is malformed (error {})", code),
+            Error::SubmissionWriteError => write!(f, "Couldn't write the submissions"),
+            Error::InvalidDeadlineError => write!(f, "The deadlines are invalid"),
+        }
+    }
+}
+
+/// The name of the part a submission without per-test results is graded as, in place of its tests.
+pub const TOTAL_PART: &str = "Total";
+
 // A single test case and result
-#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct TestCase {
     pub name: String,
     pub number: f64,
     pub score: f64,
     pub max: f64,
+    // The test's tags in the autograder's results, if any
+    pub tags: Vec<String>,
+    // Whether the test is extra credit, so its max doesn't count towards the submission's
+    pub extra_credit: bool,
 }
 
 /// Represents a single submission of the project.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Submission<'r> {
     // The submission ID
     pub id: u64,
@@ -35,75 +55,125 @@ pub struct Submission<'r> {
     pub tests: Vec<TestCase>,
     // Whether this was the active submission
     pub active: bool,
+    // Whether this submission is over the policy's submission limit, and so can't be graded
+    pub excluded: bool,
+    // The penalty for submitting more times than the policy's submission limit allows
+    pub excess_penalty: f64,
+    // The most the score can be, as a fraction of the max, if the policy limits it
+    pub cap: Option<f64>,
+    // The top-level results, if the autograder gave no per-test results
+    pub totals: Option<Totals>,
+}
+
+/// The top-level results of a submission the autograder gave no per-test results for, such as one
+/// it reported only a score for or marked as failed with a message.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Totals {
+    pub score: Option<f64>,
+    pub execution_time: Option<f64>,
+    pub output: Option<String>,
+    // The points the score is out of, which the results don't give, so it is set by
+    // `SubmissionSet::apply_totals`
+    #[serde(skip)]
+    pub max: f64,
+}
+
+/// A submission that could not be graded, because the autograder failed on it or has not
+/// finished running on it.
+#[derive(Clone, Debug)]
+pub struct SkippedSubmission<'r> {
+    // The submission ID
+    pub id: u64,
+    // The student
+    pub student: &'r Student,
+    // Submission time
+    pub time: DateTime<Utc>,
+    // The submission's status in Gradescope (anything other than "processed")
+    pub status: String,
+    // Whether this was the active submission
+    pub active: bool,
+}
+
+/// A submitter whose SID was not found on the roster (for example, a student who dropped or was
+/// added late), along with a summary of their submissions.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct UnmatchedSubmitter {
+    // The SID the submitter used
+    #[serde(rename = "SID")]
+    pub sid: String,
+    // The submitter's name and email, as recorded by Gradescope
+    #[serde(rename = "Name")]
+    pub name: Option<String>,
+    #[serde(rename = "Email")]
+    pub email: Option<String>,
+    // The number of submissions they made, including ones that failed
+    #[serde(rename = "Submissions")]
+    pub submissions: usize,
+    // The best raw score of any of their submissions, if any could be graded
+    #[serde(rename = "Best Score")]
+    pub best_score: Option<f64>,
+    // The top-level scores of their submissions without per-test results, which are only scored
+    // once `SubmissionSet::apply_totals` knows what they are out of
+    #[serde(skip)]
+    pub totals: Vec<f64>,
 }
 
 /// A collection of submissions for a particular assigment.
+#[derive(Clone)]
 pub struct SubmissionSet<'r> {
     roster: &'r Roster,
     pub submissions: Vec<Submission<'r>>,
+    pub skipped: Vec<SkippedSubmission<'r>>,
+    pub unmatched: Vec<UnmatchedSubmitter>,
+}
+
+impl Entry {
+    /// Get the time the submission was made.
+    ///
+    /// # Errors
+    ///
+    /// If the time is missing or malformed, will return `SubmissionFormatError` with the ID of the
+    /// submission.
+    pub fn time(&self) -> Result<DateTime<Utc>, Error> {
+        self.time.map_err(|code| Error::SubmissionFormatError(Some(self.id), code))
+    }
+
+    /// Get the tests the submission was graded on.
+    ///
+    /// # Errors
+    ///
+    /// If the results are missing or malformed, will return `SubmissionFormatError` with the ID of
+    /// the submission.
+    pub fn tests(&self) -> Result<&[TestCase], Error> {
+        self.tests.as_deref().map_err(|&code| Error::SubmissionFormatError(Some(self.id), code))
+    }
 }
 
 impl <'r> Submission<'r> {
-    /// Convert the YAML results for a submission into a `Submission` instance.
+    /// Convert a graded submission's entry in the metadata into a `Submission` instance.
     ///
     /// # Arguments
     ///
-    /// * `id` - The submission ID
     /// * `student` - The student whose submission this is
     /// * `active` - Whether this was the active submission
-    /// * `submission_yaml` - YAML value containing the submission data
+    /// * `entry` - The submission's entry in the metadata
     ///
     /// # Errors
     ///
-    /// If there is an error during deserialization, will return `SubmissionFormatError` with the
-    /// ID of the first invalid submission.
-    fn load(id: u64, student: &'r Student, active: bool, submission_yaml: &Value) -> Result<Submission<'r>, Error> {
-        // Get time of submission
-        let time = if let Some(Value::String(time)) = submission_yaml.get(":created_at") {
-            DateTime::<Utc>::from_utc(NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S.%f Z").or(Err(Error::SubmissionFormatError(Some(id), 0)))?, Utc)
-        } else {
-            Err(Error::SubmissionFormatError(Some(id), 1))?
-        };
-
-        if let Some(results) = submission_yaml.get(":results") {
-            if let Some(Value::Sequence(tests)) = results.get("tests") {
-                // Parse tests
-                let tests: Result<Vec<_>, _> = tests.into_iter().map(|t| {
-                    if let (Some(Value::String(name)), Some(Value::String(number)), Some(Value::Number(score)), Some(Value::Number(max))) = (t.get("name"), t.get("number"), t.get("score"), t.get("max_score")) {
-                        if let Ok(number) = number.parse::<f64>() {
-                            if score.is_f64() && max.is_f64() {
-                                Ok(TestCase {
-                                    name: name.to_string(),
-                                    number,
-                                    score: score.as_f64().unwrap(),
-                                    max: max.as_f64().unwrap(),
-                                })
-                            } else {
-                                Err(Error::SubmissionFormatError(Some(id), 2))
-                            }
-                        } else {
-                            Err(Error::SubmissionFormatError(Some(id), 20))
-                        }
-                    } else {
-                        Err(Error::SubmissionFormatError(Some(id), 3))
-                    }
-                }).collect();
-
-                let s = Submission {
-                    id,
-                    student,
-                    time,
-                    tests: tests?,
-                    active,
-                };
-
-                Ok(s)
-            } else {
-                Err(Error::SubmissionFormatError(Some(id), 4))?
-            }
-        } else {
-            Err(Error::SubmissionFormatError(Some(id), 5))?
-        }
+    /// If the submission's time or results are missing or malformed, will return
+    /// `SubmissionFormatError` with its ID.
+    fn load(student: &'r Student, active: bool, entry: &Entry) -> Result<Submission<'r>, Error> {
+        Ok(Submission {
+            id: entry.id,
+            student,
+            time: entry.time()?,
+            tests: entry.tests()?.to_vec(),
+            active,
+            excluded: false,
+            excess_penalty: 0.,
+            cap: None,
+            totals: entry.totals.clone(),
+        })
     }
 
     /// Compute the total score of this submission using the assignment's due date and any
@@ -112,18 +182,18 @@ impl <'r> Submission<'r> {
     /// # Arguments
     ///
     /// * `deadlines` - A list of 2-tuples, each containing the deadline and the respective penalty
-    /// for submitting before that deadline.  The first element of this list is the normal due
-    /// date, and must have a penalty of 0.  There must be at least one element.  The penalty
-    /// should be given as a float between 0 and 1, where 0 indicates no penalty, and 1 indicates
-    /// no credit (maximum penalty).
+    ///   for submitting before that deadline.  The first element of this list is the normal due
+    ///   date, and must have a penalty of 0.  There must be at least one element.  The penalty
+    ///   should be given as a float between 0 and 1, where 0 indicates no penalty, and 1 indicates
+    ///   no credit (maximum penalty).
     /// * `extension` - An extension, if applicable, to apply to this submission.  Passed as an
-    /// Option.
+    ///   Option.
     ///
     /// # Errors
     ///
     /// If the given set of deadlines is invalid (as described above), will return
     /// `InvalidDeadlineError`.
-    pub fn score(&self, deadlines: &Vec<(DateTime<Utc>, f64)>, extension: Option<&Extension>) -> Result<f64, Error> {
+    pub fn score(&self, deadlines: &[(DateTime<Utc>, f64)], extension: Option<&Extension>) -> Result<f64, Error> {
         Ok(self.raw_score() * (1. - self.compute_penalty(deadlines, extension)?))
     }
 
@@ -132,46 +202,71 @@ impl <'r> Submission<'r> {
     /// # Arguments
     ///
     /// * `deadlines` - A list of 2-tuples, each containing the deadline and the respective penalty
-    /// for submitting before that deadline.  The first element of this list is the normal due
-    /// date, and must have a penalty of 0.  There must be at least one element.  The penalty
-    /// should be given as a float between 0 and 1, where 0 indicates no penalty, and 1 indicates
-    /// no credit (maximum penalty).
+    ///   for submitting before that deadline.  The first element of this list is the normal due
+    ///   date, and must have a penalty of 0.  There must be at least one element.  The penalty
+    ///   should be given as a float between 0 and 1, where 0 indicates no penalty, and 1 indicates
+    ///   no credit (maximum penalty).
     /// * `extension` - An extension, if applicable, to apply to this submission.  Passed as an
-    /// Option.
+    ///   Option.
     ///
     /// # Errors
     ///
     /// If the given set of deadlines is invalid (as described above), will return
     /// `InvalidDeadlineError`.
-    pub fn compute_penalty(&self, deadlines: &Vec<(DateTime<Utc>, f64)>, extension: Option<&Extension>) -> Result<f64, Error> {
-        // Make sure the first deadline is valid
-        if deadlines.len() == 0 || deadlines[0].1 != 0. {
-            return Err(Error::InvalidDeadlineError);
+    pub fn compute_penalty(&self, deadlines: &[(DateTime<Utc>, f64)], extension: Option<&Extension>) -> Result<f64, Error> {
+        // If the submission did not fall before any deadlines, the penalty is 100%.  Otherwise,
+        // any penalty for going over the submission limit is added on.
+        Ok(period(&self.time, deadlines, extension)?.map_or(1., |(_, penalty)| (penalty + self.excess_penalty).min(1.)))
+    }
+
+    /// Compute the raw total score of this submission, not taking into account any deadlines or
+    /// extensions.  A submission with no points possible (for example, one with no tests) scores
+    /// 0.
+    pub fn raw_score(&self) -> f64 {
+        let (score, max) = self.points();
+        if max > 0. { score / max } else { 0. }
+    }
+
+    /// Compute the total number of points earned on this submission and the maximum number of
+    /// points possible, not taking into account any deadlines or extensions.  Points earned on
+    /// extra credit tests count, but their max doesn't, and the points are limited by the cap.  A
+    /// submission with no per-test results is scored on its top-level score.
+    pub fn points(&self) -> (f64, f64) {
+        let (score, max) = match &self.totals {
+            Some(totals) if self.tests.is_empty() => (totals.score.unwrap_or(0.), totals.max),
+            _ => self.tests.iter().fold((0., 0.), |(a_s, a_m), x| (a_s + x.score, if x.extra_credit { a_m } else { a_m + x.max })),
+        };
+
+        match self.cap {
+            Some(cap) => (score.min(cap * max), max),
+            None => (score, max),
         }
+    }
 
-        // Figure out which period this submission falls under
-        for (deadline, penalty) in deadlines.iter() {
-            // Allow a 5-minute buffer, just like Gradescope does, and add given extension.
-            let deadline = if let Some(ref extension) = extension {
-                *deadline + Duration::seconds(300) + Duration::seconds((extension.hours * 3600) as i64)
-            } else {
-                *deadline + Duration::seconds(300)
-            };
-        
-            if self.time <= deadline {
-                return Ok(*penalty);
+    /// Get the results this submission is graded on, in the order they appear on Gradescope: its
+    /// tests, or if it has no per-test results, a single `TOTAL_PART` test with its top-level
+    /// score.
+    pub fn results(&self) -> Vec<TestCase> {
+        match &self.totals {
+            Some(totals) if self.tests.is_empty() => vec![TestCase {
+                name: TOTAL_PART.to_owned(),
+                number: 0.,
+                score: totals.score.unwrap_or(0.),
+                max: totals.max,
+                tags: Vec::new(),
+                extra_credit: false,
+            }],
+            _ => {
+                let mut tests = self.tests.clone();
+                tests.sort_by(|a, b| a.number.partial_cmp(&b.number).unwrap());
+                tests
             }
         }
-
-        // The submission did not fall before any deadlines, so the penalty is 100%.
-        Ok(1.)
     }
 
-    /// Compute the raw total score of this submission, not taking into account any deadlines or
-    /// extensions.
-    pub fn raw_score(&self) -> f64 {
-        let (score, max) = self.tests.iter().fold((0., 0.), |(a_s, a_m), x| (a_s + x.score, a_m + x.max));
-        score / max
+    /// Whether this submission has no points possible, so it can't be given a meaningful score.
+    pub fn is_empty(&self) -> bool {
+        self.points().1 == 0.
     }
 
     /// Validate this submission against a canonical submission.  This ensures the tests names and
@@ -185,11 +280,11 @@ impl <'r> Submission<'r> {
         let mut tests = Vec::new();
 
         for t in self.tests.iter() {
-            tests.push((t.name.clone(), t.number.clone(), t.max.clone()));
+            tests.push((t.name.clone(), t.number, t.max));
         }
 
         for t in canonical.tests.iter() {
-            let k = (t.name.clone(), t.number.clone(), t.max.clone());
+            let k = (t.name.clone(), t.number, t.max);
             if tests.contains(&k) {
                 tests.remove(tests.iter().position(|x| *x == k).unwrap());
             } else {
@@ -197,10 +292,45 @@ impl <'r> Submission<'r> {
             }
         }
 
-        tests.len() == 0
+        tests.is_empty()
     }
 }
 
+/// Figure out which penalty period a submission made at a given time falls under.  Returns the
+/// deadline of that period (including the 5-minute buffer and any extension) and its penalty, or
+/// None if the time is after every deadline.
+///
+/// # Arguments
+///
+/// * `time` - The time of the submission
+/// * `deadlines` - The deadlines and their penalties, as described in `Submission::score`
+/// * `extension` - An extension, if applicable, to apply to the deadlines
+///
+/// # Errors
+///
+/// If the given set of deadlines is invalid, will return `InvalidDeadlineError`.
+pub fn period(time: &DateTime<Utc>, deadlines: &[(DateTime<Utc>, f64)], extension: Option<&Extension>) -> Result<Option<(DateTime<Utc>, f64)>, Error> {
+    // Make sure the first deadline is valid
+    if deadlines.is_empty() || deadlines[0].1 != 0. {
+        return Err(Error::InvalidDeadlineError);
+    }
+
+    for (deadline, penalty) in deadlines.iter() {
+        // Allow a 5-minute buffer, just like Gradescope does, and add given extension.
+        let deadline = if let Some(extension) = extension {
+            *deadline + Duration::seconds(300) + Duration::seconds((extension.hours * 3600) as i64)
+        } else {
+            *deadline + Duration::seconds(300)
+        };
+
+        if *time <= deadline {
+            return Ok(Some((deadline, *penalty)));
+        }
+    }
+
+    Ok(None)
+}
+
 impl <'r> SubmissionSet<'r> {
     /// Get an empty submission set.
     ///
@@ -211,6 +341,8 @@ impl <'r> SubmissionSet<'r> {
         SubmissionSet {
             roster,
             submissions: Vec::new(),
+            skipped: Vec::new(),
+            unmatched: Vec::new(),
         }
     }
 
@@ -220,7 +352,7 @@ impl <'r> SubmissionSet<'r> {
     /// # Arguments
     ///
     /// * `file` - The path to the YAML file, which is named submission_metadata.yml in the export
-    /// submission.
+    ///   submission.
     ///
     /// # Errors
     ///
@@ -229,117 +361,238 @@ impl <'r> SubmissionSet<'r> {
     /// deserialization, will return `SubmissionFormatError` with the ID of the first invalid
     /// submission (or None if the error was not related to a particular submission).
     pub fn load(&mut self, file: &str) -> Result<(), Error> {
-        // Open the file (this will fail if the file doesn't exist or we can't read it).
-        let file = File::open(file).or(Err(Error::SubmissionReadError))?;
-
-        // Parse the YAML file into a Value structure
-        let yaml: Value = serde_yaml::from_reader(file).or(Err(Error::SubmissionFormatError(None, 6)))?;
-
-        // Check that the data is the correct type (i.e. a mapping)
-        if let Value::Mapping(mapping) = yaml {
-            for (name, data) in mapping.into_iter() {
-                // Extract the active submission id
-                let submission_id = if let Value::String(name) = name {
-                    if &name[0..11] == "submission_" {
-                        *&name[11..].parse::<u64>().or(Err(Error::SubmissionFormatError(None, 7)))?
-                    } else {
-                        Err(Error::SubmissionFormatError(None, 8))?
-                    }
-                } else {
-                    Err(Error::SubmissionFormatError(None, 9))?
-                };
-
-                // Check status
-                if let Some(Value::String(status)) = data.get(":status") {
-                    if status != "processed" {
-                        if status != "failed" {
-                            eprintln!("Testing not finished on {}", submission_id);
-                        }
-                        continue;
-                    }
-                } else {
-                    Err(Error::SubmissionFormatError(Some(submission_id), 21))?
+        let metadata = Metadata::load(file)?;
+
+        for submitter in metadata.submitters.iter() {
+            // Get associated student
+            let student = match self.roster.find_student_by_sid(&submitter.sid) {
+                Some(student) => student,
+                None => {
+                    self.add_unmatched(submitter)?;
+                    continue;
                 }
+            };
 
-                // Get associated student
-                let student = if let Some(Value::Sequence(submitters)) = data.get(":submitters") {
-                    // There should only be one entry
-                    if submitters.len() != 1 {
-                        Err(Error::SubmissionFormatError(Some(submission_id), 10))?
-                    }
+            // Check the status of the active submission and each one before it, and add it to the
+            // list if it's done.  Even if the active one isn't, the student's earlier submissions
+            // may still be gradable.
+            for (entry, active) in iter::once((&submitter.active, true)).chain(submitter.history.iter().map(|e| (e, false))) {
+                match entry.status.as_deref() {
+                    Some("processed") => self.submissions.push(Submission::load(student, active, entry)?),
+                    Some(status) => self.skipped.push(SkippedSubmission { id: entry.id, student, time: entry.time()?, status: status.to_owned(), active }),
+                    None => Err(Error::SubmissionFormatError(Some(entry.id), 21))?,
+                }
+            }
+        }
 
-                    // Get the first entry
-                    if let Value::Mapping(ref mapping) = submitters[0] {
-                        if let Some(Value::String(id)) = mapping.get(&":sid".into()) {
-                            if let Some(student) = self.roster.find_student_by_uid(id.clone()) {
-                                student
-                            } else {
-                                eprintln!("No student found with id {}", id);
-                                continue
-                            }
-                        } else {
-                            Err(Error::SubmissionFormatError(Some(submission_id), 12))?
-                        }
-                    } else {
-                        Err(Error::SubmissionFormatError(Some(submission_id), 13))?
-                    }
-                } else {
-                    Err(Error::SubmissionFormatError(Some(submission_id), 14))?
-                };
-
-                // Parse the rest of the submission and add to list
-                self.submissions.push(Submission::load(submission_id, student, true, &data)?);
-
-                // Process sub-entries
-                if let Some(Value::Sequence(history)) = data.get(":history") {
-                    for data in history.into_iter() {
-                        // Get the submission id
-                        if let Some(Value::Number(submission_id)) = data.get(":id") {
-                            let submission_id = submission_id.as_i64().ok_or(Error::SubmissionFormatError(None, 15))? as u64;
-
-                            // Make sure its done
-                            if let Some(Value::String(status)) = data.get(":status") {
-                                if status != "processed" {
-                                    if status != "failed" {
-                                        eprintln!("Testing not finished on {}", submission_id);
-                                    }
-                                    continue;
-                                }
-                            } else {
-                                Err(Error::SubmissionFormatError(Some(submission_id), 21))?
-                            }
-
-                            // Parse the rest of the submission and add to list
-                            self.submissions.push(Submission::load(submission_id, student, false, data)?);
-                        } else {
-                            Err(Error::SubmissionFormatError(Some(submission_id), 16))?
-                        }
-                    }
-                } else {
-                    Err(Error::SubmissionFormatError(Some(submission_id), 17))?
+        self.resolve_active();
+        Ok(())
+    }
+
+    /// Make sure each student has only one active submission.  A student who submitted under more
+    /// than one SID (such as their UID and an alias) has an active submission for each, so their
+    /// histories are merged by keeping only the latest of them active, and the conflict is
+    /// reported.
+    fn resolve_active(&mut self) {
+        // The latest active submission of each student, and how many they have
+        let mut active: HashMap<&Student, (DateTime<Utc>, u64, usize)> = HashMap::new();
+        let times = self.submissions.iter().filter(|s| s.active).map(|s| (s.student, s.time, s.id))
+            .chain(self.skipped.iter().filter(|s| s.active).map(|s| (s.student, s.time, s.id)));
+        for (student, time, id) in times {
+            let latest = active.entry(student).or_insert((time, id, 0));
+            if time > latest.0 {
+                *latest = (time, id, latest.2);
+            }
+            latest.2 += 1;
+        }
+
+        for (student, (_, id, count)) in active.iter().filter(|(_, a)| a.2 > 1) {
+            eprintln!("{} has {} active submissions under different SIDs; only the latest, {}, is active", student.uid, count, id);
+        }
+
+        for submission in self.submissions.iter_mut().filter(|s| s.active) {
+            submission.active = active[submission.student].1 == submission.id;
+        }
+        for submission in self.skipped.iter_mut().filter(|s| s.active) {
+            submission.active = active[submission.student].1 == submission.id;
+        }
+    }
+
+    /// Decide how the submissions the autograder gave no per-test results for are graded.  If any
+    /// submission has per-test results, the others can't be written as per-test grades, so they
+    /// are excluded from grading.  Otherwise, each is scored on its top-level score out of `max`,
+    /// or if that isn't given, out of the reference submitter's score (such as the canonical
+    /// submitter's), or else out of the highest score of any of them.  Returns their IDs, and
+    /// whether they were scored rather than excluded.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - The points the project is out of, if known
+    /// * `reference` - A submitter whose active submission has full points, if any
+    pub fn apply_totals(&mut self, max: Option<f64>, reference: Option<&Student>) -> (Vec<u64>, bool) {
+        let ids: Vec<_> = self.submissions.iter().filter(|s| s.tests.is_empty() && s.totals.is_some()).map(|s| s.id).collect();
+        let scored = self.submissions.iter().all(|s| s.tests.is_empty());
+
+        let score = |s: &Submission| s.totals.as_ref().and_then(|t| t.score).unwrap_or(0.);
+        let max = max
+            .or_else(|| reference.and_then(|r| self.get_active_submission(r)).map(|s| if s.tests.is_empty() { score(s) } else { s.points().1 }))
+            .unwrap_or_else(|| self.submissions.iter().map(score).chain(self.unmatched.iter().flat_map(|u| u.totals.iter().copied())).fold(0., f64::max));
+
+        for submission in self.submissions.iter_mut().filter(|s| ids.contains(&s.id)) {
+            if let Some(totals) = submission.totals.as_mut() {
+                totals.max = max;
+            }
+            if !scored {
+                submission.excluded = true;
+            }
+        }
+
+        // Score the submitters who aren't on the roster the same way
+        for unmatched in self.unmatched.iter_mut().filter(|_| scored && max > 0.) {
+            for score in unmatched.totals.iter() {
+                if unmatched.best_score.is_none_or(|best| score / max > best) {
+                    unmatched.best_score = Some(score / max);
                 }
             }
+        }
+
+        (ids, scored)
+    }
+
+    /// Write the submissions without per-test results to a CSV file, so they can be checked, along
+    /// with whether they were excluded from grading.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path of the file to write.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be created or written, will return `SubmissionWriteError`.
+    pub fn write_totals(&self, file: &str) -> Result<(), Error> {
+        let mut wtr = csv::Writer::from_path(file).or(Err(Error::SubmissionWriteError))?;
+        wtr.write_record(["Submission", "UID", "DID", "Time", "Score", "Max", "Excluded", "Execution Time", "Output"]).or(Err(Error::SubmissionWriteError))?;
+
+        for s in self.submissions.iter().filter(|s| s.tests.is_empty()) {
+            if let Some(totals) = &s.totals {
+                let optional = |f: Option<f64>| f.map(|f| f.to_string()).unwrap_or_default();
+                wtr.write_record([&s.id.to_string(), &s.student.uid, &s.student.directory_id, &s.time.to_rfc3339(), &optional(totals.score), &totals.max.to_string(), &s.excluded.to_string(), &optional(totals.execution_time), totals.output.as_deref().unwrap_or_default()])
+                    .or(Err(Error::SubmissionWriteError))?;
+            }
+        }
 
-            Ok(())
+        wtr.flush().or(Err(Error::SubmissionWriteError))
+    }
+
+    /// Get a copy of these submissions with only the tests that match a predicate, for grading a
+    /// group of tests on its own.  The submitters who aren't on the roster are left out, and a
+    /// submission with no per-test results can't be split into groups, so it scores 0 in each.
+    ///
+    /// # Arguments
+    ///
+    /// * `keep` - Whether to keep a test
+    pub fn restrict<F: Fn(&TestCase) -> bool>(&self, keep: F) -> SubmissionSet<'r> {
+        SubmissionSet {
+            roster: self.roster,
+            submissions: self.submissions.iter().map(|s| Submission { tests: s.tests.iter().filter(|t| keep(t)).cloned().collect(), totals: None, ..s.clone() }).collect(),
+            skipped: self.skipped.clone(),
+            unmatched: Vec::new(),
+        }
+    }
+
+    /// Record the submissions of a submitter who is not on the roster.
+    ///
+    /// # Arguments
+    ///
+    /// * `submitter` - The submitter's entry in the metadata
+    ///
+    /// # Errors
+    ///
+    /// If the results of a processed submission are missing or malformed, will return
+    /// `SubmissionFormatError` with its ID.
+    fn add_unmatched(&mut self, submitter: &Submitter) -> Result<(), Error> {
+        let sid = &submitter.sid;
+        let mut unmatched = UnmatchedSubmitter {
+            sid: sid.clone(),
+            name: submitter.name.clone(),
+            email: submitter.email.clone(),
+            submissions: 0,
+            best_score: None,
+            totals: Vec::new(),
+        };
+
+        // Go through the active submission and all of the ones before it
+        for entry in iter::once(&submitter.active).chain(submitter.history.iter()) {
+            unmatched.submissions += 1;
+
+            if entry.status.as_deref() == Some("processed") {
+                if let Some(totals) = entry.totals.as_ref().filter(|_| entry.tests().is_ok_and(|t| t.is_empty())) {
+                    unmatched.totals.push(totals.score.unwrap_or(0.));
+                    continue;
+                }
+
+                let (score, max) = entry.tests()?.iter().fold((0., 0.), |(a_s, a_m), x| (a_s + x.score, a_m + x.max));
+                if max == 0. {
+                    continue;
+                }
+                if unmatched.best_score.is_none_or(|best| score / max > best) {
+                    unmatched.best_score = Some(score / max);
+                }
+            }
+        }
+
+        // Merge with any other submissions from the same SID in another file
+        if let Some(existing) = self.unmatched.iter_mut().find(|u| u.sid == *sid) {
+            existing.submissions += unmatched.submissions;
+            existing.totals.extend(unmatched.totals);
+            if let Some(score) = unmatched.best_score {
+                if existing.best_score.is_none_or(|best| score > best) {
+                    existing.best_score = Some(score);
+                }
+            }
         } else {
-            return Err(Error::SubmissionFormatError(None, 18));
+            self.unmatched.push(unmatched);
         }
+
+        Ok(())
+    }
+
+    /// Write the submitters who were not found on the roster to a CSV file, sorted by SID, so they
+    /// can be reconciled against the roster.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path of the file to write.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be created or written, will return `SubmissionWriteError`.
+    pub fn write_unmatched(&self, file: &str) -> Result<(), Error> {
+        let mut unmatched: Vec<_> = self.unmatched.iter().collect();
+        unmatched.sort_by(|a, b| a.sid.cmp(&b.sid));
+
+        let mut wtr = csv::Writer::from_path(file).or(Err(Error::SubmissionWriteError))?;
+        for u in unmatched {
+            wtr.serialize(u).or(Err(Error::SubmissionWriteError))?;
+        }
+        wtr.flush().or(Err(Error::SubmissionWriteError))
     }
 
     /// Find the most recent submission for a particular student before the given timestamp, if
-    /// provided.  If no timestamp is provided, the most recent submission will be returned.  Will
-    /// return None if no applicable submissions are found.
+    /// provided.  If no timestamp is provided, the most recent submission will be returned.
+    /// Submissions excluded by the submission limit are ignored.  Will return None if no
+    /// applicable submissions are found.
     ///
     /// # Arguments
     ///
     /// * `student` - The student whose submissions we should look for
     /// * `before` - If provided, a timestamp for which only submissions prior to it will be
-    /// considered
-    pub fn get_latest_submission<Z: TimeZone>(&self, student: &Student, before: Option<&DateTime<Z>>) -> Option<&Submission> {
+    ///   considered
+    pub fn get_latest_submission<Z: TimeZone>(&self, student: &Student, before: Option<&DateTime<Z>>) -> Option<&Submission<'r>> {
         let mut latest: Option<&Submission> = None;
 
         for submission in self.submissions.iter() {
-            if submission.student == student && (before.is_none() || submission.time <= *before.unwrap()) {
+            if submission.student == student && !submission.excluded && (before.is_none() || submission.time <= *before.unwrap()) {
                 if let Some(l) = latest {
                     // Compare to existing result
                     if submission.time > l.time {
@@ -354,36 +607,69 @@ impl <'r> SubmissionSet<'r> {
         latest
     }
 
-    /// Get the active submission for a particular student.  Will return None if the student has no
-    /// submissions.
+    /// Get all of the gradable submissions for a particular student, in the order they were made.
     ///
     /// # Arguments
     ///
     /// * `student` - The student whose submissions we should look for
-    pub fn get_active_submission(&self, student: &Student) -> Option<&Submission> {
-        for submission in self.submissions.iter() {
-            if submission.student == student && submission.active {
-                return Some(submission);
-            }
-        }
+    pub fn get_submissions(&self, student: &Student) -> Vec<&Submission<'r>> {
+        let mut submissions: Vec<_> = self.submissions.iter().filter(|s| s.student == student).collect();
+        submissions.sort_by_key(|s| s.time);
+        submissions
+    }
 
-        None
+    /// Get the active submission for a particular student.  Will return None if the student has no
+    /// submissions, or their active submission was excluded by the submission limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `student` - The student whose submissions we should look for
+    pub fn get_active_submission(&self, student: &Student) -> Option<&Submission<'r>> {
+        self.submissions.iter().find(|submission| submission.student == student && submission.active && !submission.excluded)
     }
 }
 
-impl ToString for Submission<'_> {
-    fn to_string(&self) -> String {
-        let mut string = String::new();
+impl fmt::Display for Submission<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for t in self.results() {
+            writeln!(f, "{},{},{},", self.student.directory_id, t.name, t.score)?;
+        }
 
-        let mut tests = self.tests.clone();
-        tests.sort_by(|a, b| {
-            a.number.partial_cmp(&b.number).unwrap()
-        });
+        Ok(())
+    }
+}
 
-        for t in tests {
-            string.push_str(&format!("{},{},{},\n", self.student.directory_id, t.name, t.score));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn totals_are_out_of_the_reference_score_unless_given() {
+        let roster = roster(&["1", "2"]);
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(Submission { active: true, ..total(&roster.students[0], 1, "2021-03-01 10:00 +0000", 40.) });
+        submissions.submissions.push(total(&roster.students[1], 2, "2021-03-01 11:00 +0000", 30.));
+
+        assert_eq!(submissions.apply_totals(None, Some(&roster.students[0])), (vec![1, 2], true));
+        assert_eq!(submissions.submissions[1].points(), (30., 40.));
+        assert_eq!(submissions.submissions[1].results().iter().map(|t| (t.name.as_str(), t.score, t.max)).collect::<Vec<_>>(), vec![(TOTAL_PART, 30., 40.)]);
+
+        submissions.apply_totals(Some(50.), Some(&roster.students[0]));
+        assert_eq!(submissions.submissions[1].points(), (30., 50.));
+        assert!(submissions.submissions.iter().all(|s| !s.excluded));
+    }
 
-        string
+    #[test]
+    fn totals_are_excluded_when_others_have_tests() {
+        let roster = roster(&["1", "2"]);
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(submission(&roster.students[0], 1, "2021-03-01 10:00 +0000", &[1., 1.]));
+        submissions.submissions.push(total(&roster.students[1], 2, "2021-03-01 11:00 +0000", 2.));
+
+        assert_eq!(submissions.apply_totals(None, Some(&roster.students[0])), (vec![2], false));
+        assert!(!submissions.submissions[0].excluded);
+        assert!(submissions.submissions[1].excluded);
+        assert_eq!(submissions.submissions[1].points().1, 2.);
     }
 }
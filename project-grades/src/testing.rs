@@ -0,0 +1,101 @@
+//! Helpers for building the rosters, submissions and policies used by the unit tests.
+
+use crate::{roster::*, submissions::*, policy::*, grades::*};
+use std::{fs, path::PathBuf};
+use chrono::{DateTime, Utc};
+
+/// Make a roster of students with the given UIDs, whose directory IDs are the UID prefixed by "s".
+pub fn roster(uids: &[&str]) -> Roster {
+    Roster::from_students(uids.iter().map(|uid| Student {
+        name: Some(format!("Student {}", uid)),
+        uid: uid.to_string(),
+        directory_id: format!("s{}", uid),
+        section: None,
+    }).collect())
+}
+
+/// Parse a policy from YAML.
+pub fn policy(yaml: &str) -> Policy {
+    serde_yaml::from_str(yaml).unwrap()
+}
+
+/// Parse a time in the format of `DATE_FORMAT`.
+pub fn time(time: &str) -> DateTime<Utc> {
+    parse_date(time).unwrap()
+}
+
+/// Make a submission with one test worth a point for each score, named t1, t2, etc.
+pub fn submission<'r>(student: &'r Student, id: u64, at: &str, scores: &[f64]) -> Submission<'r> {
+    Submission {
+        id,
+        student,
+        time: time(at),
+        tests: scores.iter().enumerate().map(|(i, &score)| TestCase {
+            name: format!("t{}", i + 1),
+            number: (i + 1) as f64,
+            score,
+            max: 1.,
+            tags: Vec::new(),
+            extra_credit: false,
+        }).collect(),
+        active: false,
+        excluded: false,
+        excess_penalty: 0.,
+        cap: None,
+        totals: None,
+    }
+}
+
+/// Make a submission the autograder gave only a top-level score for.
+pub fn total<'r>(student: &'r Student, id: u64, at: &str, score: f64) -> Submission<'r> {
+    Submission {
+        totals: Some(Totals { score: Some(score), execution_time: None, output: None, max: 0. }),
+        ..submission(student, id, at, &[])
+    }
+}
+
+/// Make a submission the autograder failed on.
+pub fn failed<'r>(student: &'r Student, id: u64, at: &str) -> SkippedSubmission<'r> {
+    SkippedSubmission { id, student, time: time(at), status: "failed".to_owned(), active: false }
+}
+
+/// Make a grade out of 10 for the student with the given UID, whose directory ID is the UID
+/// prefixed by "s".
+pub fn grade(uid: &str, submission: u64, score: f64, penalty: f64) -> Grade {
+    Grade {
+        uid: uid.to_owned(),
+        directory_id: format!("s{}", uid),
+        name: None,
+        submission,
+        time: time("2021-03-01 12:00 +0000"),
+        score,
+        max: 10.,
+        penalty,
+        final_score: score * (1. - penalty),
+    }
+}
+
+/// Write a file into a directory of its own for the test, returning its path.
+///
+/// # Arguments
+///
+/// * `test` - The name of the test, which the directory is named after
+/// * `name` - The name of the file
+/// * `contents` - What to write to it
+pub fn file(test: &str, name: &str, contents: &str) -> String {
+    let dir = scratch(test);
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+/// Get an empty directory of the test's own, in the system's temporary directory.
+///
+/// # Arguments
+///
+/// * `test` - The name of the test, which the directory is named after
+pub fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("project-grades-{}-{}", std::process::id(), test));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,205 @@
+//! Enforcing release tokens after the fact, as the department's old submit server did: each
+//! student has a number of tokens, each submission uses one to see its release test results, and
+//! a used token comes back after a fixed time.  Replaying each student's submissions in order, the
+//! release test results of any submission made without a token available are left out of its
+//! score.
+
+use crate::{roster::*, submissions::*, policy::*};
+use std::{collections::HashMap, fmt};
+use chrono::{DateTime, Utc, Duration};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    TokensWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TokensWriteError => write!(f, "Couldn't write the submissions made without a token"),
+        }
+    }
+}
+
+/// A submission made without a release token, whose release test results were left out.
+pub struct Untokened<'r> {
+    pub id: u64,
+    pub student: &'r Student,
+    pub time: DateTime<Utc>,
+    // When the student's next token became available.
+    pub next_token: DateTime<Utc>,
+    // The points the submission earned on the release tests, which it no longer gets.
+    pub points: f64,
+}
+
+/// A single row of the CSV written by `write`.
+#[derive(serde::Serialize)]
+struct UntokenedRow<'r> {
+    #[serde(rename = "Submission")]
+    id: u64,
+    #[serde(rename = "UID")]
+    uid: &'r str,
+    #[serde(rename = "DID")]
+    directory_id: &'r str,
+    #[serde(rename = "Time")]
+    time: DateTime<Utc>,
+    #[serde(rename = "Next Token")]
+    next_token: DateTime<Utc>,
+    #[serde(rename = "Points Lost")]
+    points: f64,
+}
+
+/// Apply the policy's release tokens, if it has them, giving each release test of a submission
+/// made without a token no points.  Only the submissions the autograder finished on use tokens.
+/// Returns the submissions made without a token, ordered by time.
+///
+/// # Arguments
+///
+/// * `roster` - The students the tokens apply to
+/// * `submissions` - All of the submissions for the project
+/// * `policy` - The late policy
+/// * `exempt` - A submitter the tokens don't apply to, such as the canonical submitter
+pub fn apply<'r>(roster: &'r Roster, submissions: &mut SubmissionSet<'r>, policy: &Policy, exempt: Option<&Student>) -> Vec<Untokened<'r>> {
+    let tokens = match &policy.release_tokens {
+        Some(tokens) => tokens,
+        None => return Vec::new(),
+    };
+    let regeneration = Duration::seconds((tokens.regeneration_hours * 3600.) as i64);
+
+    // The indices of each student's submissions
+    let mut made: HashMap<_, Vec<_>> = HashMap::new();
+    for (i, submission) in submissions.submissions.iter().enumerate() {
+        made.entry(submission.student).or_default().push(i);
+    }
+
+    let mut untokened = Vec::new();
+    for student in roster.students.iter().filter(|&s| exempt != Some(s)) {
+        // The student's submissions, in the order they were made
+        let mut indices = made.remove(student).unwrap_or_default();
+        indices.sort_by_key(|&i| submissions.submissions[i].time);
+
+        // The times of the tokens used, which each come back after the regeneration time
+        let mut used: Vec<DateTime<Utc>> = Vec::new();
+        for i in indices {
+            let submission = &mut submissions.submissions[i];
+            used.retain(|&t| t + regeneration > submission.time);
+
+            if used.len() < tokens.count {
+                used.push(submission.time);
+                continue;
+            }
+
+            // No token is available, so the release test results don't count
+            let mut points = 0.;
+            for test in submission.tests.iter_mut().filter(|t| tokens.is_release_test(t)) {
+                points += test.score;
+                test.score = 0.;
+            }
+
+            untokened.push(Untokened {
+                id: submission.id,
+                student,
+                time: submission.time,
+                next_token: used.iter().min().map_or(submission.time, |&t| t + regeneration),
+                points,
+            });
+        }
+    }
+
+    untokened.sort_by_key(|u| u.time);
+    untokened
+}
+
+/// Write the submissions made without a token to a CSV file.
+///
+/// # Arguments
+///
+/// * `untokened` - The submissions from `apply`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `TokensWriteError`.
+pub fn write(untokened: &[Untokened], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::TokensWriteError))?;
+
+    for u in untokened.iter() {
+        wtr.serialize(UntokenedRow {
+            id: u.id,
+            uid: &u.student.uid,
+            directory_id: &u.student.directory_id,
+            time: u.time,
+            next_token: u.next_token,
+            points: u.points,
+        }).or(Err(Error::TokensWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::TokensWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const POLICY: &str = "due_date: 2021-03-10 23:59 +0000\nrelease_tokens:\n  tests: [t2]\n  count: 2\n  regeneration_hours: 24\n";
+
+    #[test]
+    fn replays_tokens_in_the_order_submissions_were_made() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let policy = policy(POLICY);
+
+        // Out of order, to check that they're replayed by time
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions.push(submission(student, 3, "2021-03-01 12:00 +0000", &[1., 1.]));
+        submissions.submissions.push(submission(student, 1, "2021-03-01 10:00 +0000", &[1., 1.]));
+        submissions.submissions.push(submission(student, 2, "2021-03-01 11:00 +0000", &[1., 1.]));
+        submissions.submissions.push(submission(student, 4, "2021-03-02 10:30 +0000", &[1., 1.]));
+
+        let untokened = apply(&roster, &mut submissions, &policy, None);
+
+        // The third used no token, and the first one came back a day after it was used
+        assert_eq!(untokened.len(), 1);
+        assert_eq!(untokened[0].id, 3);
+        assert_eq!(untokened[0].next_token, time("2021-03-02 10:00 +0000"));
+        assert_eq!(untokened[0].points, 1.);
+
+        // Only the release test lost its points
+        let third = submissions.submissions.iter().find(|s| s.id == 3).unwrap();
+        assert_eq!(third.tests.iter().map(|t| t.score).collect::<Vec<_>>(), vec![1., 0.]);
+        assert!(submissions.submissions.iter().filter(|s| s.id != 3).all(|s| s.points().0 == 2.));
+    }
+
+    #[test]
+    fn students_have_their_own_tokens() {
+        let roster = roster(&["1", "2", "3"]);
+        let policy = policy(POLICY);
+
+        let mut submissions = SubmissionSet::new(&roster);
+        for (i, student) in roster.students.iter().enumerate() {
+            for (j, at) in ["2021-03-01 10:00 +0000", "2021-03-01 11:00 +0000", "2021-03-01 12:00 +0000"].iter().enumerate() {
+                submissions.submissions.push(submission(student, (i * 10 + j) as u64, at, &[1., 1.]));
+            }
+        }
+
+        let untokened = apply(&roster, &mut submissions, &policy, Some(&roster.students[0]));
+
+        assert_eq!(untokened.iter().map(|u| (u.student.uid.as_str(), u.id)).collect::<Vec<_>>(), vec![("2", 12), ("3", 22)]);
+    }
+
+    #[test]
+    fn no_tokens_without_a_policy_for_them() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+        let policy = policy("due_date: 2021-03-10 23:59 +0000\n");
+
+        let mut submissions = SubmissionSet::new(&roster);
+        for id in 0..5 {
+            submissions.submissions.push(submission(student, id, "2021-03-01 10:00 +0000", &[1., 1.]));
+        }
+
+        assert!(apply(&roster, &mut submissions, &policy, None).is_empty());
+    }
+}
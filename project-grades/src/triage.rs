@@ -0,0 +1,180 @@
+//! Listing the submissions that the autograder failed on or has not finished running, so that the
+//! ones that matter can be re-run on Gradescope before grades are published.
+
+use crate::{roster::*, extensions::*, submissions::*, policy::*};
+use std::{collections::HashMap, fmt};
+use chrono::{DateTime, Utc};
+
+/// The types of errors that can be produced within and returned from this module.
+#[derive(Debug)]
+pub enum Error {
+    TriageWriteError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TriageWriteError => write!(f, "Couldn't write the triage list"),
+        }
+    }
+}
+
+/// A submission that could not be graded, and whether re-running it could change the student's
+/// grade.
+pub struct Triage<'s, 'r> {
+    pub submission: &'s SkippedSubmission<'r>,
+    pub could_affect: bool,
+}
+
+/// A single row of the CSV written by `write`.
+#[derive(serde::Serialize)]
+struct TriageRow<'s> {
+    #[serde(rename = "Submission")]
+    id: u64,
+    #[serde(rename = "UID")]
+    uid: &'s str,
+    #[serde(rename = "DID")]
+    directory_id: &'s str,
+    #[serde(rename = "Time")]
+    time: DateTime<Utc>,
+    #[serde(rename = "Status")]
+    status: &'s str,
+    #[serde(rename = "Active")]
+    active: bool,
+    #[serde(rename = "Could Affect Grade")]
+    could_affect: bool,
+}
+
+/// Whether a skipped submission, once re-run, could change which submission is chosen for the
+/// student (or their score).  This is the case if it would be considered at all under the policy's
+/// strategy (for the default strategy, if it is the active submission or the latest in its penalty
+/// period), and either a perfect score on it would beat the student's current grade, or it would
+/// displace the chosen submission.
+fn could_affect(skipped: &SkippedSubmission, submissions: &SubmissionSet, extension: Option<&Extension>, policy: &Policy, chosen: Option<&Submission>) -> bool {
+    let deadlines = policy.deadlines_for(skipped.student);
+
+    // Submissions past the final deadline can't earn anything
+    let (end, penalty) = match period(&skipped.time, &deadlines, extension).unwrap() {
+        Some((end, penalty)) if penalty < 1. => (end, penalty),
+        _ => return false,
+    };
+
+    // Whether it would be considered, and whether it would take the place of the chosen
+    // submission by being considered
+    let latest = submissions.get_latest_submission(skipped.student, Some(&end));
+    let (considered, displaces) = match policy.strategy {
+        Strategy::Default => (skipped.active || latest.is_none_or(|l| l.time <= skipped.time), chosen.is_some_and(|c| !c.active && latest.is_some_and(|l| l.id == c.id))),
+        Strategy::Active => (skipped.active, true),
+        Strategy::Latest => (submissions.get_latest_submission::<Utc>(skipped.student, None).is_none_or(|l| l.time < skipped.time), true),
+        Strategy::Best => (true, false),
+    };
+
+    match chosen {
+        _ if !considered => false,
+        None => true,
+        Some(chosen) => displaces || 1. - penalty > chosen.score(&deadlines, extension).unwrap(),
+    }
+}
+
+/// Find every submission that could not be graded, ordered by time.
+///
+/// # Arguments
+///
+/// * `submissions` - All of the submissions for the project
+/// * `extensions` - The students' extensions
+/// * `policy` - The late policy
+/// * `chosen` - The submission chosen for each student
+pub fn find<'s, 'r>(submissions: &'s SubmissionSet<'r>, extensions: &ExtensionSet, policy: &Policy, chosen: &HashMap<&Student, &Submission>) -> Vec<Triage<'s, 'r>> {
+    let mut triage: Vec<_> = submissions.skipped.iter().map(|submission| {
+        let extension = extensions.find(submission.student);
+        let chosen = chosen.get(submission.student).copied();
+
+        Triage {
+            submission,
+            could_affect: could_affect(submission, submissions, extension, policy, chosen),
+        }
+    }).collect();
+
+    triage.sort_by_key(|t| t.submission.time);
+    triage
+}
+
+/// Write the submissions to a CSV file.
+///
+/// # Arguments
+///
+/// * `triage` - The submissions from `find`
+/// * `file` - The path of the file to write
+///
+/// # Errors
+///
+/// If the file cannot be created or written, will return `TriageWriteError`.
+pub fn write(triage: &[Triage], file: &str) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_path(file).or(Err(Error::TriageWriteError))?;
+
+    for t in triage.iter() {
+        wtr.serialize(TriageRow {
+            id: t.submission.id,
+            uid: &t.submission.student.uid,
+            directory_id: &t.submission.student.directory_id,
+            time: t.submission.time,
+            status: &t.submission.status,
+            active: t.submission.active,
+            could_affect: t.could_affect,
+        }).or(Err(Error::TriageWriteError))?;
+    }
+
+    wtr.flush().or(Err(Error::TriageWriteError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const POLICY: &str = "due_date: 2021-03-01 23:59 +0000\ndeadlines:\n  - hours: 24\n    penalty: 0.1\n";
+
+    /// Whether each failed submission could affect the grade of student "1", who made `graded`.
+    fn affects(strategy: &str, graded: Vec<Submission>, failed: Vec<SkippedSubmission>, chosen: Option<u64>) -> Vec<bool> {
+        let roster = roster(&["1"]);
+        let policy = policy(&format!("{}strategy: {}\n", POLICY, strategy));
+        let mut submissions = SubmissionSet::new(&roster);
+        submissions.submissions = graded;
+        submissions.skipped = failed;
+
+        let chosen: HashMap<_, _> = submissions.submissions.iter().filter(|s| Some(s.id) == chosen).map(|s| (&roster.students[0], s)).collect();
+        find(&submissions, &ExtensionSet::empty(), &policy, &chosen).iter().map(|t| t.could_affect).collect()
+    }
+
+    #[test]
+    fn default_strategy_counts_only_the_latest_in_each_period() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+
+        // The first failed submission was followed by a graded one in the same period, while the
+        // second would take that one's place as the latest on time
+        let graded = vec![submission(student, 2, "2021-03-01 11:00 +0000", &[1.])];
+        let failed = vec![failed(student, 1, "2021-03-01 10:00 +0000"), failed(student, 3, "2021-03-01 12:00 +0000")];
+        assert_eq!(affects("default", graded, failed, Some(2)), vec![false, true]);
+    }
+
+    #[test]
+    fn submissions_past_the_final_deadline_never_matter() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+
+        let failed = vec![failed(student, 1, "2021-03-03 12:00 +0000")];
+        assert_eq!(affects("best", Vec::new(), failed, None), vec![false]);
+    }
+
+    #[test]
+    fn best_strategy_counts_a_late_submission_only_if_it_could_score_higher() {
+        let roster = roster(&["1"]);
+        let student = &roster.students[0];
+
+        // A perfect score a day late is worth 90%, which beats half credit but not full credit
+        let late = || vec![failed(student, 2, "2021-03-02 12:00 +0000")];
+        assert_eq!(affects("best", vec![submission(student, 1, "2021-03-01 10:00 +0000", &[1., 1.])], late(), Some(1)), vec![false]);
+        assert_eq!(affects("best", vec![submission(student, 1, "2021-03-01 10:00 +0000", &[1., 0.])], late(), Some(1)), vec![true]);
+    }
+}
@@ -101,9 +101,9 @@ fn main() {
     {
         let mut file = File::create(format!("{}-idmap.csv", prefix)).unwrap();
 
-        file.write(b"UID,DID,Name\n").unwrap();
+        file.write(b"UID,DID,Name,Section\n").unwrap();
         for s in students.iter() {
-            file.write(format!("{},{},\"{} {}\"\n", s.uid, s.directory_id, s.first_name, s.last_name).as_bytes()).unwrap();
+            file.write(format!("{},{},\"{} {}\",{}\n", s.uid, s.directory_id, s.first_name, s.last_name, s.section).as_bytes()).unwrap();
         }
     }
 }